@@ -0,0 +1,440 @@
+use crate::game::registers::{FLAG_CARRY, FLAG_HALF_CARRY, FLAG_NEGATIVE, FLAG_ZERO};
+
+// Every primitive returns its result along with the complete new value of the
+// F register. Operations that leave some flags untouched take the current F
+// and carry those bits over, so callers can always assign F wholesale.
+
+fn flags(zero: bool, negative: bool, half_carry: bool, carry: bool) -> u8 {
+  let mut f: u8 = 0;
+  if zero {
+    f |= FLAG_ZERO;
+  }
+  if negative {
+    f |= FLAG_NEGATIVE;
+  }
+  if half_carry {
+    f |= FLAG_HALF_CARRY;
+  }
+  if carry {
+    f |= FLAG_CARRY;
+  }
+  return f;
+}
+
+// 8-bit arithmetic
+
+pub fn add(a: u8, b: u8) -> (u8, u8) {
+  return adc(a, b, false);
+}
+
+pub fn adc(a: u8, b: u8, carry: bool) -> (u8, u8) {
+  let c: u8 = carry as u8;
+  let result: u16 = a as u16 + b as u16 + c as u16;
+  let value: u8 = result as u8;
+  let half_carry: bool = (a & 0x0f) + (b & 0x0f) + c > 0x0f;
+  return (value, flags(value == 0, false, half_carry, result > 0xff));
+}
+
+pub fn sub(a: u8, b: u8) -> (u8, u8) {
+  return sbc(a, b, false);
+}
+
+pub fn sbc(a: u8, b: u8, carry: bool) -> (u8, u8) {
+  let c: i16 = carry as i16;
+  let result: i16 = a as i16 - b as i16 - c;
+  let value: u8 = result as u8;
+  let half_carry: bool = (a & 0x0f) as i16 - (b & 0x0f) as i16 - c < 0;
+  return (value, flags(value == 0, true, half_carry, result < 0));
+}
+
+pub fn and(a: u8, b: u8) -> (u8, u8) {
+  let value: u8 = a & b;
+  return (value, flags(value == 0, false, true, false));
+}
+
+pub fn or(a: u8, b: u8) -> (u8, u8) {
+  let value: u8 = a | b;
+  return (value, flags(value == 0, false, false, false));
+}
+
+pub fn xor(a: u8, b: u8) -> (u8, u8) {
+  let value: u8 = a ^ b;
+  return (value, flags(value == 0, false, false, false));
+}
+
+// CP is SUB with the result discarded.
+pub fn cp(a: u8, b: u8) -> u8 {
+  return sub(a, b).1;
+}
+
+pub fn inc(val: u8, f: u8) -> (u8, u8) {
+  let value: u8 = val.wrapping_add(1);
+  let half_carry: bool = (val & 0x0f) == 0x0f;
+  return (value, flags(value == 0, false, half_carry, f & FLAG_CARRY != 0));
+}
+
+pub fn dec(val: u8, f: u8) -> (u8, u8) {
+  let value: u8 = val.wrapping_sub(1);
+  let half_carry: bool = (val & 0x0f) == 0;
+  return (value, flags(value == 0, true, half_carry, f & FLAG_CARRY != 0));
+}
+
+pub fn daa(a: u8, f: u8) -> (u8, u8) {
+  let negative: bool = f & FLAG_NEGATIVE != 0;
+  let mut carry: bool = f & FLAG_CARRY != 0;
+  let mut correction: u8 = 0;
+
+  if carry || (!negative && a > 0x99) {
+    correction |= 0x60;
+    carry = true;
+  }
+  if f & FLAG_HALF_CARRY != 0 || (!negative && (a & 0x0f) > 0x09) {
+    correction |= 0x06;
+  }
+
+  let value: u8 = if negative {
+    a.wrapping_sub(correction)
+  } else {
+    a.wrapping_add(correction)
+  };
+  return (value, flags(value == 0, negative, false, carry));
+}
+
+pub fn cpl(a: u8, f: u8) -> (u8, u8) {
+  return (!a, f | FLAG_NEGATIVE | FLAG_HALF_CARRY);
+}
+
+pub fn scf(f: u8) -> u8 {
+  return (f & FLAG_ZERO) | FLAG_CARRY;
+}
+
+pub fn ccf(f: u8) -> u8 {
+  return (f & (FLAG_ZERO | FLAG_CARRY)) ^ FLAG_CARRY;
+}
+
+// 16-bit arithmetic
+
+// ADD HL, rr: Z is preserved, H and C come from bits 11 and 15.
+pub fn add16(a: u16, b: u16, f: u8) -> (u16, u8) {
+  let result: u32 = a as u32 + b as u32;
+  let half_carry: bool = (a & 0x0fff) + (b & 0x0fff) > 0x0fff;
+  return (
+    result as u16,
+    flags(f & FLAG_ZERO != 0, false, half_carry, result > 0xffff),
+  );
+}
+
+// ADD SP, e and LD HL, SP+e: the offset is signed but H and C are computed
+// from an unsigned add of the low byte.
+pub fn add_sp(sp: u16, offset: u8) -> (u16, u8) {
+  let value: u16 = sp.wrapping_add(offset as i8 as u16);
+  let half_carry: bool = (sp & 0x0f) + (offset as u16 & 0x0f) > 0x0f;
+  let carry: bool = (sp & 0xff) + offset as u16 > 0xff;
+  return (value, flags(false, false, half_carry, carry));
+}
+
+// Rotates and shifts (CB page forms, which set Z from the result)
+
+pub fn rlc(val: u8) -> (u8, u8) {
+  let value: u8 = val.rotate_left(1);
+  return (value, flags(value == 0, false, false, val & 0x80 != 0));
+}
+
+pub fn rrc(val: u8) -> (u8, u8) {
+  let value: u8 = val.rotate_right(1);
+  return (value, flags(value == 0, false, false, val & 0x01 != 0));
+}
+
+pub fn rl(val: u8, carry: bool) -> (u8, u8) {
+  let value: u8 = (val << 1) | carry as u8;
+  return (value, flags(value == 0, false, false, val & 0x80 != 0));
+}
+
+pub fn rr(val: u8, carry: bool) -> (u8, u8) {
+  let value: u8 = (val >> 1) | ((carry as u8) << 7);
+  return (value, flags(value == 0, false, false, val & 0x01 != 0));
+}
+
+pub fn sla(val: u8) -> (u8, u8) {
+  let value: u8 = val << 1;
+  return (value, flags(value == 0, false, false, val & 0x80 != 0));
+}
+
+pub fn sra(val: u8) -> (u8, u8) {
+  let value: u8 = (val >> 1) | (val & 0x80);
+  return (value, flags(value == 0, false, false, val & 0x01 != 0));
+}
+
+pub fn srl(val: u8) -> (u8, u8) {
+  let value: u8 = val >> 1;
+  return (value, flags(value == 0, false, false, val & 0x01 != 0));
+}
+
+pub fn swap(val: u8) -> (u8, u8) {
+  let value: u8 = val.rotate_left(4);
+  return (value, flags(value == 0, false, false, false));
+}
+
+pub fn bit(n: u8, val: u8, f: u8) -> u8 {
+  return flags(val & (1 << n) == 0, false, true, f & FLAG_CARRY != 0);
+}
+
+// Accumulator rotates (RLCA, RRCA, RLA, RRA) always clear Z.
+
+pub fn rlca(a: u8) -> (u8, u8) {
+  let (value, f) = rlc(a);
+  return (value, f & !FLAG_ZERO);
+}
+
+pub fn rrca(a: u8) -> (u8, u8) {
+  let (value, f) = rrc(a);
+  return (value, f & !FLAG_ZERO);
+}
+
+pub fn rla(a: u8, carry: bool) -> (u8, u8) {
+  let (value, f) = rl(a, carry);
+  return (value, f & !FLAG_ZERO);
+}
+
+pub fn rra(a: u8, carry: bool) -> (u8, u8) {
+  let (value, f) = rr(a, carry);
+  return (value, f & !FLAG_ZERO);
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const ALL_FLAGS: [u8; 16] = [
+    0x00, 0x10, 0x20, 0x30, 0x40, 0x50, 0x60, 0x70, 0x80, 0x90, 0xa0, 0xb0, 0xc0, 0xd0, 0xe0, 0xf0,
+  ];
+
+  fn z(f: u8) -> bool {
+    return f & FLAG_ZERO != 0;
+  }
+  fn n(f: u8) -> bool {
+    return f & FLAG_NEGATIVE != 0;
+  }
+  fn h(f: u8) -> bool {
+    return f & FLAG_HALF_CARRY != 0;
+  }
+  fn c(f: u8) -> bool {
+    return f & FLAG_CARRY != 0;
+  }
+
+  #[test]
+  fn adc_matches_reference_for_all_inputs() {
+    for a in 0..=255u8 {
+      for b in 0..=255u8 {
+        for &carry in &[false, true] {
+          let (value, f) = adc(a, b, carry);
+          let wide: u32 = a as u32 + b as u32 + carry as u32;
+          assert_eq!(value as u32, wide % 256, "{:#x} + {:#x} + {}", a, b, carry);
+          assert_eq!(z(f), (wide & 0xff) == 0);
+          assert!(!n(f));
+          assert_eq!(h(f), (a % 16) as u32 + (b % 16) as u32 + carry as u32 >= 16);
+          assert_eq!(c(f), wide >= 256);
+          assert_eq!(f & 0x0f, 0);
+        }
+        assert_eq!(add(a, b), adc(a, b, false));
+      }
+    }
+  }
+
+  #[test]
+  fn sbc_matches_reference_for_all_inputs() {
+    for a in 0..=255u8 {
+      for b in 0..=255u8 {
+        for &carry in &[false, true] {
+          let (value, f) = sbc(a, b, carry);
+          let wide: i32 = a as i32 - b as i32 - carry as i32;
+          assert_eq!(value, wide.rem_euclid(256) as u8, "{:#x} - {:#x} - {}", a, b, carry);
+          assert_eq!(z(f), value == 0);
+          assert!(n(f));
+          assert_eq!(h(f), ((a % 16) as i32) < (b % 16) as i32 + carry as i32);
+          assert_eq!(c(f), (a as i32) < b as i32 + carry as i32);
+          assert_eq!(f & 0x0f, 0);
+        }
+        assert_eq!(sub(a, b), sbc(a, b, false));
+        assert_eq!(cp(a, b), sub(a, b).1);
+      }
+    }
+  }
+
+  #[test]
+  fn logic_ops_for_all_inputs() {
+    for a in 0..=255u8 {
+      for b in 0..=255u8 {
+        assert_eq!(and(a, b), (a & b, if a & b == 0 { 0xa0 } else { 0x20 }));
+        assert_eq!(or(a, b), (a | b, if a | b == 0 { 0x80 } else { 0x00 }));
+        assert_eq!(xor(a, b), (a ^ b, if a ^ b == 0 { 0x80 } else { 0x00 }));
+      }
+    }
+  }
+
+  #[test]
+  fn inc_dec_preserve_carry_for_all_inputs() {
+    for val in 0..=255u8 {
+      for &f in ALL_FLAGS.iter() {
+        let (value, flags) = inc(val, f);
+        assert_eq!(value, val.wrapping_add(1));
+        assert_eq!(z(flags), value == 0);
+        assert!(!n(flags));
+        assert_eq!(h(flags), val & 0x0f == 0x0f);
+        assert_eq!(c(flags), c(f));
+
+        let (value, flags) = dec(val, f);
+        assert_eq!(value, val.wrapping_sub(1));
+        assert_eq!(z(flags), value == 0);
+        assert!(n(flags));
+        assert_eq!(h(flags), val & 0x0f == 0x00);
+        assert_eq!(c(flags), c(f));
+      }
+    }
+  }
+
+  fn to_bcd(val: u32) -> u8 {
+    return (((val / 10) % 10) << 4 | (val % 10)) as u8;
+  }
+
+  #[test]
+  fn daa_corrects_every_bcd_addition_and_subtraction() {
+    for x in 0..100u32 {
+      for y in 0..100u32 {
+        let (sum, f) = add(to_bcd(x), to_bcd(y));
+        let (value, flags) = daa(sum, f);
+        assert_eq!(value, to_bcd(x + y), "{} + {}", x, y);
+        assert_eq!(c(flags), x + y >= 100);
+        assert_eq!(z(flags), (x + y) % 100 == 0);
+        assert!(!h(flags));
+
+        let (difference, f) = sub(to_bcd(x), to_bcd(y));
+        let (value, flags) = daa(difference, f);
+        assert_eq!(value, to_bcd((x + 100 - y) % 100), "{} - {}", x, y);
+        assert_eq!(c(flags), x < y);
+        assert!(n(flags));
+      }
+    }
+  }
+
+  #[test]
+  fn daa_for_all_inputs_and_flags() {
+    for a in 0..=255u8 {
+      for &f in ALL_FLAGS.iter() {
+        let (value, flags) = daa(a, f);
+        let mut correction: u8 = 0;
+        if h(f) || (!n(f) && a % 16 > 9) {
+          correction += 0x06;
+        }
+        if c(f) || (!n(f) && a > 0x99) {
+          correction += 0x60;
+        }
+        let expected: u8 = if n(f) {
+          a.wrapping_sub(correction)
+        } else {
+          a.wrapping_add(correction)
+        };
+        assert_eq!(value, expected, "daa {:#x} with F={:#x}", a, f);
+        assert_eq!(z(flags), expected == 0);
+        assert_eq!(n(flags), n(f));
+        assert!(!h(flags));
+        assert_eq!(c(flags), correction >= 0x60);
+      }
+    }
+  }
+
+  #[test]
+  fn add16_preserves_zero_and_carries_from_bits_11_and_15() {
+    for a in (0..=0xffffu32).step_by(0x0101) {
+      for b in (0..=0xffffu32).step_by(0x0111) {
+        for &f in ALL_FLAGS.iter() {
+          let (value, flags) = add16(a as u16, b as u16, f);
+          assert_eq!(value as u32, (a + b) % 0x10000);
+          assert_eq!(z(flags), z(f));
+          assert!(!n(flags));
+          assert_eq!(h(flags), (a % 0x1000) + (b % 0x1000) >= 0x1000);
+          assert_eq!(c(flags), a + b >= 0x10000);
+        }
+      }
+    }
+    assert_eq!(add16(0x0fff, 0x0001, 0x00), (0x1000, 0x20));
+    assert_eq!(add16(0xffff, 0x0001, 0x80), (0x0000, 0xb0));
+  }
+
+  #[test]
+  fn add_sp_for_all_offsets() {
+    for sp in (0..=0xffffu32).step_by(0x0107).chain(vec![0x00ff, 0xfff8, 0xffff]) {
+      for offset in 0..=255u8 {
+        let (value, flags) = add_sp(sp as u16, offset);
+        assert_eq!(value as i32, (sp as i32 + offset as i8 as i32).rem_euclid(0x10000));
+        assert!(!z(flags));
+        assert!(!n(flags));
+        assert_eq!(h(flags), (sp % 16) + (offset as u32 % 16) >= 16);
+        assert_eq!(c(flags), (sp % 256) + offset as u32 >= 256);
+      }
+    }
+  }
+
+  #[test]
+  fn rotates_and_shifts_for_all_inputs() {
+    for val in 0..=255u8 {
+      let top: bool = val & 0x80 != 0;
+      let bottom: bool = val & 0x01 != 0;
+      let zf = |v: u8| if v == 0 { FLAG_ZERO } else { 0 };
+      let cf = |b: bool| if b { FLAG_CARRY } else { 0 };
+
+      let expected: u8 = val.rotate_left(1);
+      assert_eq!(rlc(val), (expected, zf(expected) | cf(top)));
+      assert_eq!(rlca(val), (expected, cf(top)));
+
+      let expected: u8 = val.rotate_right(1);
+      assert_eq!(rrc(val), (expected, zf(expected) | cf(bottom)));
+      assert_eq!(rrca(val), (expected, cf(bottom)));
+
+      for &carry in &[false, true] {
+        let expected: u8 = (val << 1) | if carry { 1 } else { 0 };
+        assert_eq!(rl(val, carry), (expected, zf(expected) | cf(top)));
+        assert_eq!(rla(val, carry), (expected, cf(top)));
+
+        let expected: u8 = (val >> 1) | if carry { 0x80 } else { 0 };
+        assert_eq!(rr(val, carry), (expected, zf(expected) | cf(bottom)));
+        assert_eq!(rra(val, carry), (expected, cf(bottom)));
+      }
+
+      let expected: u8 = val << 1;
+      assert_eq!(sla(val), (expected, zf(expected) | cf(top)));
+      let expected: u8 = (val >> 1) | (val & 0x80);
+      assert_eq!(sra(val), (expected, zf(expected) | cf(bottom)));
+      let expected: u8 = val >> 1;
+      assert_eq!(srl(val), (expected, zf(expected) | cf(bottom)));
+      let expected: u8 = val.rotate_left(4);
+      assert_eq!(swap(val), (expected, zf(expected)));
+    }
+  }
+
+  #[test]
+  fn bit_for_all_inputs_and_flags() {
+    for val in 0..=255u8 {
+      for index in 0..8u8 {
+        for &f in ALL_FLAGS.iter() {
+          let flags: u8 = bit(index, val, f);
+          assert_eq!(z(flags), (val >> index) & 1 == 0);
+          assert!(h(flags));
+          assert!(!n(flags));
+          assert_eq!(c(flags), c(f));
+        }
+      }
+    }
+  }
+
+  #[test]
+  fn flag_ops_for_all_flags() {
+    for &f in ALL_FLAGS.iter() {
+      assert_eq!(scf(f), (f & FLAG_ZERO) | FLAG_CARRY);
+      assert_eq!(ccf(f), (f & FLAG_ZERO) | if c(f) { 0 } else { FLAG_CARRY });
+      for a in 0..=255u8 {
+        assert_eq!(cpl(a, f), (!a, f | FLAG_NEGATIVE | FLAG_HALF_CARRY));
+      }
+    }
+  }
+}
@@ -0,0 +1,85 @@
+// Prints the disassembly of a ROM, bank by bank.
+//
+//   cargo run --bin disassemble -- game.gb [--sym game.sym]
+//     [--data [BANK:]START-END]...
+//
+// Each line shows the bank and address, the bytes, the instruction and its
+// cycles. `--sym` loads RGBDS labels. `--data` marks a range (hex
+// addresses, optionally in one bank only) to be listed as `db` bytes
+// instead of code.
+
+#![allow(clippy::needless_return)]
+
+use rust_webpack_template::game::disassembler::{format_line, DataRegion, Disassembler};
+use rust_webpack_template::game::symbols::Symbols;
+use std::process;
+
+fn usage() -> ! {
+  eprintln!("usage: disassemble ROM [--sym SYMFILE] [--data [BANK:]START-END]...");
+  process::exit(2);
+}
+
+fn parse_hex(text: &str) -> Option<u16> {
+  return u16::from_str_radix(text.trim_start_matches('$').trim_start_matches("0x"), 16).ok();
+}
+
+fn parse_region(text: &str) -> Option<DataRegion> {
+  let (bank, range) = match text.split_once(':') {
+    Some((bank, range)) => (Some(usize::from_str_radix(bank, 16).ok()?), range),
+    None => (None, text),
+  };
+  let (start, end) = range.split_once('-')?;
+  return Some(DataRegion {
+    bank,
+    start: parse_hex(start)?,
+    end: parse_hex(end)?,
+  });
+}
+
+fn read_file(path: &str) -> Vec<u8> {
+  return match std::fs::read(path) {
+    Ok(contents) => contents,
+    Err(error) => {
+      eprintln!("{}: {}", path, error);
+      process::exit(1);
+    }
+  };
+}
+
+fn main() {
+  let mut args = std::env::args().skip(1);
+  let mut path: Option<String> = None;
+  let mut disassembler: Disassembler = Default::default();
+  while let Some(arg) = args.next() {
+    if arg == "--data" {
+      let region: DataRegion = match args.next().as_deref().and_then(parse_region) {
+        Some(region) => region,
+        None => usage(),
+      };
+      disassembler.data_regions.push(region);
+    } else if arg == "--sym" {
+      let sym_path: String = args.next().unwrap_or_else(|| usage());
+      let text: String = String::from_utf8_lossy(&read_file(&sym_path)).into_owned();
+      disassembler.symbols = match Symbols::parse(&text) {
+        Ok(symbols) => symbols,
+        Err(error) => {
+          eprintln!("{}: {}", sym_path, error);
+          process::exit(1);
+        }
+      };
+    } else if path.is_none() {
+      path = Some(arg);
+    } else {
+      usage();
+    }
+  }
+  let path: String = path.unwrap_or_else(|| usage());
+
+  let rom: Vec<u8> = read_file(&path);
+  for line in disassembler.disassemble_rom(&rom).iter() {
+    if let Some(label) = &line.label {
+      println!("{}:", label);
+    }
+    println!("{}", format_line(line));
+  }
+}
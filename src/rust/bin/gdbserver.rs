@@ -0,0 +1,61 @@
+// Runs a ROM under a GDB remote stub on a local TCP port.
+//
+//   cargo run --bin gdbserver -- game.gb [--port 1234] [--sym game.sym]
+//   gdb -ex 'target remote localhost:1234'
+//
+// The game starts stopped at its entry point. GB_LOG sets the log filter,
+// e.g. `GB_LOG=info,gdb=debug` to see every packet.
+
+#![allow(clippy::needless_return)]
+
+use rust_webpack_template::game::debugger::Debugger;
+use rust_webpack_template::game::gdb::GdbStub;
+use rust_webpack_template::game::logging::{self, Filter};
+use rust_webpack_template::game::new_game;
+use std::process;
+
+fn usage() -> ! {
+  eprintln!("usage: gdbserver ROM [--port PORT] [--sym SYMFILE]");
+  process::exit(2);
+}
+
+fn fail(message: String) -> ! {
+  eprintln!("{}", message);
+  process::exit(1);
+}
+
+fn main() {
+  let filter: Filter =
+    Filter::parse(&std::env::var("GB_LOG").unwrap_or_default()).unwrap_or_else(|e| fail(e));
+  logging::init(filter).unwrap_or_else(|e| fail(e));
+
+  let mut args = std::env::args().skip(1);
+  let mut path: Option<String> = None;
+  let mut port: u16 = 1234;
+  let mut sym_path: Option<String> = None;
+  while let Some(arg) = args.next() {
+    if arg == "--port" {
+      port = args.next().and_then(|port| port.parse().ok()).unwrap_or_else(|| usage());
+    } else if arg == "--sym" {
+      sym_path = Some(args.next().unwrap_or_else(|| usage()));
+    } else if path.is_none() {
+      path = Some(arg);
+    } else {
+      usage();
+    }
+  }
+  let path: String = path.unwrap_or_else(|| usage());
+
+  let rom: Vec<u8> = std::fs::read(&path).unwrap_or_else(|e| fail(format!("{}: {}", path, e)));
+  let mut debugger: Debugger = Debugger::new(new_game(rom));
+  if let Some(sym_path) = sym_path {
+    let text: String = std::fs::read_to_string(&sym_path)
+      .unwrap_or_else(|e| fail(format!("{}: {}", sym_path, e)));
+    debugger.load_symbols(&text).unwrap_or_else(|e| fail(format!("{}: {}", sym_path, e)));
+  }
+
+  let mut stub: GdbStub = GdbStub::new(debugger);
+  if let Err(error) = stub.serve(("127.0.0.1", port)) {
+    fail(format!("gdbserver: {}", error));
+  }
+}
@@ -0,0 +1,162 @@
+// Runs Blargg's test ROMs headlessly and checks the result they report.
+//
+// The ROMs print their progress over the serial port, ending with "Passed"
+// or "Failed". Some also mirror it into cartridge RAM: 0xa001-0xa003 hold the
+// signature de b0 61, 0xa000 the status (0x80 while running, 0 on success)
+// and the text starts at 0xa004.
+//
+// The ROMs are not checked in. Point `BLARGG_ROMS` at a checkout of the
+// gb-test-roms directory, or place it at `test-roms/blargg`, then run
+// `cargo test -- --ignored`.
+
+use crate::game::{new_game, Game};
+use std::fs;
+use std::path::PathBuf;
+
+const CYCLES_PER_SECOND: u64 = 4_194_304;
+// How long to keep running once a result shows up, so the rest of the
+// message makes it out.
+const GRACE_CYCLES: u64 = CYCLES_PER_SECOND / 10;
+const SIGNATURE: [u8; 3] = [0xde, 0xb0, 0x61];
+
+#[derive(Debug)]
+enum Outcome {
+  Passed,
+  Failed(String),
+  TimedOut(String),
+}
+
+fn rom_dir() -> PathBuf {
+  return match std::env::var("BLARGG_ROMS") {
+    Ok(dir) => PathBuf::from(dir),
+    Err(_) => PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("test-roms/blargg"),
+  };
+}
+
+// The status byte at 0xa000, once the ROM has written the signature and
+// finished running.
+fn signature_status(game: &Game) -> Option<u8> {
+  let ram: &[u8] = &game.memory.cartridge.ram;
+  if ram.len() < 4 || ram[1..4] != SIGNATURE || ram[0] == 0x80 {
+    return None;
+  }
+  return Some(ram[0]);
+}
+
+fn signature_text(game: &Game) -> String {
+  return game.memory.cartridge.ram[4..]
+    .iter()
+    .take_while(|&&byte| byte != 0)
+    .map(|&byte| byte as char)
+    .collect();
+}
+
+// Blargg's ROMs park themselves on `jr -2` when they are done.
+fn at_exit_loop(game: &Game) -> bool {
+  let pc: u16 = game.registers.pc;
+  return game.memory.read_byte(pc) == 0x18 && game.memory.read_byte(pc.wrapping_add(1)) == 0xfe;
+}
+
+fn run(rom: Vec<u8>, budget: u64) -> Outcome {
+  let mut game: Game = new_game(rom);
+  let mut serial_seen: usize = 0;
+  let mut finish_by: Option<u64> = None;
+
+  while game.memory.ticks < budget {
+    game.step();
+
+    match finish_by {
+      Some(deadline) => {
+        if at_exit_loop(&game) || game.memory.ticks >= deadline {
+          break;
+        }
+      }
+      None => {
+        let serial: &str = &game.memory.serial_output;
+        let reported: bool = serial.len() != serial_seen
+          && (serial.contains("Passed") || serial.contains("Failed"));
+        serial_seen = serial.len();
+        if reported || signature_status(&game).is_some() {
+          finish_by = Some(game.memory.ticks + GRACE_CYCLES);
+        }
+      }
+    }
+  }
+
+  if let Some(status) = signature_status(&game) {
+    return match status {
+      0 => Outcome::Passed,
+      _ => Outcome::Failed(format!("status {:#04x}: {}", status, signature_text(&game))),
+    };
+  }
+  let serial: String = game.memory.serial_output.clone();
+  if finish_by.is_none() {
+    return Outcome::TimedOut(serial);
+  }
+  if serial.contains("Passed") && !serial.contains("Failed") {
+    return Outcome::Passed;
+  }
+  return Outcome::Failed(serial);
+}
+
+fn run_blargg(path: &str, seconds: u64) {
+  let rom_path: PathBuf = rom_dir().join(path);
+  let rom: Vec<u8> = fs::read(&rom_path)
+    .unwrap_or_else(|error| panic!("{}: {} (set BLARGG_ROMS)", rom_path.display(), error));
+
+  match run(rom, seconds * CYCLES_PER_SECOND) {
+    Outcome::Passed => {}
+    Outcome::Failed(output) => panic!("{} failed:\n{}", path, output),
+    Outcome::TimedOut(output) => {
+      panic!("{} did not finish within {}s:\n{}", path, seconds, output)
+    }
+  }
+}
+
+#[test]
+#[ignore = "needs Blargg's test ROMs (set BLARGG_ROMS)"]
+fn cpu_instrs() {
+  run_blargg("cpu_instrs/cpu_instrs.gb", 120);
+}
+
+#[test]
+#[ignore = "needs Blargg's test ROMs (set BLARGG_ROMS)"]
+fn instr_timing() {
+  run_blargg("instr_timing/instr_timing.gb", 10);
+}
+
+#[test]
+#[ignore = "needs Blargg's test ROMs (set BLARGG_ROMS)"]
+fn mem_timing() {
+  run_blargg("mem_timing/mem_timing.gb", 10);
+}
+
+#[test]
+#[ignore = "needs Blargg's test ROMs (set BLARGG_ROMS)"]
+fn halt_bug() {
+  run_blargg("halt_bug.gb", 10);
+}
+
+// A stand-in for a real ROM that prints `text` over serial and stops.
+fn serial_rom(text: &str) -> Vec<u8> {
+  let mut rom: Vec<u8> = vec![0; 0x8000];
+  let mut pc: usize = 0x100;
+  for byte in text.bytes() {
+    // LD A, byte; LDH (SB), A; LD A, 0x81; LDH (SC), A
+    rom[pc..pc + 8].copy_from_slice(&[0x3e, byte, 0xe0, 0x01, 0x3e, 0x81, 0xe0, 0x02]);
+    pc += 8;
+  }
+  // JR -2
+  rom[pc..pc + 2].copy_from_slice(&[0x18, 0xfe]);
+  return rom;
+}
+
+#[test]
+fn detects_serial_result() {
+  assert!(matches!(run(serial_rom("Passed\n"), CYCLES_PER_SECOND), Outcome::Passed));
+  match run(serial_rom("Failed #3\n"), CYCLES_PER_SECOND) {
+    Outcome::Failed(output) => assert_eq!(output, "Failed #3\n"),
+    outcome => panic!("unexpected {:?}", outcome),
+  }
+  assert!(matches!(run(serial_rom("01:ok "), CYCLES_PER_SECOND), Outcome::TimedOut(_)));
+}
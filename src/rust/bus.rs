@@ -0,0 +1,175 @@
+// The CPU's view of the rest of the machine.
+//
+// `read` and `write` are the accesses the CPU performs; `tick` advances
+// everything else. The CPU ticks one M-cycle before each access through the
+// provided `*_cycle` methods, so implementations see reads and writes at the
+// cycle they happen on real hardware.
+pub trait Bus {
+  fn read(&mut self, address: u16) -> u8;
+  fn write(&mut self, address: u16, val: u8);
+  fn tick(&mut self, cycles: u32);
+
+  // Interrupts that are both requested (IF) and enabled (IE).
+  fn pending_interrupts(&self) -> u8;
+  // Clears the IF bit of an interrupt the CPU has started servicing.
+  fn acknowledge_interrupt(&mut self, flag: u8);
+
+  fn read_cycle(&mut self, address: u16) -> u8 {
+    self.tick(4);
+    return self.read(address);
+  }
+
+  fn write_cycle(&mut self, address: u16, val: u8) {
+    self.tick(4);
+    self.write(address, val);
+  }
+
+  // An M-cycle where the CPU does internal work without touching the bus.
+  fn idle_cycle(&mut self) {
+    self.tick(4);
+  }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+  Idle,
+  Read(u16, u8),
+  Write(u16, u8),
+}
+
+// 64 KiB of RAM with no memory map or peripherals, for driving the CPU from
+// test harnesses and fuzzers or embedding it in other machines. IF (0xff0f)
+// and IE (0xffff) are ordinary RAM bytes, but still raise interrupts.
+pub struct FlatBus {
+  pub ram: Vec<u8>,
+  pub ticks: u64,
+  // When set, every M-cycle is appended to `activity`.
+  pub record: bool,
+  pub activity: Vec<Access>,
+}
+
+impl FlatBus {
+  pub fn new() -> FlatBus {
+    return FlatBus {
+      ram: vec![0; 0x10000],
+      ticks: 0,
+      record: false,
+      activity: Vec::new(),
+    };
+  }
+
+  fn log(&mut self, access: Access) {
+    if self.record {
+      if let Some(last) = self.activity.last_mut() {
+        *last = access;
+      }
+    }
+  }
+}
+
+impl Default for FlatBus {
+  fn default() -> FlatBus {
+    return FlatBus::new();
+  }
+}
+
+impl Bus for FlatBus {
+  fn read(&mut self, address: u16) -> u8 {
+    let val: u8 = self.ram[address as usize];
+    self.log(Access::Read(address, val));
+    return val;
+  }
+
+  fn write(&mut self, address: u16, val: u8) {
+    self.ram[address as usize] = val;
+    self.log(Access::Write(address, val));
+  }
+
+  fn tick(&mut self, cycles: u32) {
+    self.ticks += cycles as u64;
+    if self.record {
+      for _ in 0..(cycles / 4) {
+        self.activity.push(Access::Idle);
+      }
+    }
+  }
+
+  fn pending_interrupts(&self) -> u8 {
+    return self.ram[0xffff] & self.ram[0xff0f] & 0x1f;
+  }
+
+  fn acknowledge_interrupt(&mut self, flag: u8) {
+    self.ram[0xff0f] &= !flag;
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::game::cpu::Cpu;
+  use crate::game::registers::Registers;
+
+  fn machine(program: &[u8]) -> (Cpu, FlatBus, Registers) {
+    let mut bus: FlatBus = FlatBus::new();
+    bus.ram[0x100..0x100 + program.len()].copy_from_slice(program);
+    return (Default::default(), bus, Default::default());
+  }
+
+  #[test]
+  fn interrupt_dispatch_takes_five_m_cycles() {
+    // EI; NOP
+    let (mut cpu, mut bus, mut registers) = machine(&[0xfb, 0x00, 0x00]);
+    bus.ram[0xffff] = 0x04;
+    bus.ram[0xff0f] = 0x04;
+
+    cpu.step(&mut bus, &mut registers);
+    cpu.step(&mut bus, &mut registers);
+    assert_eq!(registers.pc, 0x102, "EI is delayed by one instruction");
+
+    let before: u64 = bus.ticks;
+    cpu.step(&mut bus, &mut registers);
+    assert_eq!(bus.ticks - before, 20);
+    assert_eq!(registers.pc, 0x50);
+    assert_eq!(registers.sp, 0xfffc);
+    assert_eq!(bus.ram[0xfffd], 0x01);
+    assert_eq!(bus.ram[0xfffc], 0x02);
+    assert_eq!(bus.ram[0xff0f], 0x00);
+    assert!(!cpu.interrupts_enabled());
+  }
+
+  #[test]
+  fn pushing_over_ie_cancels_dispatch() {
+    // With SP = 0x0000 the high byte of PC (0x02) lands on IE, disabling
+    // VBlank after dispatch started, so the CPU jumps to 0x0000 instead.
+    let (mut cpu, mut bus, mut registers) = machine(&[]);
+    registers.pc = 0x0200;
+    registers.sp = 0x0000;
+    bus.ram[0xffff] = 0x01;
+    bus.ram[0xff0f] = 0x01;
+    cpu.set_interrupts_enabled(true);
+
+    cpu.step(&mut bus, &mut registers);
+    assert_eq!(registers.pc, 0x0000);
+    assert_eq!(bus.ram[0xff0f], 0x01);
+  }
+
+  #[test]
+  fn records_bus_activity_per_m_cycle() {
+    // PUSH BC
+    let (mut cpu, mut bus, mut registers) = machine(&[0xc5]);
+    bus.record = true;
+    registers.set_bc(0x1234);
+    registers.sp = 0xd000;
+
+    cpu.step(&mut bus, &mut registers);
+    assert_eq!(
+      bus.activity,
+      vec![
+        Access::Read(0x100, 0xc5),
+        Access::Idle,
+        Access::Write(0xcfff, 0x12),
+        Access::Write(0xcffe, 0x34),
+      ]
+    );
+  }
+}
@@ -0,0 +1,182 @@
+// A shadow call stack, kept beside the real one from what each CPU step
+// did: CALL, RST and interrupt dispatch push a frame, and a frame is gone
+// once SP has moved above the slot holding its return address.
+//
+// Going by SP rather than pairing returns with calls keeps it right for
+// code that unwinds the stack itself: popping a return address and jumping,
+// or reloading SP, drops the frames it skips over. A RET used as a computed
+// jump pops nothing, since no frame owns the address it returns through.
+
+use crate::game::cpu::Step;
+use crate::game::instruction::Instruction;
+use crate::game::registers::Registers;
+
+// Frames beyond this are dropped from the bottom, so code that never
+// returns can't grow the shadow stack without limit.
+const MAX_FRAMES: usize = 256;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameKind {
+  Call,
+  Rst,
+  Interrupt,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Frame {
+  pub kind: FrameKind,
+  // The CALL or RST, or for interrupts the instruction that was about to
+  // run.
+  pub caller: u16,
+  // Where execution went: the routine or the interrupt vector.
+  pub target: u16,
+  pub return_address: u16,
+  // Where the return address was pushed.
+  pub slot: u16,
+}
+
+#[derive(Debug, Default)]
+pub struct CallStack {
+  // Outermost first.
+  frames: Vec<Frame>,
+}
+
+impl CallStack {
+  // Follows one step. `sp` is SP from before the step and `registers` the
+  // state after it.
+  pub fn update(&mut self, step: Step, sp: u16, registers: &Registers) {
+    let pushed: bool = registers.sp == sp.wrapping_sub(2);
+    let entered: Option<(FrameKind, u16, u16)> = match step {
+      Step::Instruction {
+        pc,
+        instruction: Instruction::Call(..),
+      } if pushed => Some((FrameKind::Call, pc, pc.wrapping_add(3))),
+      Step::Instruction {
+        pc,
+        instruction: Instruction::Rst(_),
+      } => Some((FrameKind::Rst, pc, pc.wrapping_add(1))),
+      Step::Interrupt { pc, .. } => Some((FrameKind::Interrupt, pc, pc)),
+      _ => None,
+    };
+
+    if let Some((kind, caller, return_address)) = entered {
+      if self.frames.len() == MAX_FRAMES {
+        self.frames.remove(0);
+      }
+      self.frames.push(Frame {
+        kind,
+        caller,
+        target: registers.pc,
+        return_address,
+        slot: registers.sp,
+      });
+    }
+    while self.frames.last().is_some_and(|frame| frame.slot < registers.sp) {
+      self.frames.pop();
+    }
+  }
+
+  // Outermost frame first.
+  pub fn frames(&self) -> &[Frame] {
+    return &self.frames;
+  }
+
+  // The frame whose return address is stored at `address`, if any.
+  pub fn frame_at_slot(&self, address: u16) -> Option<&Frame> {
+    return self.frames.iter().find(|frame| frame.slot == address);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::game::bus::FlatBus;
+  use crate::game::cpu::Cpu;
+
+  fn run(program: &[(u16, &[u8])], steps: usize, setup: impl Fn(&mut FlatBus)) -> Cpu {
+    let mut bus: FlatBus = FlatBus::new();
+    for (address, bytes) in program.iter() {
+      let start: usize = *address as usize;
+      bus.ram[start..start + bytes.len()].copy_from_slice(bytes);
+    }
+    setup(&mut bus);
+    let mut registers: Registers = Registers {
+      pc: 0x100,
+      sp: 0xd000,
+      ..Default::default()
+    };
+    let mut cpu: Cpu = Default::default();
+    for _ in 0..steps {
+      cpu.step(&mut bus, &mut registers);
+    }
+    return cpu;
+  }
+
+  fn summary(cpu: &Cpu) -> Vec<(FrameKind, u16, u16, u16)> {
+    return cpu
+      .call_stack
+      .frames()
+      .iter()
+      .map(|frame| (frame.kind, frame.caller, frame.target, frame.slot))
+      .collect();
+  }
+
+  #[test]
+  fn follows_calls_rsts_and_returns() {
+    // 0x100: CALL 0x200; untaken CALL NZ (Z is set by XOR A)
+    // 0x200: XOR A; CALL NZ,0x300; RST 0x08
+    // 0x008: RET
+    let program: [(u16, &[u8]); 3] = [
+      (0x100, &[0xcd, 0x00, 0x02]),
+      (0x200, &[0xaf, 0xc4, 0x00, 0x03, 0xcf]),
+      (0x008, &[0xc9]),
+    ];
+    let cpu: Cpu = run(&program, 4, |_| {});
+    assert_eq!(
+      summary(&cpu),
+      [
+        (FrameKind::Call, 0x100, 0x200, 0xcffe),
+        (FrameKind::Rst, 0x204, 0x008, 0xcffc),
+      ]
+    );
+    let cpu: Cpu = run(&program, 5, |_| {});
+    assert_eq!(summary(&cpu), [(FrameKind::Call, 0x100, 0x200, 0xcffe)]);
+    assert_eq!(cpu.call_stack.frames()[0].return_address, 0x103);
+  }
+
+  #[test]
+  fn marks_interrupts() {
+    // 0x100: EI; NOP; then the timer interrupt to 0x50.
+    let program: [(u16, &[u8]); 1] = [(0x100, &[0xfb, 0x00, 0x00])];
+    let cpu: Cpu = run(&program, 3, |bus| {
+      bus.ram[0xffff] = 0x04;
+      bus.ram[0xff0f] = 0x04;
+    });
+    assert_eq!(summary(&cpu), [(FrameKind::Interrupt, 0x102, 0x50, 0xcffe)]);
+  }
+
+  #[test]
+  fn drops_frames_the_game_unwinds_itself() {
+    // 0x100: CALL 0x200
+    // 0x200: CALL 0x300
+    // 0x300: POP HL; POP HL; JP 0x100 (discards both return addresses)
+    let program: [(u16, &[u8]); 3] = [
+      (0x100, &[0xcd, 0x00, 0x02]),
+      (0x200, &[0xcd, 0x00, 0x03]),
+      (0x300, &[0xe1, 0xe1, 0xc3, 0x00, 0x01]),
+    ];
+    let cpu: Cpu = run(&program, 3, |_| {});
+    assert_eq!(cpu.call_stack.frames().len(), 1);
+    let cpu: Cpu = run(&program, 4, |_| {});
+    assert!(cpu.call_stack.frames().is_empty());
+    // Back round to the first CALL, with nothing left over.
+    let cpu: Cpu = run(&program, 6, |_| {});
+    assert_eq!(summary(&cpu), [(FrameKind::Call, 0x100, 0x200, 0xcffe)]);
+    // A RET through a pushed address is a jump; it pops no frame.
+    // 0x100: CALL 0x200 / 0x200: LD HL,0x300; PUSH HL; RET / 0x300: NOP
+    let program: [(u16, &[u8]); 2] =
+      [(0x100, &[0xcd, 0x00, 0x02]), (0x200, &[0x21, 0x00, 0x03, 0xe5, 0xc9])];
+    let cpu: Cpu = run(&program, 4, |_| {});
+    assert_eq!(summary(&cpu), [(FrameKind::Call, 0x100, 0x200, 0xcffe)]);
+  }
+}
@@ -0,0 +1,159 @@
+// Cartridge ROM and RAM, with the memory bank controller named in the
+// header at 0x147 deciding which banks appear at 0x4000 and 0xa000.
+
+use crate::game::logging;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MbcKind {
+  RomOnly,
+  Mbc1,
+  Mbc3,
+  Mbc5,
+}
+
+pub struct Cartridge {
+  pub rom: Vec<u8>,
+  pub ram: Vec<u8>,
+  pub kind: MbcKind,
+
+  ram_enabled: bool,
+  rom_bank: u16,
+  // MBC1: the upper two bank bits. MBC3/MBC5: the RAM bank.
+  ram_bank: u8,
+  // MBC1 banking mode; when set the upper bits also bank 0x0000 and RAM.
+  advanced_banking: bool,
+}
+
+impl Cartridge {
+  pub fn new(rom: Vec<u8>) -> Cartridge {
+    let kind: MbcKind = match rom.get(0x147).copied().unwrap_or(0) {
+      0x01..=0x03 => MbcKind::Mbc1,
+      0x0f..=0x13 => MbcKind::Mbc3,
+      0x19..=0x1e => MbcKind::Mbc5,
+      _ => MbcKind::RomOnly,
+    };
+    let ram_size: usize = match rom.get(0x149).copied().unwrap_or(0) {
+      0x02 => 0x2000,
+      0x03 => 0x8000,
+      0x04 => 0x20000,
+      0x05 => 0x10000,
+      _ => 0,
+    };
+    log::info!(
+      target: logging::CART,
+      "{:?} with {} bytes of ROM and {} of RAM",
+      kind,
+      rom.len(),
+      ram_size
+    );
+    return Cartridge {
+      rom,
+      ram: vec![0; ram_size],
+      kind,
+      ram_enabled: false,
+      rom_bank: 1,
+      ram_bank: 0,
+      advanced_banking: false,
+    };
+  }
+
+  // The header flags that unlock SGB functions: 0x03 at 0x146 and the old
+  // licensee code 0x33 at 0x14b.
+  pub fn supports_sgb(&self) -> bool {
+    return self.rom.get(0x146) == Some(&0x03) && self.rom.get(0x14b) == Some(&0x33);
+  }
+
+  pub fn read_rom(&self, address: u16) -> u8 {
+    let bank: usize = if address < 0x4000 {
+      self.low_rom_bank()
+    } else {
+      self.high_rom_bank()
+    };
+    let offset: usize = bank * 0x4000 + (address as usize & 0x3fff);
+    return self.rom[offset % self.rom.len().max(1)];
+  }
+
+  // The ROM bank currently mapped at `address` (0x0000-0x7fff).
+  pub fn rom_bank_at(&self, address: u16) -> usize {
+    if address < 0x4000 {
+      return self.low_rom_bank();
+    }
+    return self.high_rom_bank();
+  }
+
+  // Writes to ROM space program the bank controller.
+  pub fn write_rom(&mut self, address: u16, val: u8) {
+    log::trace!(target: logging::MBC, "{:?} {:#06x} = {:#04x}", self.kind, address, val);
+    match (self.kind, address) {
+      (MbcKind::RomOnly, _) => {}
+      (_, 0x0000..=0x1fff) => self.ram_enabled = (val & 0x0f) == 0x0a,
+      (MbcKind::Mbc1, 0x2000..=0x3fff) => self.rom_bank = (val & 0x1f) as u16,
+      (MbcKind::Mbc1, 0x4000..=0x5fff) => self.ram_bank = val & 0x03,
+      (MbcKind::Mbc1, _) => self.advanced_banking = (val & 0x01) != 0,
+      (MbcKind::Mbc3, 0x2000..=0x3fff) => self.rom_bank = (val & 0x7f) as u16,
+      (MbcKind::Mbc3, 0x4000..=0x5fff) => self.ram_bank = val,
+      (MbcKind::Mbc3, _) => {}
+      (MbcKind::Mbc5, 0x2000..=0x2fff) => self.rom_bank = (self.rom_bank & 0x100) | val as u16,
+      (MbcKind::Mbc5, 0x3000..=0x3fff) => {
+        self.rom_bank = (self.rom_bank & 0xff) | (((val & 0x01) as u16) << 8)
+      }
+      (MbcKind::Mbc5, 0x4000..=0x5fff) => self.ram_bank = val & 0x0f,
+      (MbcKind::Mbc5, _) => {}
+    }
+  }
+
+  pub fn read_ram(&self, address: u16) -> u8 {
+    return match self.ram_offset(address) {
+      Some(offset) => self.ram[offset],
+      None => 0xff,
+    };
+  }
+
+  pub fn write_ram(&mut self, address: u16, val: u8) {
+    if let Some(offset) = self.ram_offset(address) {
+      self.ram[offset] = val;
+    }
+  }
+
+  fn low_rom_bank(&self) -> usize {
+    if self.kind == MbcKind::Mbc1 && self.advanced_banking {
+      return (self.ram_bank as usize) << 5;
+    }
+    return 0;
+  }
+
+  fn high_rom_bank(&self) -> usize {
+    return match self.kind {
+      MbcKind::RomOnly => 1,
+      // Bank 0 can't be selected in the low five bits, so it maps to 1.
+      MbcKind::Mbc1 => ((self.ram_bank as usize) << 5) | (self.rom_bank.max(1) as usize),
+      MbcKind::Mbc3 => self.rom_bank.max(1) as usize,
+      MbcKind::Mbc5 => self.rom_bank as usize,
+    };
+  }
+
+  fn ram_offset(&self, address: u16) -> Option<usize> {
+    if self.ram.is_empty() || (self.kind != MbcKind::RomOnly && !self.ram_enabled) {
+      return None;
+    }
+    let bank: usize = match self.kind {
+      MbcKind::RomOnly => 0,
+      MbcKind::Mbc1 => {
+        if self.advanced_banking {
+          self.ram_bank as usize
+        } else {
+          0
+        }
+      }
+      // MBC3 banks 0x08-0x0c select RTC registers, which aren't emulated.
+      MbcKind::Mbc3 => {
+        if self.ram_bank > 0x03 {
+          return None;
+        }
+        self.ram_bank as usize
+      }
+      MbcKind::Mbc5 => self.ram_bank as usize,
+    };
+    return Some((bank * 0x2000 + (address as usize & 0x1fff)) % self.ram.len());
+  }
+}
@@ -0,0 +1,625 @@
+// Runs a `Game` under the host's control: breakpoints, watchpoints and
+// stepping. Every run returns why it stopped.
+//
+// Execution only ever stops between instructions. A watchpoint that fires
+// part-way through an instruction stops once that instruction has finished.
+
+use crate::game::bus::Bus;
+use crate::game::call_stack::{Frame, FrameKind};
+use crate::game::cpu::Step;
+use crate::game::disassembler::{Disassembler, Line};
+use crate::game::expression::Expression;
+use crate::game::instruction::{decode, Instruction};
+use crate::game::memory::Memory;
+use crate::game::symbols::{Symbol, Symbols};
+use crate::game::Game;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Breakpoint {
+  pub address: u16,
+  // For addresses in ROM, only stop while this bank is mapped there.
+  pub bank: Option<usize>,
+  // Only stop when this evaluates to true.
+  pub condition: Option<Expression>,
+  pub enabled: bool,
+}
+
+impl Breakpoint {
+  pub fn new(address: u16) -> Breakpoint {
+    return Breakpoint {
+      address,
+      bank: None,
+      condition: None,
+      enabled: true,
+    };
+  }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccessKind {
+  Read,
+  Write,
+  Execute,
+}
+
+// Watches CPU accesses to `start..=end`. DMA and the PPU are not watched.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Watchpoint {
+  pub start: u16,
+  pub end: u16,
+  pub read: bool,
+  pub write: bool,
+  pub execute: bool,
+  pub enabled: bool,
+}
+
+impl Watchpoint {
+  fn watches(&self, address: u16, kind: AccessKind) -> bool {
+    let wanted: bool = match kind {
+      AccessKind::Read => self.read,
+      AccessKind::Write => self.write,
+      AccessKind::Execute => self.execute,
+    };
+    return self.enabled && wanted && (self.start..=self.end).contains(&address);
+  }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BreakReason {
+  // The breakpoint with this id is at PC.
+  Breakpoint(usize),
+  Watchpoint {
+    id: usize,
+    address: u16,
+    kind: AccessKind,
+    // The byte read or written, or the opcode about to execute.
+    value: u8,
+  },
+  // A step, step over or step out finished.
+  Step,
+  // Reached the address given to `run_to`.
+  Cursor,
+  // The cycle budget given to the run ran out first.
+  CycleLimit,
+}
+
+// One 16-bit word of the stack as it sits in memory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StackWord {
+  pub address: u16,
+  pub value: u16,
+  // The shadow call stack frame whose return address this is.
+  pub frame: Option<Frame>,
+}
+
+pub struct Debugger {
+  pub game: Game,
+  pub disassembler: Disassembler,
+  breakpoints: Vec<(usize, Breakpoint)>,
+  watchpoints: Vec<(usize, Watchpoint)>,
+  next_id: usize,
+}
+
+// Forwards the CPU's accesses to memory and notes the first one that hits a
+// watchpoint. Reads of the instruction's own bytes are fetches, not data
+// reads, and are left out.
+struct WatchedBus<'a> {
+  memory: &'a mut Memory,
+  watchpoints: &'a [(usize, Watchpoint)],
+  fetch: (u16, u16),
+  hit: Option<BreakReason>,
+}
+
+impl WatchedBus<'_> {
+  fn check(&mut self, address: u16, kind: AccessKind, value: u8) {
+    if self.hit.is_some() {
+      return;
+    }
+    let fetching: bool = address.wrapping_sub(self.fetch.0) < self.fetch.1;
+    if kind == AccessKind::Read && fetching {
+      return;
+    }
+    if let Some(&(id, _)) = self.watchpoints.iter().find(|(_, w)| w.watches(address, kind)) {
+      self.hit = Some(BreakReason::Watchpoint { id, address, kind, value });
+    }
+  }
+}
+
+impl Bus for WatchedBus<'_> {
+  fn read(&mut self, address: u16) -> u8 {
+    let value: u8 = self.memory.read(address);
+    self.check(address, AccessKind::Read, value);
+    return value;
+  }
+
+  fn write(&mut self, address: u16, val: u8) {
+    self.check(address, AccessKind::Write, val);
+    self.memory.write(address, val);
+  }
+
+  fn tick(&mut self, cycles: u32) {
+    self.memory.tick(cycles);
+  }
+
+  fn pending_interrupts(&self) -> u8 {
+    return self.memory.pending_interrupts();
+  }
+
+  fn acknowledge_interrupt(&mut self, flag: u8) {
+    self.memory.acknowledge_interrupt(flag);
+  }
+}
+
+impl Debugger {
+  pub fn new(game: Game) -> Debugger {
+    return Debugger {
+      game,
+      disassembler: Default::default(),
+      breakpoints: Vec::new(),
+      watchpoints: Vec::new(),
+      next_id: 0,
+    };
+  }
+
+  // Loads an RGBDS `.sym` file, replacing any symbols already loaded.
+  pub fn load_symbols(&mut self, text: &str) -> Result<(), String> {
+    self.disassembler.symbols = Symbols::parse(text)?;
+    return Ok(());
+  }
+
+  // `address` as a label and offset, with the currently mapped ROM bank.
+  pub fn describe(&self, address: u16) -> Option<String> {
+    let bank: usize = self.game.memory.cartridge.rom_bank_at(0x4000);
+    return self.disassembler.symbols.describe(bank, address);
+  }
+
+  // A breakpoint on the label `name`, restricted to the label's bank when it
+  // is in switchable ROM.
+  pub fn breakpoint_at_label(&self, name: &str) -> Result<Breakpoint, String> {
+    let symbol: &Symbol = match self.disassembler.symbols.find(name) {
+      Some(symbol) => symbol,
+      None => return Err(format!("unknown label '{}'", name)),
+    };
+    let mut breakpoint: Breakpoint = Breakpoint::new(symbol.address);
+    if (0x4000..0x8000).contains(&symbol.address) {
+      breakpoint.bank = Some(symbol.bank);
+    }
+    return Ok(breakpoint);
+  }
+
+  // The shadow call stack, outermost frame first.
+  pub fn call_stack(&self) -> &[Frame] {
+    return self.game.cpu.call_stack.frames();
+  }
+
+  // The call stack innermost first, one line per frame, with labels when
+  // symbols are loaded:
+  //
+  //   $4123 LoadLevel+$23, called from $0156 Main+$6
+  //   $0050 Timer, interrupted $0203
+  pub fn backtrace(&self) -> Vec<String> {
+    let place = |address: u16| -> String {
+      return match self.describe(address) {
+        Some(label) => format!("${:04x} {}", address, label),
+        None => format!("${:04x}", address),
+      };
+    };
+    return self
+      .call_stack()
+      .iter()
+      .rev()
+      .map(|frame| {
+        let how: &str = match frame.kind {
+          FrameKind::Call => "called from",
+          FrameKind::Rst => "rst from",
+          FrameKind::Interrupt => "interrupted",
+        };
+        format!("{}, {} {}", place(frame.target), how, place(frame.caller))
+      })
+      .collect();
+  }
+
+  // `count` words of memory from SP upwards, marking the return addresses
+  // the call stack knows about.
+  pub fn stack_view(&self, count: usize) -> Vec<StackWord> {
+    let memory: &Memory = &self.game.memory;
+    let sp: u16 = self.game.registers.sp;
+    return (0..count as u16)
+      .map(|i| {
+        let address: u16 = sp.wrapping_add(i * 2);
+        let value: u16 = memory.peek_byte(address) as u16
+          | (memory.peek_byte(address.wrapping_add(1)) as u16) << 8;
+        StackWord {
+          address,
+          value,
+          frame: self.game.cpu.call_stack.frame_at_slot(address).copied(),
+        }
+      })
+      .collect();
+  }
+
+  // Breakpoints and watchpoints share one sequence of ids.
+  pub fn add_breakpoint(&mut self, breakpoint: Breakpoint) -> usize {
+    self.next_id += 1;
+    self.breakpoints.push((self.next_id, breakpoint));
+    return self.next_id;
+  }
+
+  pub fn add_watchpoint(&mut self, watchpoint: Watchpoint) -> usize {
+    self.next_id += 1;
+    self.watchpoints.push((self.next_id, watchpoint));
+    return self.next_id;
+  }
+
+  pub fn breakpoint_mut(&mut self, id: usize) -> Option<&mut Breakpoint> {
+    return self.breakpoints.iter_mut().find(|(i, _)| *i == id).map(|(_, b)| b);
+  }
+
+  pub fn watchpoint_mut(&mut self, id: usize) -> Option<&mut Watchpoint> {
+    return self.watchpoints.iter_mut().find(|(i, _)| *i == id).map(|(_, w)| w);
+  }
+
+  // Removes the breakpoint or watchpoint with this id.
+  pub fn remove(&mut self, id: usize) {
+    self.breakpoints.retain(|(i, _)| *i != id);
+    self.watchpoints.retain(|(i, _)| *i != id);
+  }
+
+  pub fn breakpoints(&self) -> impl Iterator<Item = &(usize, Breakpoint)> {
+    return self.breakpoints.iter();
+  }
+
+  pub fn watchpoints(&self) -> impl Iterator<Item = &(usize, Watchpoint)> {
+    return self.watchpoints.iter();
+  }
+
+  // The instruction at PC and its length, read without side effects.
+  pub fn next_instruction(&self) -> (Instruction, u16) {
+    let memory: &Memory = &self.game.memory;
+    let pc: u16 = self.game.registers.pc;
+    let mut length: u16 = 1;
+    let instruction: Instruction = decode(memory.peek_byte(pc), || {
+      let byte: u8 = memory.peek_byte(pc.wrapping_add(length));
+      length += 1;
+      byte
+    });
+    return (instruction, length);
+  }
+
+  // `count` lines of disassembly starting at `address`, for the code view.
+  pub fn disassemble(&self, address: u16, count: usize) -> Vec<Line> {
+    return self.disassembler.disassemble_memory(&self.game.memory, address, count);
+  }
+
+  // Runs until a breakpoint or watchpoint triggers, or `max_cycles` T-cycles
+  // have passed.
+  pub fn run(&mut self, max_cycles: u64) -> BreakReason {
+    return self.run_until(max_cycles, |_| false);
+  }
+
+  // Runs to `address`, stopping earlier for breakpoints and watchpoints.
+  pub fn run_to(&mut self, address: u16, max_cycles: u64) -> BreakReason {
+    let reason: BreakReason = self.run_until(max_cycles, |debugger| {
+      debugger.game.registers.pc == address && !debugger.game.cpu.halted()
+    });
+    return if reason == BreakReason::Step { BreakReason::Cursor } else { reason };
+  }
+
+  // Executes one instruction (or services one interrupt).
+  pub fn step_into(&mut self) -> BreakReason {
+    if let Some(hit) = self.execute() {
+      return hit;
+    }
+    return BreakReason::Step;
+  }
+
+  // Like `step_into`, but runs a CALL or RST through to its return.
+  pub fn step_over(&mut self, max_cycles: u64) -> BreakReason {
+    let (instruction, length) = self.next_instruction();
+    if !matches!(instruction, Instruction::Call(..) | Instruction::Rst(_)) {
+      return self.step_into();
+    }
+    let return_address: u16 = self.game.registers.pc.wrapping_add(length);
+    let sp: u16 = self.game.registers.sp;
+    // A recursive call can come back to the same address deeper in the
+    // stack, so the stack has to be back where it started too.
+    return self.run_until(max_cycles, |debugger| {
+      let registers = &debugger.game.registers;
+      registers.pc == return_address && registers.sp >= sp
+    });
+  }
+
+  // Runs until the current function returns to its caller.
+  pub fn step_out(&mut self, max_cycles: u64) -> BreakReason {
+    let sp: u16 = self.game.registers.sp;
+    return self.run_until(max_cycles, |debugger| {
+      let returned: bool = matches!(
+        debugger.game.cpu.last_step,
+        Step::Instruction {
+          instruction: Instruction::Ret(_) | Instruction::Reti,
+          ..
+        }
+      );
+      returned && debugger.game.registers.sp > sp
+    });
+  }
+
+  // The first instruction always runs, so resuming from a breakpoint moves
+  // past it. After that, breakpoints are checked before each instruction and
+  // `done` after it.
+  fn run_until<F: Fn(&Debugger) -> bool>(&mut self, max_cycles: u64, done: F) -> BreakReason {
+    let deadline: u64 = self.game.memory.ticks.saturating_add(max_cycles);
+    let mut first: bool = true;
+    loop {
+      if !first {
+        if let Some(reason) = self.check_breakpoints() {
+          return reason;
+        }
+      }
+      first = false;
+      if self.game.memory.ticks >= deadline {
+        return BreakReason::CycleLimit;
+      }
+      if let Some(hit) = self.execute() {
+        return hit;
+      }
+      if done(self) {
+        return BreakReason::Step;
+      }
+    }
+  }
+
+  fn check_breakpoints(&self) -> Option<BreakReason> {
+    if self.game.cpu.halted() {
+      return None;
+    }
+    let pc: u16 = self.game.registers.pc;
+    for (id, breakpoint) in self.breakpoints.iter() {
+      if breakpoint.enabled && breakpoint.address == pc && self.bank_matches(breakpoint) {
+        let met: bool = match &breakpoint.condition {
+          Some(condition) => condition.is_true(&self.game.registers, &self.game.memory),
+          None => true,
+        };
+        if met {
+          return Some(BreakReason::Breakpoint(*id));
+        }
+      }
+    }
+    let opcode: u8 = self.game.memory.peek_byte(pc);
+    for (id, watchpoint) in self.watchpoints.iter() {
+      if watchpoint.watches(pc, AccessKind::Execute) {
+        return Some(BreakReason::Watchpoint {
+          id: *id,
+          address: pc,
+          kind: AccessKind::Execute,
+          value: opcode,
+        });
+      }
+    }
+    return None;
+  }
+
+  fn bank_matches(&self, breakpoint: &Breakpoint) -> bool {
+    return match breakpoint.bank {
+      Some(bank) if breakpoint.address < 0x8000 => {
+        self.game.memory.cartridge.rom_bank_at(breakpoint.address) == bank
+      }
+      _ => true,
+    };
+  }
+
+  // Steps the CPU once, returning the first read or write watchpoint hit.
+  fn execute(&mut self) -> Option<BreakReason> {
+    let (_, length) = self.next_instruction();
+    let game: &mut Game = &mut self.game;
+    let mut bus: WatchedBus = WatchedBus {
+      memory: &mut game.memory,
+      watchpoints: &self.watchpoints,
+      fetch: (game.registers.pc, length),
+      hit: None,
+    };
+    game.cpu.step(&mut bus, &mut game.registers);
+    return bus.hit;
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::game::new_game;
+
+  // Loads `program` at 0x100 of a 32KB ROM with no MBC.
+  fn debugger(program: &[u8]) -> Debugger {
+    let mut rom: Vec<u8> = vec![0; 0x8000];
+    rom[0x100..0x100 + program.len()].copy_from_slice(program);
+    return Debugger::new(new_game(rom));
+  }
+
+  const BUDGET: u64 = 10_000;
+
+  // 0x100: LD A,5; CALL 0x110; INC A; JR 0x105 (loops on INC A)
+  // 0x110: LD B,A; CALL 0x120; RET
+  // 0x120: LD (0xc000),A; LD A,(0xc001); RET
+  fn program() -> Vec<u8> {
+    let mut program: Vec<u8> = vec![0; 0x30];
+    program[0x00..0x07].copy_from_slice(&[0x3e, 0x05, 0xcd, 0x10, 0x01, 0x3c, 0x18]);
+    program[0x07] = 0xfd;
+    program[0x10..0x15].copy_from_slice(&[0x47, 0xcd, 0x20, 0x01, 0xc9]);
+    program[0x20..0x27].copy_from_slice(&[0xea, 0x00, 0xc0, 0xfa, 0x01, 0xc0, 0xc9]);
+    return program;
+  }
+
+  #[test]
+  fn stops_at_breakpoints_and_resumes_past_them() {
+    let mut debugger: Debugger = debugger(&program());
+    let id: usize = debugger.add_breakpoint(Breakpoint::new(0x105));
+    assert_eq!(debugger.run(BUDGET), BreakReason::Breakpoint(id));
+    assert_eq!(debugger.game.registers.pc, 0x105);
+    // A came back as the 0 read from 0xc001; INC A; JR loops straight back.
+    assert_eq!(debugger.run(BUDGET), BreakReason::Breakpoint(id));
+    assert_eq!(debugger.game.registers.a, 1);
+
+    debugger.breakpoint_mut(id).unwrap().enabled = false;
+    assert_eq!(debugger.run(100), BreakReason::CycleLimit);
+  }
+
+  #[test]
+  fn runs_without_a_cycle_limit() {
+    let mut debugger: Debugger = debugger(&program());
+    let id: usize = debugger.add_breakpoint(Breakpoint::new(0x105));
+    assert_eq!(debugger.run(u64::MAX), BreakReason::Breakpoint(id));
+    assert_eq!(debugger.run(u64::MAX), BreakReason::Breakpoint(id));
+    assert_eq!(debugger.game.registers.pc, 0x105);
+  }
+
+  #[test]
+  fn conditional_and_banked_breakpoints() {
+    let mut debugger: Debugger = debugger(&program());
+    let mut breakpoint: Breakpoint = Breakpoint::new(0x105);
+    breakpoint.condition = Some(Expression::parse("A == 8").unwrap());
+    let id: usize = debugger.add_breakpoint(breakpoint);
+    assert_eq!(debugger.run(BUDGET), BreakReason::Breakpoint(id));
+    assert_eq!(debugger.game.registers.a, 8);
+    debugger.remove(id);
+
+    // Bank 0 is always mapped at 0x0000-0x3fff, so bank 1 never matches.
+    let mut banked: Breakpoint = Breakpoint::new(0x105);
+    banked.bank = Some(1);
+    debugger.add_breakpoint(banked);
+    assert_eq!(debugger.run(200), BreakReason::CycleLimit);
+  }
+
+  #[test]
+  fn watchpoints_report_the_access() {
+    let mut debugger: Debugger = debugger(&program());
+    let write: usize = debugger.add_watchpoint(Watchpoint {
+      start: 0xc000,
+      end: 0xc001,
+      read: true,
+      write: true,
+      execute: false,
+      enabled: true,
+    });
+    let reason: BreakReason = debugger.run(BUDGET);
+    assert_eq!(
+      reason,
+      BreakReason::Watchpoint { id: write, address: 0xc000, kind: AccessKind::Write, value: 5 }
+    );
+    // Stopped after the LD (0xc000),A that did the write.
+    assert_eq!(debugger.game.registers.pc, 0x123);
+    assert!(matches!(
+      debugger.run(BUDGET),
+      BreakReason::Watchpoint { address: 0xc001, kind: AccessKind::Read, .. }
+    ));
+
+    // Fetching the operands of an instruction isn't a read.
+    let mut debugger: Debugger = debugger_with_fetch_watch();
+    assert_eq!(debugger.run(40), BreakReason::CycleLimit);
+  }
+
+  fn debugger_with_fetch_watch() -> Debugger {
+    let mut debugger: Debugger = debugger(&program());
+    debugger.add_watchpoint(Watchpoint {
+      start: 0x101,
+      end: 0x104,
+      read: true,
+      write: false,
+      execute: false,
+      enabled: true,
+    });
+    return debugger;
+  }
+
+  #[test]
+  fn execute_watchpoints_stop_before_the_instruction() {
+    let mut debugger: Debugger = debugger(&program());
+    let id: usize = debugger.add_watchpoint(Watchpoint {
+      start: 0x120,
+      end: 0x12f,
+      read: false,
+      write: false,
+      execute: true,
+      enabled: true,
+    });
+    let reason: BreakReason = debugger.run(BUDGET);
+    assert_eq!(
+      reason,
+      BreakReason::Watchpoint { id, address: 0x120, kind: AccessKind::Execute, value: 0xea }
+    );
+  }
+
+  #[test]
+  fn steps_into_over_and_out_of_calls() {
+    let mut debugger: Debugger = debugger(&program());
+    assert_eq!(debugger.step_into(), BreakReason::Step);
+    assert_eq!(debugger.game.registers.pc, 0x102);
+    assert_eq!(debugger.step_over(BUDGET), BreakReason::Step);
+    assert_eq!(debugger.game.registers.pc, 0x105);
+    assert_eq!(debugger.game.registers.b, 5);
+
+    let mut debugger: Debugger = self::debugger(&program());
+    debugger.step_into();
+    debugger.step_into();
+    assert_eq!(debugger.game.registers.pc, 0x110);
+    debugger.step_into();
+    debugger.step_into();
+    assert_eq!(debugger.game.registers.pc, 0x120);
+    // Out of 0x120 back into 0x110, then out of that back to the top.
+    assert_eq!(debugger.step_out(BUDGET), BreakReason::Step);
+    assert_eq!(debugger.game.registers.pc, 0x114);
+    assert_eq!(debugger.step_out(BUDGET), BreakReason::Step);
+    assert_eq!(debugger.game.registers.pc, 0x105);
+  }
+
+  #[test]
+  fn disassembles_from_pc() {
+    let debugger: Debugger = debugger(&program());
+    let lines: Vec<Line> = debugger.disassemble(debugger.game.registers.pc, 3);
+    let text: Vec<&str> = lines.iter().map(|line| line.text.as_str()).collect();
+    assert_eq!(text, ["ld a, $05", "call $0110", "inc a"]);
+  }
+
+  #[test]
+  fn breaks_on_labels() {
+    let mut debugger: Debugger = debugger(&program());
+    debugger.load_symbols("00:0110 Outer\n00:0120 Inner\n").unwrap();
+    assert!(debugger.breakpoint_at_label("Missing").is_err());
+    let id: usize = debugger.add_breakpoint(debugger.breakpoint_at_label("Inner").unwrap());
+    assert_eq!(debugger.run(BUDGET), BreakReason::Breakpoint(id));
+    assert_eq!(debugger.game.registers.pc, 0x120);
+    assert_eq!(debugger.describe(0x123).as_deref(), Some("Inner+$3"));
+    let text: Vec<String> = debugger.disassemble(0x110, 2).into_iter().map(|l| l.text).collect();
+    assert_eq!(text, ["ld b, a", "call Inner"]);
+  }
+
+  #[test]
+  fn shows_the_call_stack_and_raw_stack() {
+    let mut debugger: Debugger = debugger(&program());
+    debugger.load_symbols("00:0100 Main\n00:0120 Inner\n").unwrap();
+    debugger.game.registers.sp = 0xdff0;
+    assert_eq!(debugger.run_to(0x123, BUDGET), BreakReason::Cursor);
+    assert_eq!(
+      debugger.backtrace(),
+      [
+        "$0120 Inner, called from $0111 Main+$11",
+        "$0110 Main+$10, called from $0102 Main+$2",
+      ]
+    );
+    let words: Vec<StackWord> = debugger.stack_view(3);
+    let values: Vec<(u16, u16)> = words.iter().map(|word| (word.address, word.value)).collect();
+    assert_eq!(values[..2], [(0xdfec, 0x0114), (0xdfee, 0x0105)]);
+    assert_eq!(words[0].frame.map(|frame| frame.caller), Some(0x111));
+    assert_eq!(words[2].frame, None);
+    assert_eq!(debugger.step_out(BUDGET), BreakReason::Step);
+    assert_eq!(debugger.call_stack().len(), 1);
+  }
+
+  #[test]
+  fn runs_to_the_cursor() {
+    let mut debugger: Debugger = debugger(&program());
+    assert_eq!(debugger.run_to(0x120, BUDGET), BreakReason::Cursor);
+    assert_eq!(debugger.game.registers.pc, 0x120);
+    let id: usize = debugger.add_breakpoint(Breakpoint::new(0x126));
+    assert_eq!(debugger.run_to(0x107, BUDGET), BreakReason::Breakpoint(id));
+  }
+}
@@ -0,0 +1,527 @@
+// Turns machine code back into RGBDS assembly, one instruction per line.
+//
+// Mnemonics and registers are lowercase, numbers are `$` hex, and jump
+// targets are absolute addresses. Byte ranges marked as data come out as
+// `db` lines instead of being decoded. With symbols loaded, lines carry the
+// label at their address and jump targets and memory operands use labels.
+
+use crate::game::instruction::{
+  decode, AluOp, Condition, Instruction, R16Memory, R16Stack, ShiftOp, R16, R8,
+};
+use crate::game::memory::Memory;
+use crate::game::symbols::Symbols;
+
+const BANK_SIZE: usize = 0x4000;
+// Bytes per `db` line in data regions.
+const DATA_PER_LINE: usize = 8;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Line {
+  pub address: u16,
+  // The ROM bank the bytes came from; 0 outside 0x4000-0x7fff.
+  pub bank: usize,
+  // The symbol at this address, if any.
+  pub label: Option<String>,
+  pub bytes: Vec<u8>,
+  pub text: String,
+  // T-cycles, and for conditional jumps, calls and returns the cycles when
+  // the branch is taken. Zero for data.
+  pub cycles: u8,
+  pub taken_cycles: Option<u8>,
+}
+
+// A range of addresses that holds data rather than code, optionally only in
+// one bank.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DataRegion {
+  pub bank: Option<usize>,
+  pub start: u16,
+  pub end: u16,
+}
+
+#[derive(Debug, Default)]
+pub struct Disassembler {
+  pub data_regions: Vec<DataRegion>,
+  pub symbols: Symbols,
+}
+
+impl Disassembler {
+  fn data_region(&self, bank: usize, address: u16) -> Option<&DataRegion> {
+    return self.data_regions.iter().find(|region| {
+      region.bank.is_none_or(|b| b == bank) && (region.start..=region.end).contains(&address)
+    });
+  }
+
+  // Disassembles one line at `address`, reading bytes through `read`, with
+  // ROM bank `bank` mapped at 0x4000-0x7fff.
+  pub fn line_at<F: Fn(u16) -> u8>(&self, read: F, address: u16, bank: usize) -> Line {
+    let label: Option<String> = self.symbols.label_at(bank, address).map(String::from);
+    let bank: usize = if (0x4000..0x8000).contains(&address) { bank } else { 0 };
+    if let Some(region) = self.data_region(bank, address) {
+      let count: usize = (region.end - address) as usize + 1;
+      let bytes: Vec<u8> = (0..count.min(DATA_PER_LINE))
+        .map(|offset| read(address.wrapping_add(offset as u16)))
+        .collect();
+      return Line {
+        address,
+        bank,
+        label,
+        text: db(&bytes),
+        bytes,
+        cycles: 0,
+        taken_cycles: None,
+      };
+    }
+
+    let mut bytes: Vec<u8> = vec![read(address)];
+    let instruction: Instruction = decode(bytes[0], || {
+      let byte: u8 = read(address.wrapping_add(bytes.len() as u16));
+      bytes.push(byte);
+      byte
+    });
+    let next: u16 = address.wrapping_add(bytes.len() as u16);
+    let mut text: String = format_instruction(instruction, next);
+    if let Some((operand, target)) = referenced_address(instruction, next) {
+      if let Some(name) = self.symbols.label_at(bank, target) {
+        text = text.replacen(&operand, name, 1);
+      }
+    }
+    let (cycles, taken_cycles) = cycles(instruction);
+    return Line {
+      address,
+      bank,
+      label,
+      bytes,
+      text,
+      cycles,
+      taken_cycles,
+    };
+  }
+
+  // `count` lines from `address` as the CPU currently sees memory.
+  pub fn disassemble_memory(&self, memory: &Memory, address: u16, count: usize) -> Vec<Line> {
+    let mut lines: Vec<Line> = Vec::with_capacity(count);
+    let mut address: u16 = address;
+    let bank: usize = memory.cartridge.rom_bank_at(0x4000);
+    for _ in 0..count {
+      let line: Line = self.line_at(|a| memory.peek_byte(a), address, bank);
+      address = address.wrapping_add(line.bytes.len() as u16);
+      lines.push(line);
+    }
+    return lines;
+  }
+
+  // Every bank of a ROM image: bank 0 at 0x0000 and each other bank at
+  // 0x4000, as the CPU would see it. Instructions don't run across the end of
+  // a bank.
+  pub fn disassemble_rom(&self, rom: &[u8]) -> Vec<Line> {
+    let mut lines: Vec<Line> = Vec::new();
+    for (bank, data) in rom.chunks(BANK_SIZE).enumerate() {
+      let base: u16 = if bank == 0 { 0x0000 } else { 0x4000 };
+      // Bank 0 code sees bank 1 until it switches banks.
+      let mapped: usize = bank.max(1);
+      let mut offset: usize = 0;
+      while offset < data.len() {
+        let address: u16 = base + offset as u16;
+        let read = |a: u16| -> u8 {
+          let index: usize = a.wrapping_sub(base) as usize;
+          return data.get(index).copied().unwrap_or(0);
+        };
+        let mut line: Line = self.line_at(read, address, mapped);
+        let room: usize = data.len() - offset;
+        if line.bytes.len() > room {
+          line.bytes.truncate(room);
+          line.text = db(&line.bytes);
+          line.cycles = 0;
+          line.taken_cycles = None;
+        }
+        offset += line.bytes.len();
+        lines.push(line);
+      }
+    }
+    return lines;
+  }
+}
+
+fn db(bytes: &[u8]) -> String {
+  let values: Vec<String> = bytes.iter().map(|byte| format!("${:02x}", byte)).collect();
+  return format!("db {}", values.join(", "));
+}
+
+fn r8(register: R8) -> &'static str {
+  return match register {
+    R8::B => "b",
+    R8::C => "c",
+    R8::D => "d",
+    R8::E => "e",
+    R8::H => "h",
+    R8::L => "l",
+    R8::HlIndirect => "[hl]",
+    R8::A => "a",
+  };
+}
+
+fn r16(register: R16) -> &'static str {
+  return match register {
+    R16::BC => "bc",
+    R16::DE => "de",
+    R16::HL => "hl",
+    R16::SP => "sp",
+  };
+}
+
+fn r16_stack(register: R16Stack) -> &'static str {
+  return match register {
+    R16Stack::BC => "bc",
+    R16Stack::DE => "de",
+    R16Stack::HL => "hl",
+    R16Stack::AF => "af",
+  };
+}
+
+fn r16_memory(register: R16Memory) -> &'static str {
+  return match register {
+    R16Memory::BC => "[bc]",
+    R16Memory::DE => "[de]",
+    R16Memory::HlIncrement => "[hl+]",
+    R16Memory::HlDecrement => "[hl-]",
+  };
+}
+
+fn condition(condition: Condition) -> &'static str {
+  return match condition {
+    Condition::NZ => "nz",
+    Condition::Z => "z",
+    Condition::NC => "nc",
+    Condition::C => "c",
+  };
+}
+
+fn alu(op: AluOp) -> &'static str {
+  return match op {
+    AluOp::Add => "add a,",
+    AluOp::Adc => "adc a,",
+    AluOp::Sub => "sub a,",
+    AluOp::Sbc => "sbc a,",
+    AluOp::And => "and a,",
+    AluOp::Xor => "xor a,",
+    AluOp::Or => "or a,",
+    AluOp::Cp => "cp a,",
+  };
+}
+
+fn shift(op: ShiftOp) -> &'static str {
+  return match op {
+    ShiftOp::Rlc => "rlc",
+    ShiftOp::Rrc => "rrc",
+    ShiftOp::Rl => "rl",
+    ShiftOp::Rr => "rr",
+    ShiftOp::Sla => "sla",
+    ShiftOp::Sra => "sra",
+    ShiftOp::Swap => "swap",
+    ShiftOp::Srl => "srl",
+  };
+}
+
+// A signed offset as RGBDS writes it: `sp + $05`, `sp - $05`.
+fn offset(value: i8) -> String {
+  if value < 0 {
+    return format!("- ${:02x}", value.unsigned_abs());
+  }
+  return format!("+ ${:02x}", value);
+}
+
+// A signed operand in `$` hex: `$05`, `-$05`.
+fn signed(value: i8) -> String {
+  if value < 0 {
+    return format!("-${:02x}", value.unsigned_abs());
+  }
+  return format!("${:02x}", value);
+}
+
+fn with_condition(mnemonic: &str, cond: Option<Condition>, operand: String) -> String {
+  return match cond {
+    Some(cond) => format!("{} {}, {}", mnemonic, condition(cond), operand),
+    None => format!("{} {}", mnemonic, operand),
+  };
+}
+
+// The address a jump, call or memory access refers to, and how
+// `format_instruction` writes it.
+fn referenced_address(instruction: Instruction, next: u16) -> Option<(String, u16)> {
+  use Instruction::*;
+  let address: u16 = match instruction {
+    Jr(_, value) => next.wrapping_add(value as i16 as u16),
+    Jp(_, address) | Call(_, address) => address,
+    LdAddressSp(address) | LdAddressA(address) | LdAAddress(address) => address,
+    LdhAddressA(offset) | LdhAAddress(offset) => 0xff00 | offset as u16,
+    Rst(address) => return Some((format!("${:02x}", address), address)),
+    _ => return None,
+  };
+  return Some((format!("${:04x}", address), address));
+}
+
+// A line as the disassemble tool prints it: bank and address, bytes,
+// instruction and cycles, taken cycles first for conditional branches.
+pub fn format_line(line: &Line) -> String {
+  let bytes: Vec<String> = line.bytes.iter().map(|byte| format!("{:02x}", byte)).collect();
+  let cycles: String = match (line.cycles, line.taken_cycles) {
+    (0, _) => String::new(),
+    (cycles, Some(taken)) => format!(" ; {}/{}", taken, cycles),
+    (cycles, None) => format!(" ; {}", cycles),
+  };
+  return format!(
+    "{:02x}:{:04x}  {:<24} {}{}",
+    line.bank,
+    line.address,
+    bytes.join(" "),
+    line.text,
+    cycles
+  );
+}
+
+// `next` is the address after the instruction, which relative jumps count
+// from.
+pub fn format_instruction(instruction: Instruction, next: u16) -> String {
+  use Instruction::*;
+  return match instruction {
+    Nop => "nop".to_string(),
+    Stop => "stop".to_string(),
+    Halt => "halt".to_string(),
+    Di => "di".to_string(),
+    Ei => "ei".to_string(),
+
+    Ld(to, from) => format!("ld {}, {}", r8(to), r8(from)),
+    LdImmediate(to, value) => format!("ld {}, ${:02x}", r8(to), value),
+    LdR16Immediate(to, value) => format!("ld {}, ${:04x}", r16(to), value),
+    LdIndirectA(to) => format!("ld {}, a", r16_memory(to)),
+    LdAIndirect(from) => format!("ld a, {}", r16_memory(from)),
+    LdAddressSp(address) => format!("ld [${:04x}], sp", address),
+    LdAddressA(address) => format!("ld [${:04x}], a", address),
+    LdAAddress(address) => format!("ld a, [${:04x}]", address),
+    LdhAddressA(offset) => format!("ldh [${:04x}], a", 0xff00 | offset as u16),
+    LdhAAddress(offset) => format!("ldh a, [${:04x}]", 0xff00 | offset as u16),
+    LdhCA => "ldh [c], a".to_string(),
+    LdhAC => "ldh a, [c]".to_string(),
+    LdSpHl => "ld sp, hl".to_string(),
+    LdHlSpOffset(value) => format!("ld hl, sp {}", offset(value)),
+
+    Inc(register) => format!("inc {}", r8(register)),
+    Dec(register) => format!("dec {}", r8(register)),
+    IncR16(register) => format!("inc {}", r16(register)),
+    DecR16(register) => format!("dec {}", r16(register)),
+    AddHl(register) => format!("add hl, {}", r16(register)),
+    AddSpOffset(value) => format!("add sp, {}", signed(value)),
+    Alu(op, register) => format!("{} {}", alu(op), r8(register)),
+    AluImmediate(op, value) => format!("{} ${:02x}", alu(op), value),
+
+    Rlca => "rlca".to_string(),
+    Rrca => "rrca".to_string(),
+    Rla => "rla".to_string(),
+    Rra => "rra".to_string(),
+    Daa => "daa".to_string(),
+    Cpl => "cpl".to_string(),
+    Scf => "scf".to_string(),
+    Ccf => "ccf".to_string(),
+
+    Jr(cond, value) => {
+      with_condition("jr", cond, format!("${:04x}", next.wrapping_add(value as i16 as u16)))
+    }
+    Jp(cond, address) => with_condition("jp", cond, format!("${:04x}", address)),
+    JpHl => "jp hl".to_string(),
+    Call(cond, address) => with_condition("call", cond, format!("${:04x}", address)),
+    Ret(Some(cond)) => format!("ret {}", condition(cond)),
+    Ret(None) => "ret".to_string(),
+    Reti => "reti".to_string(),
+    Rst(address) => format!("rst ${:02x}", address),
+    Push(register) => format!("push {}", r16_stack(register)),
+    Pop(register) => format!("pop {}", r16_stack(register)),
+
+    Shift(op, register) => format!("{} {}", shift(op), r8(register)),
+    Bit(bit, register) => format!("bit {}, {}", bit, r8(register)),
+    Res(bit, register) => format!("res {}, {}", bit, r8(register)),
+    Set(bit, register) => format!("set {}, {}", bit, r8(register)),
+
+    Invalid(opcode) => format!("db ${:02x}", opcode),
+  };
+}
+
+// T-cycles for the instruction, and for conditional branches the cycles
+// when taken.
+pub fn cycles(instruction: Instruction) -> (u8, Option<u8>) {
+  use Instruction::*;
+  let memory = |register: R8, base: u8, extra: u8| -> u8 {
+    if register == R8::HlIndirect {
+      base + extra
+    } else {
+      base
+    }
+  };
+  let plain: u8 = match instruction {
+    Jr(Some(_), _) => return (8, Some(12)),
+    Jp(Some(_), _) => return (12, Some(16)),
+    Call(Some(_), _) => return (12, Some(24)),
+    Ret(Some(_)) => return (8, Some(20)),
+
+    Nop | Stop | Halt | Di | Ei | Invalid(_) => 4,
+    Ld(to, from) => memory(to, 4, 4).max(memory(from, 4, 4)),
+    LdImmediate(to, _) => memory(to, 8, 4),
+    LdR16Immediate(..) => 12,
+    LdIndirectA(_) | LdAIndirect(_) => 8,
+    LdAddressSp(_) => 20,
+    LdAddressA(_) | LdAAddress(_) => 16,
+    LdhAddressA(_) | LdhAAddress(_) => 12,
+    LdhCA | LdhAC | LdSpHl => 8,
+    LdHlSpOffset(_) => 12,
+    Inc(register) | Dec(register) => memory(register, 4, 8),
+    IncR16(_) | DecR16(_) | AddHl(_) => 8,
+    AddSpOffset(_) => 16,
+    Alu(_, register) => memory(register, 4, 4),
+    AluImmediate(..) => 8,
+    Rlca | Rrca | Rla | Rra | Daa | Cpl | Scf | Ccf => 4,
+    Jr(None, _) => 12,
+    Jp(None, _) => 16,
+    JpHl => 4,
+    Call(None, _) => 24,
+    Ret(None) | Reti | Rst(_) | Push(_) => 16,
+    Pop(_) => 12,
+    Shift(_, register) | Res(_, register) | Set(_, register) => memory(register, 8, 8),
+    Bit(_, register) => memory(register, 8, 4),
+  };
+  return (plain, None);
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::game::bus::FlatBus;
+  use crate::game::cpu::Cpu;
+  use crate::game::registers::Registers;
+
+  fn line(bytes: &[u8], address: u16) -> Line {
+    let disassembler: Disassembler = Default::default();
+    return disassembler.line_at(|a| bytes[(a - address) as usize], address, 0);
+  }
+
+  #[test]
+  fn formats_rgbds_syntax() {
+    let cases: [(&[u8], &str); 16] = [
+      (&[0x00], "nop"),
+      (&[0x3e, 0x12], "ld a, $12"),
+      (&[0x21, 0x34, 0x12], "ld hl, $1234"),
+      (&[0x22], "ld [hl+], a"),
+      (&[0x3a], "ld a, [hl-]"),
+      (&[0xe0, 0x40], "ldh [$ff40], a"),
+      (&[0xf2], "ldh a, [c]"),
+      (&[0xf8, 0xfe], "ld hl, sp - $02"),
+      (&[0xe8, 0xfe], "add sp, -$02"),
+      (&[0xe8, 0x05], "add sp, $05"),
+      (&[0x86], "add a, [hl]"),
+      (&[0x20, 0xfe], "jr nz, $0150"),
+      (&[0xc4, 0x00, 0x40], "call nz, $4000"),
+      (&[0xff], "rst $38"),
+      (&[0xcb, 0x7c], "bit 7, h"),
+      (&[0xd3], "db $d3"),
+    ];
+    for (bytes, text) in cases.iter() {
+      let line: Line = line(bytes, 0x150);
+      assert_eq!(line.text, *text);
+      assert_eq!(line.bytes, bytes.to_vec());
+    }
+  }
+
+  #[test]
+  fn reports_cycles_for_both_branch_outcomes() {
+    assert_eq!(line(&[0x20, 0x00], 0).cycles, 8);
+    assert_eq!(line(&[0x20, 0x00], 0).taken_cycles, Some(12));
+    assert_eq!(line(&[0xcb, 0x46], 0).cycles, 12);
+    assert_eq!(line(&[0xcb, 0x86], 0).cycles, 16);
+    assert_eq!(line(&[0x34], 0).cycles, 12);
+    assert_eq!(line(&[0xcd, 0, 0], 0).taken_cycles, None);
+  }
+
+  // The cycle table agrees with the CPU for every unconditional opcode.
+  #[test]
+  fn cycles_match_the_cpu() {
+    for opcode in 0..=0xffu16 {
+      for &cb in [0x00u8, 0x46, 0x86, 0x40].iter() {
+        let bytes: [u8; 3] = [opcode as u8, cb, 0xc0];
+        let mut rest = bytes[1..].iter();
+        let instruction: Instruction = decode(bytes[0], || *rest.next().unwrap());
+        if matches!(instruction, Instruction::Halt | Instruction::Stop | Instruction::Invalid(_)) {
+          continue;
+        }
+        let (expected, taken) = cycles(instruction);
+        if taken.is_some() {
+          continue;
+        }
+        let mut bus: FlatBus = FlatBus::new();
+        bus.ram[0xc000..0xc003].copy_from_slice(&bytes);
+        let mut registers: Registers = Registers {
+          pc: 0xc000,
+          sp: 0xd000,
+          ..Default::default()
+        };
+        registers.set_hl(0xd800);
+        let mut cpu: Cpu = Default::default();
+        cpu.step(&mut bus, &mut registers);
+        assert_eq!(bus.ticks, expected as u64, "{:?}", instruction);
+      }
+    }
+  }
+
+  #[test]
+  fn data_regions_become_db_lines() {
+    let mut disassembler: Disassembler = Default::default();
+    disassembler.data_regions.push(DataRegion {
+      bank: None,
+      start: 0x104,
+      end: 0x10e,
+    });
+    let mut rom: Vec<u8> = vec![0; 0x8000];
+    rom[0x100..0x104].copy_from_slice(&[0x00, 0xc3, 0x50, 0x01]);
+    let lines: Vec<Line> = disassembler.disassemble_rom(&rom);
+    let at = |address: u16| lines.iter().find(|line| line.address == address).unwrap();
+    assert_eq!(at(0x101).text, "jp $0150");
+    assert_eq!(at(0x104).text, "db $00, $00, $00, $00, $00, $00, $00, $00");
+    assert_eq!(at(0x10c).bytes.len(), 3);
+    assert_eq!(at(0x10f).text, "nop");
+    // Bank 1 starts over at 0x4000.
+    assert_eq!(lines.iter().filter(|line| line.address == 0x4000).count(), 1);
+    assert_eq!(lines.iter().find(|line| line.address == 0x4000).unwrap().bank, 1);
+  }
+
+  #[test]
+  fn labels_replace_addresses() {
+    let disassembler: Disassembler = Disassembler {
+      symbols: Symbols::parse("00:0150 Main\n00:ff80 hCounter\n02:4000 LoadLevel\n00:0038 Crash")
+        .unwrap(),
+      ..Default::default()
+    };
+    let mut rom: Vec<u8> = vec![0; 0xc000];
+    rom[0x150..0x158].copy_from_slice(&[0xcd, 0x00, 0x40, 0xe0, 0x80, 0x18, 0xf9, 0xff]);
+    rom[0x8000] = 0xc9;
+    let lines: Vec<Line> = disassembler.disassemble_rom(&rom);
+    let at = |bank: usize, address: u16| {
+      lines.iter().find(|line| line.bank == bank && line.address == address).unwrap()
+    };
+    assert_eq!(at(0, 0x150).label.as_deref(), Some("Main"));
+    // Bank 0 code is read with bank 1 mapped, which has no label at 0x4000.
+    assert_eq!(at(0, 0x150).text, "call $4000");
+    assert_eq!(at(0, 0x153).text, "ldh [hCounter], a");
+    assert_eq!(at(0, 0x155).text, "jr Main");
+    assert_eq!(at(0, 0x157).text, "rst Crash");
+    assert_eq!(at(1, 0x4000).label, None);
+    assert_eq!(at(2, 0x4000).label.as_deref(), Some("LoadLevel"));
+  }
+
+  #[test]
+  fn instructions_do_not_run_past_the_end_of_a_bank() {
+    let mut rom: Vec<u8> = vec![0; 0x8000];
+    rom[0x3fff] = 0xc3;
+    let disassembler: Disassembler = Default::default();
+    let lines: Vec<Line> = disassembler.disassemble_rom(&rom);
+    let last: &Line = lines.iter().find(|line| line.address == 0x3fff).unwrap();
+    assert_eq!(last.text, "db $c3");
+  }
+}
@@ -0,0 +1,320 @@
+// Expressions over CPU state, used as breakpoint conditions.
+//
+//   A == $3f && ZF
+//   HL >= 0xc000 || !CF
+//   [HL] == 12
+//
+// Registers are A-L, F, AF, BC, DE, HL, SP and PC; flags are ZF, NF, HF and
+// CF. Numbers are decimal, or hex with a `$` or `0x` prefix. `[e]` reads the
+// byte at address `e`. Comparisons and `!` give 1 or 0, and any non-zero
+// value counts as true.
+
+use crate::game::memory::Memory;
+use crate::game::registers::{
+  Registers, FLAG_CARRY, FLAG_HALF_CARRY, FLAG_NEGATIVE, FLAG_ZERO,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operand {
+  A,
+  B,
+  C,
+  D,
+  E,
+  F,
+  H,
+  L,
+  AF,
+  BC,
+  DE,
+  HL,
+  SP,
+  PC,
+  ZeroFlag,
+  NegativeFlag,
+  HalfCarryFlag,
+  CarryFlag,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BinaryOp {
+  Equal,
+  NotEqual,
+  Less,
+  LessEqual,
+  Greater,
+  GreaterEqual,
+  And,
+  Or,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Expression {
+  Number(u32),
+  Operand(Operand),
+  // The byte in memory at the inner expression's value.
+  Memory(Box<Expression>),
+  Not(Box<Expression>),
+  Binary(BinaryOp, Box<Expression>, Box<Expression>),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Token {
+  Number(u32),
+  Name(String),
+  Op(&'static str),
+}
+
+const OPS: [&str; 13] = ["==", "!=", "<=", ">=", "&&", "||", "<", ">", "!", "(", ")", "[", "]"];
+
+fn tokenize(text: &str) -> Result<Vec<Token>, String> {
+  let mut tokens: Vec<Token> = Vec::new();
+  let mut rest: &str = text.trim_start();
+  while !rest.is_empty() {
+    if let Some(op) = OPS.iter().find(|op| rest.starts_with(*op)) {
+      tokens.push(Token::Op(op));
+      rest = &rest[op.len()..];
+    } else if rest.starts_with(|c: char| c.is_ascii_alphanumeric() || c == '$') {
+      let end: usize = rest
+        .find(|c: char| !(c.is_ascii_alphanumeric() || c == '$' || c == '_'))
+        .unwrap_or(rest.len());
+      let word: &str = &rest[..end];
+      tokens.push(parse_word(word)?);
+      rest = &rest[end..];
+    } else {
+      return Err(format!("unexpected '{}'", rest.chars().next().unwrap()));
+    }
+    rest = rest.trim_start();
+  }
+  return Ok(tokens);
+}
+
+fn parse_word(word: &str) -> Result<Token, String> {
+  let number = if let Some(hex) = word.strip_prefix('$') {
+    u32::from_str_radix(hex, 16)
+  } else if let Some(hex) = word.strip_prefix("0x").or_else(|| word.strip_prefix("0X")) {
+    u32::from_str_radix(hex, 16)
+  } else if word.starts_with(|c: char| c.is_ascii_digit()) {
+    word.parse::<u32>()
+  } else {
+    return Ok(Token::Name(word.to_ascii_uppercase()));
+  };
+  return number.map(Token::Number).map_err(|_| format!("bad number '{}'", word));
+}
+
+fn operand(name: &str) -> Option<Operand> {
+  return Some(match name {
+    "A" => Operand::A,
+    "B" => Operand::B,
+    "C" => Operand::C,
+    "D" => Operand::D,
+    "E" => Operand::E,
+    "F" => Operand::F,
+    "H" => Operand::H,
+    "L" => Operand::L,
+    "AF" => Operand::AF,
+    "BC" => Operand::BC,
+    "DE" => Operand::DE,
+    "HL" => Operand::HL,
+    "SP" => Operand::SP,
+    "PC" => Operand::PC,
+    "ZF" => Operand::ZeroFlag,
+    "NF" => Operand::NegativeFlag,
+    "HF" => Operand::HalfCarryFlag,
+    "CF" => Operand::CarryFlag,
+    _ => return None,
+  });
+}
+
+struct Parser {
+  tokens: Vec<Token>,
+  position: usize,
+}
+
+impl Parser {
+  fn peek_op(&self) -> Option<&'static str> {
+    return match self.tokens.get(self.position) {
+      Some(Token::Op(op)) => Some(op),
+      _ => None,
+    };
+  }
+
+  fn expect(&mut self, op: &str) -> Result<(), String> {
+    if self.peek_op() != Some(op) {
+      return Err(format!("expected '{}'", op));
+    }
+    self.position += 1;
+    return Ok(());
+  }
+
+  // Lowest precedence first: ||, &&, comparisons, then unary.
+  fn or(&mut self) -> Result<Expression, String> {
+    let mut left: Expression = self.and()?;
+    while self.peek_op() == Some("||") {
+      self.position += 1;
+      left = Expression::Binary(BinaryOp::Or, Box::new(left), Box::new(self.and()?));
+    }
+    return Ok(left);
+  }
+
+  fn and(&mut self) -> Result<Expression, String> {
+    let mut left: Expression = self.comparison()?;
+    while self.peek_op() == Some("&&") {
+      self.position += 1;
+      left = Expression::Binary(BinaryOp::And, Box::new(left), Box::new(self.comparison()?));
+    }
+    return Ok(left);
+  }
+
+  fn comparison(&mut self) -> Result<Expression, String> {
+    let left: Expression = self.unary()?;
+    let op: BinaryOp = match self.peek_op() {
+      Some("==") => BinaryOp::Equal,
+      Some("!=") => BinaryOp::NotEqual,
+      Some("<") => BinaryOp::Less,
+      Some("<=") => BinaryOp::LessEqual,
+      Some(">") => BinaryOp::Greater,
+      Some(">=") => BinaryOp::GreaterEqual,
+      _ => return Ok(left),
+    };
+    self.position += 1;
+    return Ok(Expression::Binary(op, Box::new(left), Box::new(self.unary()?)));
+  }
+
+  fn unary(&mut self) -> Result<Expression, String> {
+    let token: Token = match self.tokens.get(self.position) {
+      Some(token) => token.clone(),
+      None => return Err("unexpected end of expression".to_string()),
+    };
+    self.position += 1;
+    return match token {
+      Token::Number(value) => Ok(Expression::Number(value)),
+      Token::Name(name) => match operand(&name) {
+        Some(operand) => Ok(Expression::Operand(operand)),
+        None => Err(format!("unknown register or flag '{}'", name)),
+      },
+      Token::Op("!") => Ok(Expression::Not(Box::new(self.unary()?))),
+      Token::Op("(") => {
+        let inner: Expression = self.or()?;
+        self.expect(")")?;
+        Ok(inner)
+      }
+      Token::Op("[") => {
+        let inner: Expression = self.or()?;
+        self.expect("]")?;
+        Ok(Expression::Memory(Box::new(inner)))
+      }
+      Token::Op(op) => Err(format!("unexpected '{}'", op)),
+    };
+  }
+}
+
+impl Expression {
+  pub fn parse(text: &str) -> Result<Expression, String> {
+    let mut parser: Parser = Parser {
+      tokens: tokenize(text)?,
+      position: 0,
+    };
+    let expression: Expression = parser.or()?;
+    if parser.position != parser.tokens.len() {
+      return Err("unexpected input after expression".to_string());
+    }
+    return Ok(expression);
+  }
+
+  pub fn evaluate(&self, registers: &Registers, memory: &Memory) -> u32 {
+    return match self {
+      Expression::Number(value) => *value,
+      Expression::Operand(operand) => read_operand(*operand, registers),
+      Expression::Memory(address) => {
+        memory.peek_byte(address.evaluate(registers, memory) as u16) as u32
+      }
+      Expression::Not(inner) => (inner.evaluate(registers, memory) == 0) as u32,
+      Expression::Binary(op, left, right) => {
+        let left: u32 = left.evaluate(registers, memory);
+        // && and || short-circuit, so `[HL]` is only read when needed.
+        match op {
+          BinaryOp::And => (left != 0 && right.evaluate(registers, memory) != 0) as u32,
+          BinaryOp::Or => (left != 0 || right.evaluate(registers, memory) != 0) as u32,
+          _ => {
+            let right: u32 = right.evaluate(registers, memory);
+            (match op {
+              BinaryOp::Equal => left == right,
+              BinaryOp::NotEqual => left != right,
+              BinaryOp::Less => left < right,
+              BinaryOp::LessEqual => left <= right,
+              BinaryOp::Greater => left > right,
+              _ => left >= right,
+            }) as u32
+          }
+        }
+      }
+    };
+  }
+
+  pub fn is_true(&self, registers: &Registers, memory: &Memory) -> bool {
+    return self.evaluate(registers, memory) != 0;
+  }
+}
+
+fn read_operand(operand: Operand, registers: &Registers) -> u32 {
+  let flag = |mask: u8| -> u32 { (registers.f & mask != 0) as u32 };
+  return match operand {
+    Operand::A => registers.a as u32,
+    Operand::B => registers.b as u32,
+    Operand::C => registers.c as u32,
+    Operand::D => registers.d as u32,
+    Operand::E => registers.e as u32,
+    Operand::F => registers.f as u32,
+    Operand::H => registers.h as u32,
+    Operand::L => registers.l as u32,
+    Operand::AF => registers.get_af() as u32,
+    Operand::BC => registers.get_bc() as u32,
+    Operand::DE => registers.get_de() as u32,
+    Operand::HL => registers.get_hl() as u32,
+    Operand::SP => registers.sp as u32,
+    Operand::PC => registers.pc as u32,
+    Operand::ZeroFlag => flag(FLAG_ZERO),
+    Operand::NegativeFlag => flag(FLAG_NEGATIVE),
+    Operand::HalfCarryFlag => flag(FLAG_HALF_CARRY),
+    Operand::CarryFlag => flag(FLAG_CARRY),
+  };
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::game::cartridge::Cartridge;
+
+  fn evaluate(text: &str, registers: &Registers, memory: &Memory) -> u32 {
+    return Expression::parse(text).unwrap().evaluate(registers, memory);
+  }
+
+  #[test]
+  fn evaluates_registers_flags_and_memory() {
+    let mut memory: Memory = Memory::new(Cartridge::new(vec![0; 0x8000]));
+    memory.write_byte(0xc123, 42);
+    let mut registers: Registers = Registers {
+      a: 0x3f,
+      f: FLAG_ZERO,
+      ..Default::default()
+    };
+    registers.set_hl(0xc123);
+
+    assert_eq!(evaluate("A == $3f && ZF", &registers, &memory), 1);
+    assert_eq!(evaluate("a == 0x3F && cf", &registers, &memory), 0);
+    assert_eq!(evaluate("!CF || B > 10", &registers, &memory), 1);
+    assert_eq!(evaluate("[HL] == 42", &registers, &memory), 1);
+    assert_eq!(evaluate("(HL >= 0xc000) && (SP <= 65534)", &registers, &memory), 1);
+    assert_eq!(evaluate("HL", &registers, &memory), 0xc123);
+  }
+
+  #[test]
+  fn rejects_malformed_expressions() {
+    assert!(Expression::parse("A ==").is_err());
+    assert!(Expression::parse("IX == 1").is_err());
+    assert!(Expression::parse("(A == 1").is_err());
+    assert!(Expression::parse("A 1").is_err());
+    assert!(Expression::parse("$zz").is_err());
+  }
+}
@@ -1,85 +1,171 @@
-use js_sys::Uint8Array;
-
+#[path = "./alu.rs"]
+pub mod alu;
+#[path = "./bus.rs"]
+pub mod bus;
+#[path = "./call_stack.rs"]
+pub mod call_stack;
+#[path = "./cartridge.rs"]
+pub mod cartridge;
 #[path = "./cpu.rs"]
 pub mod cpu;
+#[path = "./debugger.rs"]
+pub mod debugger;
+#[path = "./disassembler.rs"]
+pub mod disassembler;
+#[path = "./expression.rs"]
+pub mod expression;
+#[path = "./gdb.rs"]
+pub mod gdb;
 #[path = "./gpu.rs"]
 pub mod gpu;
+#[path = "./instruction.rs"]
+pub mod instruction;
 #[path = "./interrupts.rs"]
 pub mod interrupts;
+#[path = "./logging.rs"]
+pub mod logging;
 #[path = "./memory.rs"]
 pub mod memory;
+#[path = "./palette.rs"]
+pub mod palette;
 #[path = "./registers.rs"]
 pub mod registers;
+#[path = "./sgb.rs"]
+pub mod sgb;
+#[path = "./symbols.rs"]
+pub mod symbols;
+#[path = "./timer.rs"]
+pub mod timer;
+#[path = "./trace.rs"]
+pub mod trace;
+#[path = "./vram_viewer.rs"]
+pub mod vram_viewer;
+
+#[cfg(test)]
+#[path = "./blargg_tests.rs"]
+mod blargg_tests;
+#[cfg(test)]
+#[path = "./mooneye_tests.rs"]
+mod mooneye_tests;
+#[cfg(test)]
+#[path = "./ppu_tests.rs"]
+mod ppu_tests;
+#[cfg(test)]
+#[path = "./sm83_tests.rs"]
+mod sm83_tests;
+
+// The hardware being emulated. Game Boy Color support isn't emulated, so
+// CGB-only cartridges can't run.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Model {
+  Dmg,
+  Sgb,
+}
 
 pub struct Game {
-  pub ticks: u32,
+  pub model: Model,
   pub cpu: cpu::Cpu,
   pub memory: memory::Memory,
   pub registers: registers::Registers,
-}
-
-macro_rules! log {
-    ( $( $t:tt )* ) => {
-        web_sys::console::log_1(&format!( $( $t )* ).into());
-    }
+  // Colours used when handing frames to the host.
+  pub palette: palette::Palette,
+  // Instruction trace, when one is being taken.
+  pub tracer: Option<trace::Tracer>,
 }
 
 impl Game {
   pub fn step(&mut self) {
-    // Read the next instruction.
-    // `next_command` will increment the PC.
-    log!("PC: {:#x}", self.registers.pc);
-    let instruction: u8 = self.memory.read_byte(self.registers.next_command());
-
-    log!("Instruction executing {:#x}", instruction);
-    log!("Ticks before: {}", self.ticks);
-
-    self.cpu.step(
-      instruction,
-      &mut self.memory,
-      &mut self.registers,
-      &mut self.ticks,
-    );
-
-    log!("Ticks after: {}", self.ticks);
-
-    log!("GPU step begin");
-    gpu::GPU.step(&mut self.ticks);
-    log!("GPU step end");
-    log!("Interrupt step begin");
-    interrupts::INTERRUPTS.step(&mut self.registers, &mut self.memory, &mut self.ticks);
-    log!("Interrupt step end");
+    // The trace line shows the state before the instruction runs.
+    let pc: u16 = self.registers.pc;
+    let line: Option<String> = match self.tracer.as_mut().map(|tracer| tracer.wants(pc)) {
+      Some(true) => Some(trace::format_line(&self.registers, &self.memory)),
+      _ => None,
+    };
+
+    // The CPU fetches and executes one instruction (or services an
+    // interrupt), ticking the GPU and timer on every M-cycle as it goes.
+    self.cpu.step(&mut self.memory, &mut self.registers);
+
+    if let (Some(tracer), Some(line)) = (&mut self.tracer, line) {
+      if let cpu::Step::Instruction { pc, .. } = self.cpu.last_step {
+        tracer.record(line, self.memory.cartridge.rom_bank_at(0x4000), pc);
+      }
+    }
   }
+
+  // The last frame as RGBA, four bytes per pixel, coloured with `palette`,
+  // or by the SGB's palettes when running as one.
+  pub fn frame_rgba(&self) -> Vec<u8> {
+    let gpu: &gpu::Gpu = &self.memory.gpu;
+    if let Some(sgb) = &self.memory.sgb {
+      return sgb.screen_rgba(gpu);
+    }
+    let mut out: Vec<u8> = vec![0; gpu.framebuffer.len() * 4];
+    self.palette.to_rgba(&gpu.framebuffer, &gpu.layers, &mut out);
+    return out;
+  }
+
+  // Colours later frames, and the current one from `frame_rgba`, with
+  // `palette`.
+  pub fn set_palette(&mut self, palette: palette::Palette) {
+    self.palette = palette;
+  }
+
+  // Shows or hides the background, window or all objects in later frames.
+  pub fn set_layer_enabled(&mut self, layer: gpu::Layer, enabled: bool) {
+    self.memory.gpu.layer_toggles.set_layer(layer, enabled);
+  }
+
+  // Shows or hides the object in OAM entry `index` (0-39); other indices
+  // are ignored.
+  pub fn set_object_enabled(&mut self, index: usize, enabled: bool) {
+    if let Some(shown) = self.memory.gpu.layer_toggles.object.get_mut(index) {
+      *shown = enabled;
+    }
+  }
+
+  // The SGB's 256x224 picture with its border, or None on other models.
+  pub fn sgb_frame_rgba(&self) -> Option<Vec<u8>> {
+    return self.memory.sgb.as_ref().map(|sgb| sgb.frame_rgba(&self.memory.gpu));
+  }
+}
+
+pub fn new_game(cartridge: Vec<u8>) -> Game {
+  return new_game_with_model(cartridge, Model::Dmg);
 }
 
-pub fn new_game(cartridge: [u8; 0x8000]) -> Game {
+pub fn new_game_with_model(cartridge: Vec<u8>, model: Model) -> Game {
+  let mut memory: memory::Memory = memory::Memory::new(cartridge::Cartridge::new(cartridge));
+  let registers: registers::Registers = match model {
+    Model::Dmg => Default::default(),
+    Model::Sgb => {
+      memory.sgb = Some(sgb::Sgb::new(memory.cartridge.supports_sgb()));
+      registers::Registers::sgb()
+    }
+  };
   return Game {
-    ticks: 0,
+    model,
     cpu: Default::default(),
-    registers: Default::default(),
-    memory: memory::Memory {
-      cartridge,
-      io: [0; 0x100],
-      video_ram: [0; 0x2000],
-      switchable_ram: [0; 0x2000],
-      write_ram: [0; 0x2000],
-      hardware_ram: [0; 0x80],
-      oam: [0; 0x100],
-    },
+    registers,
+    memory,
+    palette: Default::default(),
+    tracer: None,
   };
 }
 
+#[allow(dead_code)]
 const NINTENDO_LOGO: &[u8] = &[
   0xCE, 0xED, 0x66, 0x66, 0xCC, 0x0D, 0x00, 0x0B, 0x03, 0x73, 0x00, 0x83, 0x00, 0x0C, 0x00, 0x0D,
   0x00, 0x08, 0x11, 0x1F, 0x88, 0x89, 0x00, 0x0E, 0xDC, 0xCC, 0x6E, 0xE6, 0xDD, 0xDD, 0xD9, 0x99,
   0xBB, 0xBB, 0x67, 0x63, 0x6E, 0x0E, 0xEC, 0xCC, 0xDD, 0xDC, 0x99, 0x9F, 0xBB, 0xB9, 0x33, 0x3E,
 ];
 
+#[allow(dead_code)]
 fn matches_nintendo_logo(buf: &[u8]) -> bool {
-  return (*NINTENDO_LOGO).eq(&*buf);
+  return (*NINTENDO_LOGO).eq(buf);
 }
 
-pub fn validate_cartridge(loaded: &[u8]) -> Result<[u8; 0x8000], &'static str> {
+pub fn validate_cartridge(loaded: &[u8]) -> Result<Vec<u8>, &'static str> {
   if loaded.len() < 100 {
     return Err("Invalid game cart");
   }
@@ -87,8 +173,71 @@ pub fn validate_cartridge(loaded: &[u8]) -> Result<[u8; 0x8000], &'static str> {
   //   return Err("Validation for Nintendo Logo failed");
   // }
   else {
-    let mut buffer: [u8; 0x8000] = [0u8; 0x8000];
-    Uint8Array::from(loaded).copy_to(&mut buffer);
-    return Ok(buffer);
+    return Ok(loaded.to_vec());
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  // A game spinning on `JR -2` whose background is tile 1, solid colour 3.
+  fn game_with_dark_background() -> Game {
+    let mut rom: Vec<u8> = vec![0; 0x8000];
+    rom[0x100..0x102].copy_from_slice(&[0x18, 0xfe]);
+    let mut game: Game = new_game(rom);
+    game.memory.write_byte(0xff40, 0x00);
+    for offset in 0..16 {
+      game.memory.write_byte(0x8010 + offset, 0xff);
+    }
+    for offset in 0..0x400 {
+      game.memory.write_byte(0x9800 + offset, 1);
+    }
+    game.memory.write_byte(0xff47, 0xe4);
+    game.memory.write_byte(0xff40, 0x91);
+    return game;
+  }
+
+  fn run_frame(game: &mut Game) {
+    let frames: u64 = game.memory.gpu.frames;
+    while game.memory.gpu.frames == frames {
+      game.step();
+    }
+  }
+
+  #[test]
+  fn layers_and_objects_toggle_through_the_game() {
+    let mut game: Game = game_with_dark_background();
+    let dark: palette::Rgba = game.palette.color(gpu::LAYER_BG, 3);
+    let light: palette::Rgba = game.palette.color(gpu::LAYER_BG, 0);
+    run_frame(&mut game);
+    run_frame(&mut game);
+    assert_eq!(game.frame_rgba()[..4], dark);
+
+    game.set_layer_enabled(gpu::Layer::Background, false);
+    run_frame(&mut game);
+    assert_eq!(game.frame_rgba()[..4], light);
+    game.set_layer_enabled(gpu::Layer::Background, true);
+    run_frame(&mut game);
+    assert_eq!(game.frame_rgba()[..4], dark);
+
+    game.set_layer_enabled(gpu::Layer::Objects, false);
+    game.set_object_enabled(3, false);
+    game.set_object_enabled(40, false);
+    let toggles: gpu::LayerToggles = game.memory.gpu.layer_toggles;
+    assert!(!toggles.objects && !toggles.object[3]);
+    assert!(toggles.window && toggles.object[2] && toggles.object[39]);
+  }
+
+  #[test]
+  fn frames_use_the_selected_palette() {
+    let mut game: Game = game_with_dark_background();
+    run_frame(&mut game);
+    run_frame(&mut game);
+    game.set_palette(palette::Palette::pocket_grey());
+    assert_eq!(game.frame_rgba()[..4], palette::POCKET_GREY[3]);
+    let custom: palette::Palette = palette::Palette::custom([[1, 2, 3, 4]; 4]);
+    game.set_palette(custom);
+    assert_eq!(game.frame_rgba()[..4], [1, 2, 3, 4]);
   }
 }
@@ -0,0 +1,528 @@
+// A GDB remote serial protocol stub, so GDB-compatible debuggers can attach
+// to a running game:
+//
+//   (gdb) target remote localhost:1234
+//
+// Registers are the pairs AF, BC, DE, HL, SP and PC, 16 bits each and
+// little-endian, as described by `TARGET_XML`. Memory reads don't disturb
+// the hardware; writes go through the bus like a CPU write. Breakpoints
+// (Z0/Z1) and watchpoints (Z2-Z4) are the `Debugger`'s own.
+//
+// Packets are answered by `GdbStub::handle`. Native builds can serve them
+// over TCP with `GdbStub::serve`; wasm has no sockets.
+
+use crate::game::debugger::{AccessKind, BreakReason, Breakpoint, Debugger, Watchpoint};
+use crate::game::logging;
+use crate::game::registers::Registers;
+
+// Cycles to run between checks for GDB asking to stop, about one frame.
+const SLICE_CYCLES: u64 = 70224;
+
+pub const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <feature name="org.gnu.gdb.sm83.cpu">
+    <reg name="af" bitsize="16" type="int"/>
+    <reg name="bc" bitsize="16" type="int"/>
+    <reg name="de" bitsize="16" type="int"/>
+    <reg name="hl" bitsize="16" type="int"/>
+    <reg name="sp" bitsize="16" type="data_ptr"/>
+    <reg name="pc" bitsize="16" type="code_ptr"/>
+  </feature>
+</target>
+"#;
+
+const REGISTER_COUNT: usize = 6;
+
+// A Z packet's type, address and length.
+type Point = (u8, u16, u16);
+
+pub struct GdbStub {
+  pub debugger: Debugger,
+  // Debugger ids of the breakpoints and watchpoints GDB has inserted.
+  points: Vec<(Point, usize)>,
+  no_ack: bool,
+  attached: bool,
+}
+
+fn get_register(registers: &Registers, index: usize) -> u16 {
+  return match index {
+    0 => registers.get_af(),
+    1 => registers.get_bc(),
+    2 => registers.get_de(),
+    3 => registers.get_hl(),
+    4 => registers.sp,
+    _ => registers.pc,
+  };
+}
+
+fn set_register(registers: &mut Registers, index: usize, val: u16) {
+  match index {
+    0 => registers.set_af(val),
+    1 => registers.set_bc(val),
+    2 => registers.set_de(val),
+    3 => registers.set_hl(val),
+    4 => registers.sp = val,
+    _ => registers.pc = val,
+  }
+}
+
+fn hex_u16_le(val: u16) -> String {
+  return format!("{:02x}{:02x}", val & 0xff, val >> 8);
+}
+
+fn parse_hex(text: &str) -> Option<u32> {
+  return u32::from_str_radix(text, 16).ok();
+}
+
+fn parse_hex_bytes(text: &str) -> Option<Vec<u8>> {
+  if !text.len().is_multiple_of(2) {
+    return None;
+  }
+  return (0..text.len())
+    .step_by(2)
+    .map(|i| u8::from_str_radix(text.get(i..i + 2)?, 16).ok())
+    .collect();
+}
+
+// `address,length` as in m, M and Z packets.
+fn parse_range(text: &str) -> Option<(u16, u16)> {
+  let (address, length) = text.split_once(',')?;
+  return Some((parse_hex(address)? as u16, parse_hex(length)? as u16));
+}
+
+// Frames a reply as `$data#checksum`, escaping the characters that would
+// end it early.
+pub fn frame(data: &str) -> String {
+  let mut body: String = String::with_capacity(data.len());
+  for c in data.chars() {
+    if matches!(c, '$' | '#' | '}' | '*') {
+      body.push('}');
+      body.push((c as u8 ^ 0x20) as char);
+    } else {
+      body.push(c);
+    }
+  }
+  let checksum: u8 = body.bytes().fold(0, u8::wrapping_add);
+  return format!("${}#{:02x}", body, checksum);
+}
+
+impl GdbStub {
+  pub fn new(debugger: Debugger) -> GdbStub {
+    return GdbStub {
+      debugger,
+      points: Vec::new(),
+      no_ack: false,
+      attached: true,
+    };
+  }
+
+  // False once GDB has detached or killed the session.
+  pub fn attached(&self) -> bool {
+    return self.attached;
+  }
+
+  // Answers one packet, given without its framing. `interrupted` is polled
+  // while the game runs and returns true when GDB asks it to stop. Returns
+  // None when no reply is due.
+  pub fn handle(&mut self, packet: &str, interrupted: &mut dyn FnMut() -> bool) -> Option<String> {
+    log::debug!(target: logging::GDB, "{}", packet);
+    let command: &str = packet.get(..1).unwrap_or("");
+    let args: &str = packet.get(1..).unwrap_or("");
+    let reply: String = match command {
+      "?" => "S05".to_string(),
+      "g" => {
+        let registers: &Registers = &self.debugger.game.registers;
+        (0..REGISTER_COUNT).map(|i| hex_u16_le(get_register(registers, i))).collect()
+      }
+      "G" => self.write_registers(args),
+      "p" => match parse_hex(args) {
+        Some(i) if (i as usize) < REGISTER_COUNT => {
+          hex_u16_le(get_register(&self.debugger.game.registers, i as usize))
+        }
+        _ => "E00".to_string(),
+      },
+      "P" => self.write_register(args),
+      "m" => self.read_memory(args),
+      "M" => self.write_memory(args),
+      "Z" => self.insert_point(args),
+      "z" => self.remove_point(args),
+      "c" => {
+        self.jump(args);
+        self.resume(interrupted)
+      }
+      "s" => {
+        self.jump(args);
+        let reason: BreakReason = self.debugger.step_into();
+        self.stop_reply(reason)
+      }
+      "D" => {
+        self.detach();
+        "OK".to_string()
+      }
+      "k" => {
+        self.detach();
+        return None;
+      }
+      "H" | "T" => "OK".to_string(),
+      "q" | "Q" => self.query(packet),
+      _ => String::new(),
+    };
+    return Some(reply);
+  }
+
+  fn query(&mut self, packet: &str) -> String {
+    if packet.starts_with("qSupported") {
+      return "PacketSize=1000;qXfer:features:read+;swbreak+;QStartNoAckMode+".to_string();
+    }
+    if let Some(range) = packet.strip_prefix("qXfer:features:read:target.xml:") {
+      let (offset, length) = match parse_range(range) {
+        Some((offset, length)) => (offset as usize, length as usize),
+        None => return "E00".to_string(),
+      };
+      let start: usize = offset.min(TARGET_XML.len());
+      let end: usize = (start + length).min(TARGET_XML.len());
+      let more: &str = if end < TARGET_XML.len() { "m" } else { "l" };
+      return format!("{}{}", more, &TARGET_XML[start..end]);
+    }
+    return match packet {
+      "QStartNoAckMode" => {
+        self.no_ack = true;
+        "OK".to_string()
+      }
+      "qAttached" => "1".to_string(),
+      "qC" => "QC1".to_string(),
+      "qfThreadInfo" => "m1".to_string(),
+      "qsThreadInfo" => "l".to_string(),
+      _ => String::new(),
+    };
+  }
+
+  fn write_registers(&mut self, args: &str) -> String {
+    let bytes: Vec<u8> = match parse_hex_bytes(args) {
+      Some(bytes) if bytes.len() == REGISTER_COUNT * 2 => bytes,
+      _ => return "E00".to_string(),
+    };
+    for (i, pair) in bytes.chunks(2).enumerate() {
+      let val: u16 = pair[0] as u16 | (pair[1] as u16) << 8;
+      set_register(&mut self.debugger.game.registers, i, val);
+    }
+    return "OK".to_string();
+  }
+
+  fn write_register(&mut self, args: &str) -> String {
+    let parsed = args.split_once('=').and_then(|(index, val)| {
+      let index: usize = parse_hex(index)? as usize;
+      let bytes: Vec<u8> = parse_hex_bytes(val)?;
+      return if index < REGISTER_COUNT && bytes.len() == 2 { Some((index, bytes)) } else { None };
+    });
+    return match parsed {
+      Some((index, bytes)) => {
+        let val: u16 = bytes[0] as u16 | (bytes[1] as u16) << 8;
+        set_register(&mut self.debugger.game.registers, index, val);
+        "OK".to_string()
+      }
+      None => "E00".to_string(),
+    };
+  }
+
+  fn read_memory(&self, args: &str) -> String {
+    let (address, length) = match parse_range(args) {
+      Some(range) => range,
+      None => return "E00".to_string(),
+    };
+    return (0..length)
+      .map(|i| format!("{:02x}", self.debugger.game.memory.peek_byte(address.wrapping_add(i))))
+      .collect();
+  }
+
+  fn write_memory(&mut self, args: &str) -> String {
+    let parsed = args.split_once(':').and_then(|(range, data)| {
+      return Some((parse_range(range)?, parse_hex_bytes(data)?));
+    });
+    let ((address, length), bytes) = match parsed {
+      Some(parsed) => parsed,
+      None => return "E00".to_string(),
+    };
+    if bytes.len() != length as usize {
+      return "E00".to_string();
+    }
+    for (i, byte) in bytes.iter().enumerate() {
+      self.debugger.game.memory.write_byte(address.wrapping_add(i as u16), *byte);
+    }
+    return "OK".to_string();
+  }
+
+  fn parse_point(args: &str) -> Option<Point> {
+    let (kind, rest) = args.split_once(',')?;
+    let (address, length) = parse_range(rest)?;
+    return Some((parse_hex(kind)? as u8, address, length.max(1)));
+  }
+
+  fn insert_point(&mut self, args: &str) -> String {
+    let point: Point = match GdbStub::parse_point(args) {
+      Some(point) if point.0 <= 4 => point,
+      _ => return String::new(),
+    };
+    if self.points.iter().any(|(p, _)| *p == point) {
+      return "OK".to_string();
+    }
+    let (kind, address, length) = point;
+    let id: usize = if kind <= 1 {
+      self.debugger.add_breakpoint(Breakpoint::new(address))
+    } else {
+      self.debugger.add_watchpoint(Watchpoint {
+        start: address,
+        end: address.wrapping_add(length - 1),
+        read: kind != 2,
+        write: kind != 3,
+        execute: false,
+        enabled: true,
+      })
+    };
+    self.points.push((point, id));
+    return "OK".to_string();
+  }
+
+  fn remove_point(&mut self, args: &str) -> String {
+    let point: Point = match GdbStub::parse_point(args) {
+      Some(point) => point,
+      None => return "E00".to_string(),
+    };
+    if let Some(index) = self.points.iter().position(|(p, _)| *p == point) {
+      let (_, id) = self.points.remove(index);
+      self.debugger.remove(id);
+    }
+    return "OK".to_string();
+  }
+
+  // `c` and `s` may give an address to resume from.
+  fn jump(&mut self, args: &str) {
+    if let Some(address) = parse_hex(args) {
+      self.debugger.game.registers.pc = address as u16;
+    }
+  }
+
+  fn resume(&mut self, interrupted: &mut dyn FnMut() -> bool) -> String {
+    loop {
+      let reason: BreakReason = self.debugger.run(SLICE_CYCLES);
+      if reason != BreakReason::CycleLimit {
+        return self.stop_reply(reason);
+      }
+      if interrupted() {
+        return "S02".to_string();
+      }
+    }
+  }
+
+  fn stop_reply(&self, reason: BreakReason) -> String {
+    return match reason {
+      BreakReason::Breakpoint(_) => "T05swbreak:;".to_string(),
+      BreakReason::Watchpoint { id, address, kind, .. } => {
+        let inserted: Option<u8> =
+          self.points.iter().find(|(_, i)| *i == id).map(|((kind, _, _), _)| *kind);
+        let name: &str = match (inserted, kind) {
+          (Some(4), _) => "awatch",
+          (_, AccessKind::Write) => "watch",
+          _ => "rwatch",
+        };
+        format!("T05{}:{:x};", name, address)
+      }
+      _ => "S05".to_string(),
+    };
+  }
+
+  fn detach(&mut self) {
+    for (_, id) in self.points.drain(..) {
+      self.debugger.remove(id);
+    }
+    self.attached = false;
+  }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+mod server {
+  use super::{frame, logging, GdbStub};
+  use std::io::{self, Read, Write};
+  use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+
+  // Reads up to the next packet and returns its body, acknowledging it
+  // unless acks are off. Returns None when the connection closes.
+  fn read_packet(stream: &mut TcpStream, no_ack: bool) -> io::Result<Option<String>> {
+    let mut byte: [u8; 1] = [0];
+    loop {
+      // Skip acks and anything else between packets.
+      loop {
+        if stream.read(&mut byte)? == 0 {
+          return Ok(None);
+        }
+        if byte[0] == b'$' {
+          break;
+        }
+      }
+      let mut body: Vec<u8> = Vec::new();
+      loop {
+        if stream.read(&mut byte)? == 0 {
+          return Ok(None);
+        }
+        if byte[0] == b'#' {
+          break;
+        }
+        body.push(byte[0]);
+      }
+      let mut checksum: [u8; 2] = [0; 2];
+      stream.read_exact(&mut checksum)?;
+      let expected: Option<u8> =
+        std::str::from_utf8(&checksum).ok().and_then(|text| u8::from_str_radix(text, 16).ok());
+      if expected == Some(body.iter().fold(0, |sum: u8, b| sum.wrapping_add(*b))) {
+        if !no_ack {
+          stream.write_all(b"+")?;
+        }
+        return Ok(Some(String::from_utf8_lossy(&body).into_owned()));
+      }
+      stream.write_all(b"-")?;
+    }
+  }
+
+  // Whether GDB has sent a break (0x03) while the game runs.
+  fn break_requested(stream: &mut TcpStream) -> bool {
+    let mut byte: [u8; 1] = [0];
+    if stream.set_nonblocking(true).is_err() {
+      return false;
+    }
+    let requested: bool = matches!(stream.read(&mut byte), Ok(1) if byte[0] == 0x03);
+    let _ = stream.set_nonblocking(false);
+    return requested;
+  }
+
+  impl GdbStub {
+    // Waits for one debugger to connect to `address` and serves it until it
+    // detaches or disconnects. Bind to localhost: the protocol has no
+    // authentication.
+    pub fn serve<A: ToSocketAddrs>(&mut self, address: A) -> io::Result<()> {
+      let listener: TcpListener = TcpListener::bind(address)?;
+      log::info!(target: logging::GDB, "listening on {}", listener.local_addr()?);
+      let (stream, peer) = listener.accept()?;
+      log::info!(target: logging::GDB, "{} attached", peer);
+      return self.serve_connection(stream);
+    }
+
+    pub fn serve_connection(&mut self, mut stream: TcpStream) -> io::Result<()> {
+      stream.set_nodelay(true)?;
+      self.attached = true;
+      while self.attached {
+        let packet: String = match read_packet(&mut stream, self.no_ack)? {
+          Some(packet) => packet,
+          None => break,
+        };
+        let mut poll = || break_requested(&mut stream);
+        let reply: Option<String> = self.handle(&packet, &mut poll);
+        if let Some(reply) = reply {
+          stream.write_all(frame(&reply).as_bytes())?;
+        }
+      }
+      return Ok(());
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::game::new_game;
+
+  // 0x100: LD A,5; LD (0xc000),A; INC A; JR 0x105
+  fn stub() -> GdbStub {
+    let mut rom: Vec<u8> = vec![0; 0x8000];
+    rom[0x100..0x108].copy_from_slice(&[0x3e, 0x05, 0xea, 0x00, 0xc0, 0x3c, 0x18, 0xfd]);
+    let mut stub: GdbStub = GdbStub::new(Debugger::new(new_game(rom)));
+    stub.debugger.game.registers.sp = 0xfffe;
+    stub.debugger.game.registers.pc = 0x100;
+    return stub;
+  }
+
+  fn send(stub: &mut GdbStub, packet: &str) -> String {
+    return stub.handle(packet, &mut || false).unwrap();
+  }
+
+  #[test]
+  fn frames_and_escapes_replies() {
+    assert_eq!(frame("OK"), "$OK#9a");
+    assert_eq!(frame(""), "$#00");
+    assert_eq!(frame("a#b"), "$a}\u{3}b#43");
+  }
+
+  #[test]
+  fn reads_and_writes_registers_and_memory() {
+    let mut stub: GdbStub = stub();
+    stub.debugger.game.registers.set_af(0x01b0);
+    assert_eq!(send(&mut stub, "g"), "b0011300d8004d01feff0001");
+    assert_eq!(send(&mut stub, "p5"), "0001");
+    assert_eq!(send(&mut stub, "P2=3412"), "OK");
+    assert_eq!(stub.debugger.game.registers.get_de(), 0x1234);
+    assert_eq!(send(&mut stub, "G"), "E00");
+    assert_eq!(send(&mut stub, "m100,3"), "3e05ea");
+    assert_eq!(send(&mut stub, "Mc010,2:abcd"), "OK");
+    assert_eq!(send(&mut stub, "mc010,2"), "abcd");
+  }
+
+  #[test]
+  fn serves_the_target_description_in_chunks() {
+    let mut stub: GdbStub = stub();
+    assert!(send(&mut stub, "qSupported:multiprocess+").contains("qXfer:features:read+"));
+    let first: String = send(&mut stub, "qXfer:features:read:target.xml:0,20");
+    assert_eq!(first, format!("m{}", &TARGET_XML[..0x20]));
+    let rest: String = send(&mut stub, "qXfer:features:read:target.xml:20,1000");
+    assert_eq!(rest, format!("l{}", &TARGET_XML[0x20..]));
+  }
+
+  #[test]
+  fn breakpoints_watchpoints_and_stepping() {
+    let mut stub: GdbStub = stub();
+    assert_eq!(send(&mut stub, "s"), "S05");
+    assert_eq!(stub.debugger.game.registers.pc, 0x102);
+    assert_eq!(send(&mut stub, "Z2,c000,1"), "OK");
+    assert_eq!(send(&mut stub, "c"), "T05watch:c000;");
+    assert_eq!(send(&mut stub, "z2,c000,1"), "OK");
+    assert_eq!(send(&mut stub, "Z0,106,1"), "OK");
+    assert_eq!(send(&mut stub, "c"), "T05swbreak:;");
+    assert_eq!(stub.debugger.game.registers.pc, 0x106);
+    assert_eq!(send(&mut stub, "z0,106,1"), "OK");
+    assert_eq!(stub.debugger.breakpoints().count(), 0);
+    // With nothing to stop at, the run ends when GDB interrupts it.
+    let mut polls: usize = 0;
+    let reply: Option<String> = stub.handle("c", &mut || {
+      polls += 1;
+      polls == 3
+    });
+    assert_eq!(reply.as_deref(), Some("S02"));
+  }
+
+  #[test]
+  fn serves_packets_over_tcp() {
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+
+    let listener: TcpListener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap();
+    let server = std::thread::spawn(move || {
+      let mut stub: GdbStub = stub();
+      let (stream, _) = listener.accept().unwrap();
+      stub.serve_connection(stream).unwrap();
+      return stub.debugger.game.registers.pc;
+    });
+
+    let mut client: TcpStream = TcpStream::connect(address).unwrap();
+    let mut exchange = |packet: &str, expected: &str| {
+      client.write_all(frame(packet).as_bytes()).unwrap();
+      let mut reply: Vec<u8> = vec![0; expected.len()];
+      client.read_exact(&mut reply).unwrap();
+      assert_eq!(String::from_utf8(reply).unwrap(), expected);
+    };
+    exchange("p5", "+$0001#c1");
+    exchange("QStartNoAckMode", "+$OK#9a");
+    exchange("s", "$S05#b8");
+    exchange("D", "$OK#9a");
+    assert_eq!(server.join().unwrap(), 0x102);
+  }
+}
@@ -1,15 +1,161 @@
-use crate::game::interrupts::INTERRUPTS;
-use crate::game::memory::Memory;
+use crate::game::interrupts::{Interrupts, INTERRUPTS_LCDSTAT};
+use crate::game::logging;
+use std::collections::VecDeque;
+
+pub const SCREEN_WIDTH: usize = 160;
+pub const SCREEN_HEIGHT: usize = 144;
+
+// LCDC bits
+pub const LCDC_BG_ENABLE: u8 = 1 << 0;
+pub const LCDC_OBJ_ENABLE: u8 = 1 << 1;
+pub const LCDC_OBJ_TALL: u8 = 1 << 2;
+pub const LCDC_BG_MAP: u8 = 1 << 3;
+pub const LCDC_TILE_DATA: u8 = 1 << 4;
+pub const LCDC_WINDOW_ENABLE: u8 = 1 << 5;
+pub const LCDC_WINDOW_MAP: u8 = 1 << 6;
+pub const LCDC_DISPLAY_ENABLE: u8 = 1 << 7;
+
+// STAT bits. 0-1 are the mode, 2 is set while LY == LYC and 3-6 select
+// which conditions raise the LCD STAT interrupt.
+pub const STAT_COINCIDENCE: u8 = 1 << 2;
+pub const STAT_HBLANK_SOURCE: u8 = 1 << 3;
+pub const STAT_VBLANK_SOURCE: u8 = 1 << 4;
+pub const STAT_OAM_SOURCE: u8 = 1 << 5;
+pub const STAT_COINCIDENCE_SOURCE: u8 = 1 << 6;
+const STAT_SOURCES: u8 = 0x78;
+
+// OAM attribute bits
+pub const OBJ_BEHIND_BG: u8 = 1 << 7;
+pub const OBJ_FLIP_Y: u8 = 1 << 6;
+pub const OBJ_FLIP_X: u8 = 1 << 5;
+pub const OBJ_PALETTE: u8 = 1 << 4;
+
+// Which palette a framebuffer pixel was drawn with, so hosts can colour the
+// background and each object palette differently.
+pub const LAYER_BG: u8 = 0;
+pub const LAYER_OBJ0: u8 = 1;
+pub const LAYER_OBJ1: u8 = 2;
+
+pub const TILE_COUNT: usize = 384;
+
+const OBJS_PER_LINE: usize = 10;
+
+const DOTS_PER_LINE: u16 = 456;
+const LINES_PER_FRAME: u8 = 154;
+const OAM_SCAN_DOTS: u16 = 80;
+// Mode 3 never runs long enough to leave less than 87 dots of HBlank.
+const MAX_TRANSFER_DOTS: u16 = 289;
+
+// How mode 3 turns VRAM into pixels. `Scanline` draws each line in one go
+// when mode 3 ends, so register writes during the line are missed.
+// `PixelFifo` models the hardware fetcher and FIFOs dot by dot, which is
+// slower but shows mid-line changes to SCX, BGP, LCDC and so on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Renderer {
+  Scanline,
+  PixelFifo,
+}
+
+// Layers and objects to draw, for debugging and screenshots. A hidden
+// background or window shows colour 0 in its place and a hidden object is
+// left out; everything else, including timing, runs as normal.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LayerToggles {
+  pub background: bool,
+  pub window: bool,
+  pub objects: bool,
+  // Indexed by OAM entry.
+  pub object: [bool; 40],
+}
+
+impl Default for LayerToggles {
+  fn default() -> LayerToggles {
+    return LayerToggles {
+      background: true,
+      window: true,
+      objects: true,
+      object: [true; 40],
+    };
+  }
+}
+
+// The layers `LayerToggles` can hide as a whole.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Layer {
+  Background,
+  Window,
+  Objects,
+}
+
+impl LayerToggles {
+  pub fn set_layer(&mut self, layer: Layer, shown: bool) {
+    match layer {
+      Layer::Background => self.background = shown,
+      Layer::Window => self.window = shown,
+      Layer::Objects => self.objects = shown,
+    }
+  }
+
+  fn shows_object(&self, entry: usize) -> bool {
+    return self.objects && self.object[entry / 4];
+  }
+}
 
 pub struct Gpu {
   pub control: u8,
   pub scroll_x: u8,
   pub scroll_y: u8,
   pub scanline: u8,
-  pub tick: u32,
+  pub scanline_compare: u8,
+  // The interrupt source bits of STAT; the rest is derived on read.
+  pub stat_sources: u8,
+  pub window_x: u8,
+  pub window_y: u8,
+  pub bg_palette: u8,
+  pub obj_palette0: u8,
+  pub obj_palette1: u8,
+  // Dots into the current line, 0-455.
+  pub dot: u16,
+
+  // Written only through `write_vram`, which keeps `tiles` in step.
+  video_ram: [u8; 0x2000],
+  // The 384 tiles at 0x8000-0x97ff decoded to colour numbers, [tile][y][x].
+  pub tiles: [[[u8; 8]; 8]; TILE_COUNT],
+  // Set when a tile, or an entry of the tile map at 0x9800 (0) or 0x9c00
+  // (1), is written; cleared by `take_dirty_tiles` and `take_dirty_maps`.
+  dirty_tiles: [bool; TILE_COUNT],
+  dirty_maps: [bool; 2],
+  pub oam: [u8; 0x100],
+
+  // Shades 0 (lightest) to 3 (darkest) after the palettes are applied, one
+  // byte per pixel, row by row.
+  pub framebuffer: [u8; SCREEN_WIDTH * SCREEN_HEIGHT],
+  // The LAYER_* each framebuffer pixel came from.
+  pub layers: [u8; SCREEN_WIDTH * SCREEN_HEIGHT],
+  // Frames completed since power on; bumped on entering VBlank.
+  pub frames: u64,
+  pub layer_toggles: LayerToggles,
 
-  last_ticks: u32,
   mode: GpuMode,
+  // The line being drawn. LY (`scanline`) matches it except on line 153.
+  line: u8,
+  // Length of mode 3 on the current line, fixed when it starts.
+  transfer_dots: u16,
+  // Set by turning the LCD on: the first line skips OAM scan, and the first
+  // frame is not shown.
+  lcd_starting: bool,
+  blank_frame: bool,
+  // The window has its own line counter, which only advances on lines
+  // where the window was drawn.
+  window_line: u8,
+  // All enabled STAT conditions OR'd together. The interrupt fires only when
+  // this goes from low to high, so overlapping conditions block each other.
+  stat_line: bool,
+  // The renderer drawing the current line, and the one asked for by
+  // `set_renderer`, which takes over when the next line's mode 3 starts.
+  renderer: Renderer,
+  next_renderer: Renderer,
+  fifo: PixelFifo,
 }
 
 enum GpuMode {
@@ -19,107 +165,1104 @@ enum GpuMode {
   Vram,
 }
 
+impl Default for Gpu {
+  fn default() -> Gpu {
+    // The state the boot ROM leaves behind.
+    return Gpu {
+      control: 0x91,
+      scroll_x: 0,
+      scroll_y: 0,
+      scanline: 0,
+      scanline_compare: 0,
+      stat_sources: 0,
+      window_x: 0,
+      window_y: 0,
+      bg_palette: 0xfc,
+      obj_palette0: 0xff,
+      obj_palette1: 0xff,
+      dot: 0,
+
+      video_ram: [0; 0x2000],
+      tiles: [[[0; 8]; 8]; TILE_COUNT],
+      dirty_tiles: [false; TILE_COUNT],
+      dirty_maps: [false; 2],
+      oam: [0; 0x100],
+
+      framebuffer: [0; SCREEN_WIDTH * SCREEN_HEIGHT],
+      layers: [LAYER_BG; SCREEN_WIDTH * SCREEN_HEIGHT],
+      frames: 0,
+      layer_toggles: Default::default(),
+
+      mode: GpuMode::Oam,
+      line: 0,
+      transfer_dots: 0,
+      lcd_starting: false,
+      blank_frame: false,
+      window_line: 0,
+      stat_line: false,
+      renderer: Renderer::Scanline,
+      next_renderer: Renderer::Scanline,
+      fifo: Default::default(),
+    };
+  }
+}
+
 impl Gpu {
-  pub fn step(&mut self, ticks: &mut u32) {
-    self.tick = *ticks - self.last_ticks;
-    self.last_ticks = *ticks;
+  // Advances the GPU by `cycles` T-cycles (dots). Called by the memory bus on
+  // every M-cycle so the mode and scanline are current for mid-instruction
+  // reads.
+  pub fn step(&mut self, cycles: u32, interrupts: &mut Interrupts) {
+    if self.control & LCDC_DISPLAY_ENABLE == 0 {
+      return;
+    }
+    for _ in 0..cycles {
+      self.step_dot(interrupts);
+    }
+    self.update_stat_line(interrupts);
+  }
+
+  // Each line is 456 dots: 80 of OAM scan, a variable-length transfer to the
+  // LCD, and HBlank for the rest. Lines 144-153 are VBlank.
+  fn step_dot(&mut self, interrupts: &mut Interrupts) {
+    self.dot += 1;
 
     use GpuMode::*;
     match self.mode {
-      Hblank => {
-        if self.tick >= 204 {
-          self.scanline += 1;
-
-          if self.scanline == 143 {
-            if INTERRUPTS.has_vblank_interrupt() {
-              INTERRUPTS.set_vblank_interrupt();
+      Oam => {
+        if self.dot == OAM_SCAN_DOTS {
+          self.start_transfer();
+        }
+      }
+      Vram => {
+        let done: bool = match self.renderer {
+          Renderer::Scanline => {
+            let done: bool = self.dot == OAM_SCAN_DOTS + self.transfer_dots;
+            if done {
+              self.render_scanline();
             }
-
-            self.mode = Vblank;
-          } else {
-            self.mode = Oam;
+            done
           }
-          self.tick -= 204;
+          Renderer::PixelFifo => self.fifo_dot(),
+        };
+        if done {
+          self.mode = Hblank;
+        }
+      }
+      Hblank | Vblank => {
+        // The first line after the LCD is switched on has no OAM scan; it
+        // reports mode 0 until the transfer starts.
+        if self.lcd_starting && self.dot == OAM_SCAN_DOTS {
+          self.lcd_starting = false;
+          self.start_transfer();
+        }
+        // LY already reads 0 for most of line 153.
+        if self.line == 153 && self.dot == 4 {
+          self.scanline = 0;
+        }
+        if self.dot == DOTS_PER_LINE {
+          self.next_line(interrupts);
         }
       }
-      Vblank => {
-        if self.tick >= 456 {
-          self.scanline += 1;
+    }
+  }
 
-          if self.scanline == 153 {
-            self.scanline = 0;
-            self.mode = Oam;
-          }
-          self.tick -= 456;
+  fn next_line(&mut self, interrupts: &mut Interrupts) {
+    self.dot = 0;
+    self.line += 1;
+    if self.line == SCREEN_HEIGHT as u8 {
+      interrupts.set_vblank_interrupt();
+      self.frames += 1;
+      self.window_line = 0;
+      self.blank_frame = false;
+      self.mode = GpuMode::Vblank;
+    } else if self.line == LINES_PER_FRAME {
+      self.line = 0;
+      self.mode = GpuMode::Oam;
+    } else if self.line < SCREEN_HEIGHT as u8 {
+      self.mode = GpuMode::Oam;
+    }
+    self.scanline = self.line;
+  }
+
+  pub fn renderer(&self) -> Renderer {
+    return self.next_renderer;
+  }
+
+  pub fn set_renderer(&mut self, renderer: Renderer) {
+    self.next_renderer = renderer;
+  }
+
+  fn start_transfer(&mut self) {
+    self.mode = GpuMode::Vram;
+    self.renderer = self.next_renderer;
+    match self.renderer {
+      Renderer::Scanline => self.transfer_dots = self.transfer_length(),
+      Renderer::PixelFifo => self.start_fifo(),
+    }
+  }
+
+  // How long mode 3 lasts on this line. The fetcher stalls to discard the
+  // SCX fine scroll, to restart for the window and to fetch each object.
+  fn transfer_length(&self) -> u16 {
+    let mut dots: u16 = 172 + (self.scroll_x % 8) as u16;
+    if self.control & LCDC_BG_ENABLE != 0 && self.window_visible() {
+      dots += 6;
+    }
+    if self.control & LCDC_OBJ_ENABLE != 0 {
+      for entry in self.objects_on_line() {
+        if self.oam[entry + 1] >= 168 {
+          continue;
         }
+        let alignment: u8 = self.oam[entry + 1].wrapping_add(self.scroll_x) % 8;
+        dots += 6 + (5 - alignment.min(5)) as u16;
       }
-      Oam => {
-        if self.tick >= 80 {
-          self.mode = Vram;
-          self.tick -= 80;
+    }
+    return dots.min(MAX_TRANSFER_DOTS);
+  }
+
+  // Turning the LCD off resets LY and the mode and blanks the screen;
+  // turning it back on starts a new frame that is not displayed.
+  pub fn write_control(&mut self, val: u8, interrupts: &mut Interrupts) {
+    let was_enabled: bool = self.control & LCDC_DISPLAY_ENABLE != 0;
+    self.control = val;
+    let enabled: bool = val & LCDC_DISPLAY_ENABLE != 0;
+
+    if was_enabled && !enabled {
+      log::debug!(target: logging::PPU, "LCD off at line {}", self.line);
+      self.line = 0;
+      self.scanline = 0;
+      self.dot = 0;
+      self.mode = GpuMode::Hblank;
+      self.window_line = 0;
+      self.framebuffer = [0; SCREEN_WIDTH * SCREEN_HEIGHT];
+      self.layers = [LAYER_BG; SCREEN_WIDTH * SCREEN_HEIGHT];
+      self.stat_line = false;
+    } else if !was_enabled && enabled {
+      log::debug!(target: logging::PPU, "LCD on");
+      self.lcd_starting = true;
+      self.blank_frame = true;
+      self.update_stat_line(interrupts);
+    }
+  }
+
+  // The CPU can't reach VRAM while mode 3 is reading it, or OAM during OAM
+  // scan and mode 3. With the LCD off both are always free.
+  pub fn vram_accessible(&self) -> bool {
+    return !matches!(self.mode, GpuMode::Vram);
+  }
+
+  pub fn oam_accessible(&self) -> bool {
+    return !matches!(self.mode, GpuMode::Oam | GpuMode::Vram);
+  }
+
+  pub fn read_vram(&self, address: u16) -> u8 {
+    return self.video_ram[(address & 0x1fff) as usize];
+  }
+
+  // Takes an address in 0x8000-0x9fff (or an offset into VRAM).
+  pub fn write_vram(&mut self, address: u16, val: u8) {
+    let offset: usize = (address & 0x1fff) as usize;
+    self.video_ram[offset] = val;
+    if offset < TILE_COUNT * 16 {
+      self.update_tile_row(offset);
+    } else {
+      self.dirty_maps[(offset - 0x1800) / 0x400] = true;
+    }
+  }
+
+  // Which tiles have been written since the last call.
+  pub fn take_dirty_tiles(&mut self) -> [bool; TILE_COUNT] {
+    return std::mem::replace(&mut self.dirty_tiles, [false; TILE_COUNT]);
+  }
+
+  // Which tile maps have been written since the last call.
+  pub fn take_dirty_maps(&mut self) -> [bool; 2] {
+    return std::mem::take(&mut self.dirty_maps);
+  }
+
+  // Re-decodes the row of 2bpp tile data that `offset` falls in.
+  fn update_tile_row(&mut self, offset: usize) {
+    let tile: usize = offset / 16;
+    let y: usize = (offset % 16) / 2;
+    let low: u8 = self.video_ram[tile * 16 + y * 2];
+    let high: u8 = self.video_ram[tile * 16 + y * 2 + 1];
+    for (x, color) in self.tiles[tile][y].iter_mut().enumerate() {
+      let bit: usize = 7 - x;
+      *color = (((high >> bit) & 1) << 1) | ((low >> bit) & 1);
+    }
+    self.dirty_tiles[tile] = true;
+  }
+
+  pub fn read_stat(&self) -> u8 {
+    let mut stat: u8 = 0x80 | self.stat_sources | self.mode_bits();
+    if self.scanline == self.scanline_compare {
+      stat |= STAT_COINCIDENCE;
+    }
+    return stat;
+  }
+
+  pub fn write_stat(&mut self, val: u8, interrupts: &mut Interrupts) {
+    self.stat_sources = val & STAT_SOURCES;
+    self.update_stat_line(interrupts);
+  }
+
+  pub fn write_scanline_compare(&mut self, val: u8, interrupts: &mut Interrupts) {
+    self.scanline_compare = val;
+    self.update_stat_line(interrupts);
+  }
+
+  fn mode_bits(&self) -> u8 {
+    return match self.mode {
+      GpuMode::Hblank => 0,
+      GpuMode::Vblank => 1,
+      GpuMode::Oam => 2,
+      GpuMode::Vram => 3,
+    };
+  }
+
+  fn update_stat_line(&mut self, interrupts: &mut Interrupts) {
+    let sources: u8 = self.stat_sources;
+    let line: bool = match self.mode {
+      GpuMode::Hblank => sources & STAT_HBLANK_SOURCE != 0,
+      GpuMode::Vblank => sources & STAT_VBLANK_SOURCE != 0,
+      GpuMode::Oam => sources & STAT_OAM_SOURCE != 0,
+      GpuMode::Vram => false,
+    } || (sources & STAT_COINCIDENCE_SOURCE != 0 && self.scanline == self.scanline_compare);
+
+    if line && !self.stat_line {
+      interrupts.request(INTERRUPTS_LCDSTAT);
+    }
+    self.stat_line = line;
+  }
+
+  // Draws the current scanline into the framebuffer.
+  fn render_scanline(&mut self) {
+    let y: usize = self.scanline as usize;
+    // Raw colour numbers of the background and window, before BGP; sprites
+    // with the behind-BG flag only show through colour 0.
+    let mut bg_colors: [u8; SCREEN_WIDTH] = [0; SCREEN_WIDTH];
+
+    if self.control & LCDC_BG_ENABLE != 0 {
+      let map: u16 = if self.control & LCDC_BG_MAP != 0 { 0x1c00 } else { 0x1800 };
+      let bg_y: u8 = self.scroll_y.wrapping_add(self.scanline);
+      if self.layer_toggles.background {
+        for (x, color) in bg_colors.iter_mut().enumerate() {
+          let bg_x: u8 = self.scroll_x.wrapping_add(x as u8);
+          *color = self.map_pixel(map, bg_x, bg_y);
         }
       }
-      Vram => {
-        if self.tick >= 172 {
-          self.mode = Hblank;
-          self.tick -= 172;
+
+      if self.window_visible() {
+        let map: u16 = if self.control & LCDC_WINDOW_MAP != 0 { 0x1c00 } else { 0x1800 };
+        let left: i16 = self.window_x as i16 - 7;
+        let shown: bool = self.layer_toggles.window;
+        for (x, color) in bg_colors.iter_mut().enumerate() {
+          if (x as i16) >= left {
+            *color = if shown {
+              self.map_pixel(map, (x as i16 - left) as u8, self.window_line)
+            } else {
+              0
+            };
+          }
         }
+        self.window_line += 1;
       }
     }
+
+    let row = y * SCREEN_WIDTH..(y + 1) * SCREEN_WIDTH;
+    for (pixel, &color) in self.framebuffer[row.clone()].iter_mut().zip(bg_colors.iter()) {
+      *pixel = shade(self.bg_palette, color);
+    }
+    self.layers[row.clone()].fill(LAYER_BG);
+
+    if self.control & LCDC_OBJ_ENABLE != 0 {
+      self.render_objects(&bg_colors);
+    }
+
+    if self.blank_frame {
+      self.framebuffer[row.clone()].fill(0);
+      self.layers[row].fill(LAYER_BG);
+    }
+  }
+
+  // WX is offset by 7, so the window's left edge is at WX - 7.
+  fn window_visible(&self) -> bool {
+    return self.control & LCDC_WINDOW_ENABLE != 0
+      && self.scanline >= self.window_y
+      && self.window_x <= 166;
   }
-}
 
-pub const GPU: Gpu = Gpu {
-  control: 0,
-  scroll_x: 0,
-  scroll_y: 0,
-  scanline: 0,
-  tick: 0,
+  // OAM offsets of the first ten objects, in OAM order, that overlap the
+  // current line.
+  fn objects_on_line(&self) -> Vec<usize> {
+    let height: i16 = if self.control & LCDC_OBJ_TALL != 0 { 16 } else { 8 };
+    let line: i16 = self.scanline as i16;
+    return (0..40)
+      .map(|index| index * 4)
+      .filter(|&entry| {
+        let top: i16 = self.oam[entry] as i16 - 16;
+        line >= top && line < top + height
+      })
+      .take(OBJS_PER_LINE)
+      .collect();
+  }
 
-  last_ticks: 0,
-  mode: GpuMode::Hblank,
-};
+  // Colour number of a background or window pixel from the tile map at
+  // `map` (an offset into VRAM).
+  pub(crate) fn map_pixel(&self, map: u16, x: u8, y: u8) -> u8 {
+    let index: usize = map as usize + (y as usize / 8) * 32 + (x as usize / 8);
+    let tile: u8 = self.video_ram[index];
+    // With LCDC.4 clear, tile numbers are signed and relative to 0x9000.
+    let tile_address: usize = if self.control & LCDC_TILE_DATA != 0 {
+      tile as usize * 16
+    } else {
+      (0x1000 + (tile as i8 as i16) * 16) as usize
+    };
+    return self.tile_pixel(tile_address, x % 8, y % 8);
+  }
 
-const TILES: [[[u8; 8]; 8]; 384] = [[[0; 8]; 8]; 384];
+  // `y` may run past 7 into the following tile, for 8x16 objects.
+  fn tile_pixel(&self, tile_address: usize, x: u8, y: u8) -> u8 {
+    let tile: usize = tile_address / 16 + y as usize / 8;
+    return self.tiles[tile][y as usize % 8][x as usize];
+  }
 
-struct Sprite {
-  x: u8,
-  y: u8,
+  fn render_objects(&mut self, bg_colors: &[u8; SCREEN_WIDTH]) {
+    let height: i16 = if self.control & LCDC_OBJ_TALL != 0 { 16 } else { 8 };
+    let line: i16 = self.scanline as i16;
+
+    let mut visible: Vec<usize> = self.objects_on_line();
+    // Where objects overlap, the one with the smaller X wins, then the one
+    // earlier in OAM. Drawing in reverse priority order lets winners paint
+    // over losers.
+    visible.sort_by_key(|&entry| (self.oam[entry + 1], entry));
+
+    for &entry in visible.iter().rev() {
+      if !self.layer_toggles.shows_object(entry) {
+        continue;
+      }
+      let top: i16 = self.oam[entry] as i16 - 16;
+      let left: i16 = self.oam[entry + 1] as i16 - 8;
+      let flags: u8 = self.oam[entry + 3];
+      let mut tile: u8 = self.oam[entry + 2];
+      if height == 16 {
+        tile &= 0xfe;
+      }
+
+      let mut row: i16 = line - top;
+      if flags & OBJ_FLIP_Y != 0 {
+        row = height - 1 - row;
+      }
+      let (palette, layer): (u8, u8) = if flags & OBJ_PALETTE != 0 {
+        (self.obj_palette1, LAYER_OBJ1)
+      } else {
+        (self.obj_palette0, LAYER_OBJ0)
+      };
+
+      for column in 0..8 {
+        let x: i16 = left + column;
+        if x < 0 || x >= SCREEN_WIDTH as i16 {
+          continue;
+        }
+        let tile_x: u8 = if flags & OBJ_FLIP_X != 0 { 7 - column as u8 } else { column as u8 };
+        let color: u8 = self.tile_pixel(tile as usize * 16, tile_x, row as u8);
+        if color == 0 {
+          continue;
+        }
+        if flags & OBJ_BEHIND_BG != 0 && bg_colors[x as usize] != 0 {
+          continue;
+        }
+        let pixel: usize = self.scanline as usize * SCREEN_WIDTH + x as usize;
+        self.framebuffer[pixel] = shade(palette, color);
+        self.layers[pixel] = layer;
+      }
+    }
+  }
+}
+
+// State of the pixel FIFO renderer during mode 3.
+#[derive(Default)]
+struct PixelFifo {
+  // Background/window colour numbers waiting to be shifted out.
+  bg: VecDeque<u8>,
+  // Object pixels lined up with `bg`; shorter when no object covers them.
+  obj: VecDeque<ObjPixel>,
+  // The fetcher reads the tile number, the low byte and the high byte, two
+  // dots each, then pushes eight pixels once the background FIFO is empty.
+  fetch_step: u8,
+  fetch_dots: u8,
+  // Tile column of the next fetch, relative to SCX or the window's left edge.
+  fetch_x: u8,
   tile: u8,
+  low: u8,
+  high: u8,
+  in_window: bool,
+  // Next pixel to output on this line.
+  lcd_x: u8,
+  // Pixels to drop before output starts: the SCX fine scroll, or the part of
+  // the window left of the screen when WX < 7.
+  discard: u8,
+  // Dots during which the fetcher and shifter are both paused.
+  stall: u16,
+  // OAM offsets of the objects on this line still to be fetched, leftmost
+  // first.
+  objects: Vec<usize>,
 }
 
-#[derive(Clone, Copy)]
-struct Color {
-  pub r: u8,
-  pub g: u8,
-  pub b: u8,
+#[derive(Clone, Copy, Default)]
+struct ObjPixel {
+  color: u8,
+  obp1: bool,
+  behind_bg: bool,
 }
 
-const BACKGROUND_PALETTE: [Color; 4] = [Color { r: 0, g: 0, b: 0 }; 4];
-const SPRITE_PALETTE: [[Color; 4]; 2] = [[Color { r: 0, g: 0, b: 0 }; 4]; 2];
+impl Gpu {
+  fn start_fifo(&mut self) {
+    let mut objects: Vec<usize> = self.objects_on_line();
+    // Objects past the right edge are never reached.
+    objects.retain(|&entry| self.oam[entry + 1] < 168);
+    objects.sort_by_key(|&entry| (self.oam[entry + 1], entry));
+    self.fifo = PixelFifo {
+      discard: self.scroll_x % 8,
+      // The first tile is fetched twice; the first copy is thrown away.
+      stall: 6,
+      objects,
+      ..Default::default()
+    };
+  }
 
-fn update_tile(memory: &Memory, _addr: &mut u16) {
-  let addr = *_addr & 0x1ffe;
+  // Runs one dot of mode 3, returning true once the last pixel of the line
+  // has been output.
+  fn fifo_dot(&mut self) -> bool {
+    if self.fifo.stall > 0 {
+      self.fifo.stall -= 1;
+      return false;
+    }
 
-  let tile: u16 = (addr >> 4) & 511;
-  let y: u8 = ((addr >> 1) & 7) as u8;
+    // An object is fetched when its left edge reaches the output position.
+    // The fetch pauses everything for 6 dots, plus however long the fetcher
+    // needs to finish the background tile it is on.
+    if self.control & LCDC_OBJ_ENABLE != 0 {
+      if let Some(&entry) = self.fifo.objects.first() {
+        let x: u8 = self.oam[entry + 1];
+        if x <= self.fifo.lcd_x + 8 {
+          // Output waits for the object even if the background FIFO has
+          // just run dry.
+          if self.fifo.bg.is_empty() {
+            self.step_fetcher();
+          }
+          if !self.fifo.bg.is_empty() {
+            self.fifo.objects.remove(0);
+            self.fetch_object(entry);
+            let alignment: u8 = x.wrapping_add(self.scroll_x) % 8;
+            self.fifo.stall = 5 + (5 - alignment.min(5)) as u16;
+          }
+          return false;
+        }
+      }
+    }
+
+    self.step_fetcher();
+
+    if self.fifo.bg.is_empty() {
+      return false;
+    }
+
+    // Reaching the window throws away the background pixels and restarts the
+    // fetcher on the window's tile map.
+    let window_reached: bool = self.fifo.lcd_x as u16 + 7 >= self.window_x as u16;
+    let window_enabled: bool = self.control & LCDC_BG_ENABLE != 0 && self.window_visible();
+    if !self.fifo.in_window && window_enabled && window_reached {
+      self.fifo.in_window = true;
+      self.fifo.bg.clear();
+      self.fifo.fetch_step = 0;
+      self.fifo.fetch_dots = 0;
+      self.fifo.fetch_x = 0;
+      self.fifo.discard = 7u8.saturating_sub(self.window_x);
+      self.step_fetcher();
+      return false;
+    }
+
+    let bg_color: u8 = self.fifo.bg.pop_front().unwrap();
+    if self.fifo.discard > 0 {
+      self.fifo.discard -= 1;
+      return false;
+    }
+    let obj: ObjPixel = self.fifo.obj.pop_front().unwrap_or_default();
+    self.output_pixel(bg_color, obj);
+
+    self.fifo.lcd_x += 1;
+    if self.fifo.lcd_x as usize == SCREEN_WIDTH {
+      if self.fifo.in_window {
+        self.window_line += 1;
+      }
+      return true;
+    }
+    return false;
+  }
 
-  let mut bit_index: u8;
-  for idx in 0..8 {
-    bit_index = 1 << (7 - idx);
+  fn step_fetcher(&mut self) {
+    self.fifo.fetch_dots += 1;
+    match self.fifo.fetch_step {
+      0..=2 if self.fifo.fetch_dots < 2 => {}
+      0 => {
+        self.fifo.tile = self.video_ram[self.fetch_map_address()];
+        self.fifo.fetch_step = 1;
+        self.fifo.fetch_dots = 0;
+      }
+      1 | 2 => {
+        let address: usize = self.fetch_tile_address() + self.fetch_row() as usize * 2;
+        if self.fifo.fetch_step == 1 {
+          self.fifo.low = self.video_ram[address];
+        } else {
+          self.fifo.high = self.video_ram[address + 1];
+        }
+        self.fifo.fetch_step += 1;
+        self.fifo.fetch_dots = 0;
+      }
+      _ => {
+        if self.fifo.bg.is_empty() {
+          for bit in (0..8).rev() {
+            let color: u8 = (((self.fifo.high >> bit) & 1) << 1) | ((self.fifo.low >> bit) & 1);
+            self.fifo.bg.push_back(color);
+          }
+          self.fifo.fetch_x = self.fifo.fetch_x.wrapping_add(1);
+          self.fifo.fetch_step = 0;
+          self.fifo.fetch_dots = 0;
+        }
+      }
+    }
+  }
+
+  // VRAM offset of the tile map entry the fetcher reads next. LCDC and SCX
+  // are sampled here, per tile, as on hardware.
+  fn fetch_map_address(&self) -> usize {
+    if self.fifo.in_window {
+      let map: usize = if self.control & LCDC_WINDOW_MAP != 0 { 0x1c00 } else { 0x1800 };
+      return map + (self.window_line as usize / 8) * 32 + (self.fifo.fetch_x as usize & 31);
+    }
+    let map: usize = if self.control & LCDC_BG_MAP != 0 { 0x1c00 } else { 0x1800 };
+    let column: usize = ((self.scroll_x / 8) as usize + self.fifo.fetch_x as usize) & 31;
+    let row: usize = self.scroll_y.wrapping_add(self.scanline) as usize / 8;
+    return map + row * 32 + column;
+  }
+
+  fn fetch_tile_address(&self) -> usize {
+    let tile: u8 = self.fifo.tile;
+    if self.control & LCDC_TILE_DATA != 0 {
+      return tile as usize * 16;
+    }
+    return (0x1000 + (tile as i8 as i16) * 16) as usize;
+  }
+
+  fn fetch_row(&self) -> u8 {
+    if self.fifo.in_window {
+      return self.window_line % 8;
+    }
+    return self.scroll_y.wrapping_add(self.scanline) % 8;
+  }
+
+  // Mixes an object's pixels into the object FIFO. Pixels already there came
+  // from objects with priority, so only transparent slots are filled.
+  fn fetch_object(&mut self, entry: usize) {
+    let height: u8 = if self.control & LCDC_OBJ_TALL != 0 { 16 } else { 8 };
+    let flags: u8 = self.oam[entry + 3];
+    let mut tile: u8 = self.oam[entry + 2];
+    if height == 16 {
+      tile &= 0xfe;
+    }
+    let mut row: u8 = self.scanline.wrapping_add(16).wrapping_sub(self.oam[entry]);
+    if flags & OBJ_FLIP_Y != 0 {
+      row = height - 1 - row;
+    }
+    // Columns already left of the output position are clipped.
+    let skip: u8 = self.fifo.lcd_x + 8 - self.oam[entry + 1];
+    let shown: bool = self.layer_toggles.shows_object(entry);
+    for column in skip..8 {
+      let tile_x: u8 = if flags & OBJ_FLIP_X != 0 { 7 - column } else { column };
+      let pixel: ObjPixel = ObjPixel {
+        color: if shown { self.tile_pixel(tile as usize * 16, tile_x, row) } else { 0 },
+        obp1: flags & OBJ_PALETTE != 0,
+        behind_bg: flags & OBJ_BEHIND_BG != 0,
+      };
+      let slot: usize = (column - skip) as usize;
+      if slot < self.fifo.obj.len() {
+        if self.fifo.obj[slot].color == 0 {
+          self.fifo.obj[slot] = pixel;
+        }
+      } else {
+        self.fifo.obj.push_back(pixel);
+      }
+    }
+  }
 
-    let a: u8 = if ((*memory).video_ram[addr as usize] & bit_index) != 0 {
-      1
+  // Palettes and the enable bits are applied as each pixel leaves the FIFO.
+  fn output_pixel(&mut self, bg_color: u8, obj: ObjPixel) {
+    let bg_shown: bool = if self.fifo.in_window {
+      self.layer_toggles.window
     } else {
-      0
+      self.layer_toggles.background
     };
-
-    let b: u8 = if ((*memory).video_ram[(addr as usize) + 1] & bit_index) != 0 {
-      2
+    let bg_color: u8 = if self.control & LCDC_BG_ENABLE != 0 && bg_shown { bg_color } else { 0 };
+    let obj_visible: bool = obj.color != 0
+      && self.control & LCDC_OBJ_ENABLE != 0
+      && !(obj.behind_bg && bg_color != 0);
+    let (mut pixel, mut layer): (u8, u8) = if !obj_visible {
+      (shade(self.bg_palette, bg_color), LAYER_BG)
+    } else if obj.obp1 {
+      (shade(self.obj_palette1, obj.color), LAYER_OBJ1)
     } else {
-      0
+      (shade(self.obj_palette0, obj.color), LAYER_OBJ0)
+    };
+    if self.blank_frame {
+      pixel = 0;
+      layer = LAYER_BG;
+    }
+    let index: usize = self.scanline as usize * SCREEN_WIDTH + self.fifo.lcd_x as usize;
+    self.framebuffer[index] = pixel;
+    self.layers[index] = layer;
+  }
+}
+
+// Maps a colour number through a BGP/OBP palette register.
+pub(crate) fn shade(palette: u8, color: u8) -> u8 {
+  return (palette >> (color * 2)) & 0x03;
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::game::interrupts::INTERRUPTS_VBLANK;
+
+  fn gpu_with_tile(tile: usize, rows: [(u8, u8); 8]) -> Gpu {
+    let mut gpu: Gpu = Default::default();
+    for (row, &(low, high)) in rows.iter().enumerate() {
+      gpu.write_vram((tile * 16 + row * 2) as u16, low);
+      gpu.write_vram((tile * 16 + row * 2 + 1) as u16, high);
+    }
+    return gpu;
+  }
+
+  fn render_line(gpu: &mut Gpu, line: u8) -> Vec<u8> {
+    gpu.scanline = line;
+    gpu.render_scanline();
+    let start: usize = line as usize * SCREEN_WIDTH;
+    return gpu.framebuffer[start..start + SCREEN_WIDTH].to_vec();
+  }
+
+  // Steps the GPU one M-cycle at a time until LY reaches `line`, returning
+  // how many LCD STAT interrupts were requested on the way.
+  fn run_to_line(gpu: &mut Gpu, interrupts: &mut Interrupts, line: u8) -> u32 {
+    let mut requests: u32 = 0;
+    while gpu.scanline != line {
+      gpu.step(4, interrupts);
+      if interrupts.flags & INTERRUPTS_LCDSTAT != 0 {
+        interrupts.flags &= !INTERRUPTS_LCDSTAT;
+        requests += 1;
+      }
+    }
+    return requests;
+  }
+
+  #[test]
+  fn stat_reports_mode_and_coincidence() {
+    let mut gpu: Gpu = Default::default();
+    let mut interrupts: Interrupts = Default::default();
+    gpu.write_scanline_compare(2, &mut interrupts);
+    run_to_line(&mut gpu, &mut interrupts, 1);
+
+    let mut modes: Vec<u8> = Vec::new();
+    while gpu.scanline == 1 {
+      let mode: u8 = gpu.read_stat() & 0x03;
+      if modes.last() != Some(&mode) {
+        modes.push(mode);
+      }
+      gpu.step(4, &mut interrupts);
+    }
+    assert_eq!(modes, vec![2, 3, 0]);
+    assert_eq!(gpu.read_stat() & STAT_COINCIDENCE, STAT_COINCIDENCE);
+    assert_eq!(interrupts.flags & INTERRUPTS_LCDSTAT, 0, "source not enabled");
+  }
+
+  #[test]
+  fn coincidence_interrupt_fires_once_per_match() {
+    let mut gpu: Gpu = Default::default();
+    let mut interrupts: Interrupts = Default::default();
+    gpu.write_scanline_compare(10, &mut interrupts);
+    gpu.write_stat(STAT_COINCIDENCE_SOURCE, &mut interrupts);
+
+    assert_eq!(run_to_line(&mut gpu, &mut interrupts, 9), 0);
+    assert_eq!(run_to_line(&mut gpu, &mut interrupts, 10), 1);
+    assert_eq!(run_to_line(&mut gpu, &mut interrupts, 11), 0);
+  }
+
+  #[test]
+  fn overlapping_sources_block_each_other() {
+    let mut gpu: Gpu = Default::default();
+    let mut interrupts: Interrupts = Default::default();
+    gpu.write_scanline_compare(10, &mut interrupts);
+    gpu.write_stat(STAT_HBLANK_SOURCE | STAT_OAM_SOURCE, &mut interrupts);
+    run_to_line(&mut gpu, &mut interrupts, 10);
+
+    // HBlank and OAM each raise the line once per scanline, and they touch
+    // at the line boundary, so HBlank -> OAM is a single long high period.
+    assert_eq!(run_to_line(&mut gpu, &mut interrupts, 12), 2);
+
+    // With LY == LYC holding the line high for all of line 12, its OAM and
+    // HBlank periods add nothing new.
+    gpu.write_scanline_compare(12, &mut interrupts);
+    gpu.write_stat(
+      STAT_HBLANK_SOURCE | STAT_OAM_SOURCE | STAT_COINCIDENCE_SOURCE,
+      &mut interrupts,
+    );
+    assert_eq!(run_to_line(&mut gpu, &mut interrupts, 13), 0);
+  }
+
+  // Steps one dot at a time until the GPU enters `mode`, returning the dot
+  // within the line it happened on.
+  fn run_to_mode(gpu: &mut Gpu, interrupts: &mut Interrupts, mode: u8) -> u16 {
+    while gpu.read_stat() & 0x03 != mode {
+      gpu.step(1, interrupts);
+    }
+    return gpu.dot;
+  }
+
+  #[test]
+  fn frame_is_154_lines_of_456_dots() {
+    let mut gpu: Gpu = Default::default();
+    let mut interrupts: Interrupts = Default::default();
+    run_to_line(&mut gpu, &mut interrupts, 144);
+    assert_eq!(gpu.frames, 1);
+    assert_eq!(gpu.read_stat() & 0x03, 1);
+    assert_ne!(interrupts.flags & INTERRUPTS_VBLANK, 0);
+
+    let mut dots: u32 = 0;
+    while gpu.frames == 1 {
+      gpu.step(1, &mut interrupts);
+      dots += 1;
+    }
+    assert_eq!(dots, 154 * 456);
+  }
+
+  #[test]
+  fn line_153_reads_as_0_after_one_m_cycle() {
+    let mut gpu: Gpu = Default::default();
+    let mut interrupts: Interrupts = Default::default();
+    run_to_line(&mut gpu, &mut interrupts, 153);
+    gpu.step(4, &mut interrupts);
+    assert_eq!(gpu.scanline, 0);
+    assert_eq!(gpu.read_stat() & 0x03, 1, "still in VBlank");
+    while gpu.line == 153 {
+      gpu.step(4, &mut interrupts);
+    }
+    assert_eq!((gpu.scanline, gpu.read_stat() & 0x03), (0, 2));
+  }
+
+  #[test]
+  fn fine_scroll_lengthens_mode_3() {
+    let mut gpu: Gpu = Default::default();
+    let mut interrupts: Interrupts = Default::default();
+    run_to_line(&mut gpu, &mut interrupts, 1);
+    assert_eq!(run_to_mode(&mut gpu, &mut interrupts, 3), 80);
+    assert_eq!(run_to_mode(&mut gpu, &mut interrupts, 0), 80 + 172);
+
+    gpu.scroll_x = 5;
+    run_to_line(&mut gpu, &mut interrupts, 2);
+    assert_eq!(run_to_mode(&mut gpu, &mut interrupts, 0), 80 + 172 + 5);
+  }
+
+  #[test]
+  fn objects_and_window_lengthen_mode_3() {
+    let mut gpu: Gpu = Default::default();
+    let mut interrupts: Interrupts = Default::default();
+    gpu.control |= LCDC_OBJ_ENABLE | LCDC_WINDOW_ENABLE;
+    gpu.window_x = 7;
+    // One object aligned to a tile (11 dots) and one offset by 5 (6 dots).
+    gpu.oam[0..4].copy_from_slice(&[16, 8, 0, 0]);
+    gpu.oam[4..8].copy_from_slice(&[16, 13, 0, 0]);
+    run_to_line(&mut gpu, &mut interrupts, 1);
+    assert_eq!(run_to_mode(&mut gpu, &mut interrupts, 0), 80 + 172 + 6 + 11 + 6);
+  }
+
+  #[test]
+  fn lcd_off_resets_ly_and_blanks_the_first_frame_back_on() {
+    let mut gpu: Gpu = Default::default();
+    let mut interrupts: Interrupts = Default::default();
+    gpu.framebuffer[0] = 3;
+    run_to_line(&mut gpu, &mut interrupts, 50);
+
+    gpu.write_control(gpu.control & !LCDC_DISPLAY_ENABLE, &mut interrupts);
+    gpu.step(456 * 4, &mut interrupts);
+    assert_eq!((gpu.scanline, gpu.read_stat() & 0x03, gpu.dot), (0, 0, 0));
+    assert_eq!(gpu.framebuffer[0], 0);
+
+    // Back on: line 0 has no OAM scan and the frame is not displayed.
+    gpu.bg_palette = 0xff;
+    gpu.write_control(gpu.control | LCDC_DISPLAY_ENABLE, &mut interrupts);
+    assert_eq!(run_to_mode(&mut gpu, &mut interrupts, 3), 80);
+    run_to_line(&mut gpu, &mut interrupts, 144);
+    assert_eq!(gpu.framebuffer[0], 0);
+
+    run_to_line(&mut gpu, &mut interrupts, 1);
+    assert_eq!(gpu.framebuffer[0], 3);
+  }
+
+  // A busy scene: noisy tiles, scrolled background, window, and objects that
+  // overlap, hang off the left edge, flip and use 8x16 mode.
+  fn busy_scene(renderer: Renderer) -> Gpu {
+    let mut gpu: Gpu = Default::default();
+    gpu.set_renderer(renderer);
+    let mut seed: u32 = 1;
+    for offset in 0..0x2000 {
+      seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+      gpu.write_vram(offset, (seed >> 16) as u8);
+    }
+    gpu.control = LCDC_DISPLAY_ENABLE
+      | LCDC_BG_ENABLE
+      | LCDC_OBJ_ENABLE
+      | LCDC_WINDOW_ENABLE
+      | LCDC_WINDOW_MAP
+      | LCDC_OBJ_TALL;
+    gpu.scroll_x = 13;
+    gpu.scroll_y = 7;
+    gpu.window_x = 90;
+    gpu.window_y = 60;
+    gpu.bg_palette = 0b00_01_10_11;
+    gpu.obj_palette0 = 0b11_10_01_00;
+    gpu.obj_palette1 = 0b01_11_10_00;
+    for index in 0..40 {
+      let entry: usize = index * 4;
+      gpu.oam[entry] = (16 + (index * 29) % 150) as u8;
+      gpu.oam[entry + 1] = ((index * 37) % 170) as u8;
+      gpu.oam[entry + 2] = (index * 3) as u8;
+      gpu.oam[entry + 3] = ((index * 0x30) & 0xf0) as u8;
+    }
+    return gpu;
+  }
+
+  fn render_frame(gpu: &mut Gpu) {
+    let mut interrupts: Interrupts = Default::default();
+    while gpu.frames == 0 {
+      gpu.step(4, &mut interrupts);
+    }
+  }
+
+  #[test]
+  fn pixel_fifo_matches_scanline_renderer() {
+    let mut scanline: Gpu = busy_scene(Renderer::Scanline);
+    let mut fifo: Gpu = busy_scene(Renderer::PixelFifo);
+    render_frame(&mut scanline);
+    render_frame(&mut fifo);
+
+    for y in 0..SCREEN_HEIGHT {
+      let row = y * SCREEN_WIDTH..(y + 1) * SCREEN_WIDTH;
+      assert_eq!(fifo.framebuffer[row.clone()], scanline.framebuffer[row.clone()], "line {}", y);
+      assert_eq!(fifo.layers[row.clone()], scanline.layers[row], "line {}", y);
+    }
+  }
+
+  #[test]
+  fn hidden_layers_match_across_renderers() {
+    let mut toggles: LayerToggles = LayerToggles {
+      window: false,
+      ..Default::default()
     };
+    toggles.object[3] = false;
+    toggles.object[17] = false;
+    let mut frames: Vec<Gpu> = Vec::new();
+    for &renderer in [Renderer::Scanline, Renderer::PixelFifo].iter() {
+      let mut gpu: Gpu = busy_scene(renderer);
+      gpu.layer_toggles = toggles;
+      render_frame(&mut gpu);
+      frames.push(gpu);
+    }
+    assert_eq!(frames[0].framebuffer[..], frames[1].framebuffer[..]);
+    assert_eq!(frames[0].layers[..], frames[1].layers[..]);
+
+    let mut shown: Gpu = busy_scene(Renderer::Scanline);
+    render_frame(&mut shown);
+    assert_ne!(shown.framebuffer[..], frames[0].framebuffer[..]);
+    // The window still counts its lines while hidden.
+    assert_eq!(shown.window_line, frames[0].window_line);
+  }
+
+  #[test]
+  fn hidden_background_shows_colour_0_and_objects() {
+    let mut gpu: Gpu = gpu_with_tile(0, [(0xff, 0xff); 8]);
+    gpu.bg_palette = 0b11_10_01_00;
+    gpu.obj_palette0 = 0b11_10_01_00;
+    gpu.control |= LCDC_OBJ_ENABLE;
+    gpu.oam[0..4].copy_from_slice(&[16, 8, 0, OBJ_BEHIND_BG]);
+    assert_eq!(render_line(&mut gpu, 0)[0..9], [3; 9]);
+    assert_eq!(gpu.layers[0], LAYER_BG);
+
+    gpu.layer_toggles.background = false;
+    let line: Vec<u8> = render_line(&mut gpu, 0);
+    assert_eq!(line[0..9], [3, 3, 3, 3, 3, 3, 3, 3, 0]);
+    assert_eq!(gpu.layers[0], LAYER_OBJ0);
+
+    gpu.layer_toggles.objects = false;
+    assert_eq!(render_line(&mut gpu, 0)[0..9], [0; 9]);
+  }
+
+  #[test]
+  fn pixel_fifo_mode_3_matches_timing_model() {
+    for &renderer in [Renderer::Scanline, Renderer::PixelFifo].iter() {
+      let mut gpu: Gpu = busy_scene(renderer);
+      let mut interrupts: Interrupts = Default::default();
+      let mut lengths: Vec<u16> = Vec::new();
+      for line in 1..SCREEN_HEIGHT as u8 {
+        run_to_line(&mut gpu, &mut interrupts, line);
+        run_to_mode(&mut gpu, &mut interrupts, 3);
+        lengths.push(run_to_mode(&mut gpu, &mut interrupts, 0) - OAM_SCAN_DOTS);
+      }
+      let mut reference: Gpu = busy_scene(Renderer::Scanline);
+      let mut expected: Vec<u16> = Vec::new();
+      for line in 1..SCREEN_HEIGHT as u8 {
+        reference.scanline = line;
+        expected.push(reference.transfer_length());
+      }
+      assert_eq!(lengths, expected, "{:?}", renderer);
+    }
+  }
+
+  #[test]
+  fn pixel_fifo_shows_mid_line_palette_changes() {
+    let mut gpu: Gpu = gpu_with_tile(0, [(0xff, 0xff); 8]);
+    gpu.set_renderer(Renderer::PixelFifo);
+    gpu.bg_palette = 0x00;
+    let mut interrupts: Interrupts = Default::default();
+    run_to_line(&mut gpu, &mut interrupts, 1);
+    run_to_mode(&mut gpu, &mut interrupts, 3);
+    while gpu.fifo.lcd_x < 80 {
+      gpu.step(1, &mut interrupts);
+    }
+    gpu.bg_palette = 0xff;
+    run_to_mode(&mut gpu, &mut interrupts, 0);
+
+    let line: &[u8] = &gpu.framebuffer[SCREEN_WIDTH..2 * SCREEN_WIDTH];
+    assert_eq!((line[79], line[80]), (0, 3));
+  }
+
+  #[test]
+  fn vram_writes_update_the_tile_cache() {
+    let mut gpu: Gpu = Default::default();
+    gpu.write_vram(0x8000 + 5 * 16 + 2, 0b1010_0000);
+    assert_eq!(gpu.tiles[5][1][..4], [1, 0, 1, 0]);
+    gpu.write_vram(0x8000 + 5 * 16 + 3, 0b1100_0000);
+    assert_eq!(gpu.tiles[5][1][..4], [3, 2, 1, 0]);
+    let dirty: [bool; TILE_COUNT] = gpu.take_dirty_tiles();
+    assert!(dirty[5]);
+    assert!(!dirty[4]);
+    assert!(!gpu.take_dirty_tiles()[5]);
+    assert_eq!(gpu.read_vram(0x8000 + 5 * 16 + 3), 0b1100_0000);
+
+    assert_eq!(gpu.take_dirty_maps(), [false, false]);
+    gpu.write_vram(0x9c10, 1);
+    assert_eq!(gpu.take_dirty_maps(), [false, true]);
+    assert_eq!(gpu.take_dirty_maps(), [false, false]);
+  }
+
+  #[test]
+  fn background_uses_scroll_and_palette() {
+    // Tile 1: leftmost pixel colour 3, the rest colour 0.
+    let mut gpu: Gpu = gpu_with_tile(1, [(0x80, 0x80); 8]);
+    gpu.control = LCDC_DISPLAY_ENABLE | LCDC_TILE_DATA | LCDC_BG_ENABLE;
+    gpu.write_vram(0x1800 + 1, 1);
+    gpu.bg_palette = 0b01_00_00_00;
+
+    assert_eq!(render_line(&mut gpu, 0)[8..10], [1, 0]);
+    gpu.scroll_x = 3;
+    assert_eq!(render_line(&mut gpu, 0)[5..7], [1, 0]);
+  }
+
+  #[test]
+  fn signed_tile_data_is_relative_to_0x9000() {
+    let mut gpu: Gpu = gpu_with_tile(0x100 - 1, [(0xff, 0x00); 8]);
+    gpu.control = LCDC_DISPLAY_ENABLE | LCDC_BG_ENABLE;
+    gpu.write_vram(0x1800, 0xff);
+    gpu.bg_palette = 0b11_10_01_00;
+
+    assert_eq!(render_line(&mut gpu, 0)[0..9], [1, 1, 1, 1, 1, 1, 1, 1, 0]);
+  }
+
+  #[test]
+  fn window_line_counter_only_advances_when_drawn() {
+    let mut gpu: Gpu = gpu_with_tile(1, [(0xff, 0xff); 8]);
+    gpu.control = LCDC_DISPLAY_ENABLE | LCDC_TILE_DATA | LCDC_BG_ENABLE | LCDC_WINDOW_ENABLE;
+    gpu.write_vram(0x1800, 1);
+    gpu.window_y = 2;
+    gpu.window_x = 7 + 100;
+
+    assert_eq!(render_line(&mut gpu, 0)[100], 0);
+    assert_eq!(gpu.window_line, 0);
+    assert_eq!(render_line(&mut gpu, 2)[100], 3);
+    assert_eq!(gpu.window_line, 1);
+  }
+
+  #[test]
+  fn objects_honour_flips_and_palettes() {
+    // Tile 2: rightmost pixel colour 1.
+    let mut gpu: Gpu = gpu_with_tile(2, [(0x01, 0x00); 8]);
+    gpu.control = LCDC_DISPLAY_ENABLE | LCDC_TILE_DATA | LCDC_OBJ_ENABLE;
+    gpu.obj_palette0 = 0b00_00_10_00;
+    gpu.obj_palette1 = 0b00_00_11_00;
+    gpu.oam[0..4].copy_from_slice(&[16, 8, 2, OBJ_PALETTE]);
+    gpu.oam[4..8].copy_from_slice(&[16, 20, 2, OBJ_FLIP_X]);
+
+    let line: Vec<u8> = render_line(&mut gpu, 0);
+    assert_eq!(line[7], 3);
+    assert_eq!(line[12], 2);
+    assert_eq!(line[19], 0);
+    assert_eq!(gpu.layers[7], LAYER_OBJ1);
+    assert_eq!(gpu.layers[12], LAYER_OBJ0);
+    assert_eq!(gpu.layers[19], LAYER_BG);
+  }
+
+  #[test]
+  fn overlapping_objects_prefer_smaller_x_then_oam_order() {
+    let mut gpu: Gpu = gpu_with_tile(2, [(0x01, 0x00); 8]);
+    gpu.control = LCDC_DISPLAY_ENABLE | LCDC_TILE_DATA | LCDC_OBJ_ENABLE;
+    gpu.obj_palette0 = 0b00_00_10_00;
+    gpu.obj_palette1 = 0b00_00_11_00;
+
+    // Both draw their pixel at x = 8; the second entry has the smaller X.
+    gpu.oam[0..4].copy_from_slice(&[16, 16, 2, OBJ_FLIP_X | OBJ_PALETTE]);
+    gpu.oam[4..8].copy_from_slice(&[16, 9, 2, 0]);
+    assert_eq!(render_line(&mut gpu, 0)[8], 2);
+
+    // Same X: the earlier entry wins.
+    gpu.oam[0..4].copy_from_slice(&[16, 9, 2, OBJ_PALETTE]);
+    assert_eq!(render_line(&mut gpu, 0)[8], 3);
+  }
+
+  #[test]
+  fn at_most_ten_objects_per_line() {
+    let mut gpu: Gpu = gpu_with_tile(0, [(0x80, 0x00); 8]);
+    gpu.control = LCDC_DISPLAY_ENABLE | LCDC_TILE_DATA | LCDC_OBJ_ENABLE;
+    gpu.obj_palette0 = 0b00_00_11_00;
+    for index in 0..11 {
+      gpu.oam[index * 4..index * 4 + 4].copy_from_slice(&[16, 8 + index as u8 * 8, 0, 0]);
+    }
+
+    let line: Vec<u8> = render_line(&mut gpu, 0);
+    assert_eq!(line[72], 3);
+    assert_eq!(line[80], 0);
+  }
+
+  #[test]
+  fn objects_behind_bg_show_through_colour_zero() {
+    let mut gpu: Gpu = gpu_with_tile(1, [(0xf0, 0x00); 8]);
+    for row in 0..8 {
+      gpu.write_vram(row * 2, 0xff);
+    }
+    gpu.control = LCDC_DISPLAY_ENABLE | LCDC_TILE_DATA | LCDC_BG_ENABLE | LCDC_OBJ_ENABLE;
+    gpu.write_vram(0x1800, 1);
+    gpu.bg_palette = 0b11_10_01_00;
+    gpu.obj_palette0 = 0b11_11_11_00;
+    gpu.oam[0..4].copy_from_slice(&[16, 8, 0, OBJ_BEHIND_BG]);
 
-    TILES[tile as usize][y as usize][idx] = a + b;
+    let line: Vec<u8> = render_line(&mut gpu, 0);
+    assert_eq!(line[0..8], [1, 1, 1, 1, 3, 3, 3, 3]);
   }
 }
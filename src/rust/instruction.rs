@@ -0,0 +1,394 @@
+// Decodes SM83 opcodes into a typed `Instruction` by their bit fields.
+//
+// An opcode byte splits into x (bits 7-6), y (bits 5-3) and z (bits 2-0);
+// y further splits into p (bits 5-4) and q (bit 3). Register, register pair
+// and condition operands are encoded the same way across the whole table,
+// so the decoder only has to know which group an opcode belongs to.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum R8 {
+  B,
+  C,
+  D,
+  E,
+  H,
+  L,
+  // (HL): the byte in memory pointed to by HL.
+  HlIndirect,
+  A,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum R16 {
+  BC,
+  DE,
+  HL,
+  SP,
+}
+
+// Register pairs as encoded in PUSH and POP, where AF takes the place of SP.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum R16Stack {
+  BC,
+  DE,
+  HL,
+  AF,
+}
+
+// Register pairs as encoded in LD (rr), A and LD A, (rr).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum R16Memory {
+  BC,
+  DE,
+  // (HL+): HL is incremented after the access.
+  HlIncrement,
+  // (HL-): HL is decremented after the access.
+  HlDecrement,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Condition {
+  NZ,
+  Z,
+  NC,
+  C,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AluOp {
+  Add,
+  Adc,
+  Sub,
+  Sbc,
+  And,
+  Xor,
+  Or,
+  Cp,
+}
+
+// The rotate and shift group of the CB page.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShiftOp {
+  Rlc,
+  Rrc,
+  Rl,
+  Rr,
+  Sla,
+  Sra,
+  Swap,
+  Srl,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Instruction {
+  Nop,
+  Stop,
+  Halt,
+  Di,
+  Ei,
+
+  // LD r, r'
+  Ld(R8, R8),
+  // LD r, n
+  LdImmediate(R8, u8),
+  // LD rr, nn
+  LdR16Immediate(R16, u16),
+  // LD (rr), A
+  LdIndirectA(R16Memory),
+  // LD A, (rr)
+  LdAIndirect(R16Memory),
+  // LD (nn), SP
+  LdAddressSp(u16),
+  // LD (nn), A
+  LdAddressA(u16),
+  // LD A, (nn)
+  LdAAddress(u16),
+  // LDH (n), A
+  LdhAddressA(u8),
+  // LDH A, (n)
+  LdhAAddress(u8),
+  // LD (C), A
+  LdhCA,
+  // LD A, (C)
+  LdhAC,
+  // LD SP, HL
+  LdSpHl,
+  // LD HL, SP+e
+  LdHlSpOffset(i8),
+
+  Inc(R8),
+  Dec(R8),
+  IncR16(R16),
+  DecR16(R16),
+  // ADD HL, rr
+  AddHl(R16),
+  // ADD SP, e
+  AddSpOffset(i8),
+  // ADD/ADC/SUB/SBC/AND/XOR/OR/CP A, r
+  Alu(AluOp, R8),
+  // ADD/ADC/SUB/SBC/AND/XOR/OR/CP A, n
+  AluImmediate(AluOp, u8),
+
+  Rlca,
+  Rrca,
+  Rla,
+  Rra,
+  Daa,
+  Cpl,
+  Scf,
+  Ccf,
+
+  Jr(Option<Condition>, i8),
+  Jp(Option<Condition>, u16),
+  JpHl,
+  Call(Option<Condition>, u16),
+  Ret(Option<Condition>),
+  Reti,
+  // RST n: the target address, one of 0x00, 0x08, ..., 0x38.
+  Rst(u16),
+  Push(R16Stack),
+  Pop(R16Stack),
+
+  // CB page
+  Shift(ShiftOp, R8),
+  Bit(u8, R8),
+  Res(u8, R8),
+  Set(u8, R8),
+
+  // One of the eleven unused opcodes, which lock up the CPU.
+  Invalid(u8),
+}
+
+const R8_TABLE: [R8; 8] = [
+  R8::B,
+  R8::C,
+  R8::D,
+  R8::E,
+  R8::H,
+  R8::L,
+  R8::HlIndirect,
+  R8::A,
+];
+const R16_TABLE: [R16; 4] = [R16::BC, R16::DE, R16::HL, R16::SP];
+const R16_STACK_TABLE: [R16Stack; 4] = [R16Stack::BC, R16Stack::DE, R16Stack::HL, R16Stack::AF];
+const R16_MEMORY_TABLE: [R16Memory; 4] = [
+  R16Memory::BC,
+  R16Memory::DE,
+  R16Memory::HlIncrement,
+  R16Memory::HlDecrement,
+];
+const CONDITION_TABLE: [Condition; 4] = [Condition::NZ, Condition::Z, Condition::NC, Condition::C];
+const ALU_TABLE: [AluOp; 8] = [
+  AluOp::Add,
+  AluOp::Adc,
+  AluOp::Sub,
+  AluOp::Sbc,
+  AluOp::And,
+  AluOp::Xor,
+  AluOp::Or,
+  AluOp::Cp,
+];
+const SHIFT_TABLE: [ShiftOp; 8] = [
+  ShiftOp::Rlc,
+  ShiftOp::Rrc,
+  ShiftOp::Rl,
+  ShiftOp::Rr,
+  ShiftOp::Sla,
+  ShiftOp::Sra,
+  ShiftOp::Swap,
+  ShiftOp::Srl,
+];
+
+// Decodes `opcode`, calling `next` once for every following byte the
+// instruction needs (the CB opcode and any immediates), in order. The
+// executor passes a `next` that reads at PC as an M-cycle; the debugger and
+// disassembler pass one that peeks memory without side effects.
+pub fn decode<F: FnMut() -> u8>(opcode: u8, mut next: F) -> Instruction {
+  let x: u8 = opcode >> 6;
+  let y: u8 = (opcode >> 3) & 0x07;
+  let z: u8 = opcode & 0x07;
+  let p: usize = (y >> 1) as usize;
+  let q: u8 = y & 0x01;
+
+  use Instruction::*;
+  match x {
+    0 => match z {
+      0 => match y {
+        0 => Nop,
+        1 => LdAddressSp(next_short(&mut next)),
+        2 => {
+          next();
+          Stop
+        }
+        3 => Jr(None, next() as i8),
+        _ => Jr(Some(CONDITION_TABLE[(y - 4) as usize]), next() as i8),
+      },
+      1 => {
+        if q == 0 {
+          LdR16Immediate(R16_TABLE[p], next_short(&mut next))
+        } else {
+          AddHl(R16_TABLE[p])
+        }
+      }
+      2 => {
+        if q == 0 {
+          LdIndirectA(R16_MEMORY_TABLE[p])
+        } else {
+          LdAIndirect(R16_MEMORY_TABLE[p])
+        }
+      }
+      3 => {
+        if q == 0 {
+          IncR16(R16_TABLE[p])
+        } else {
+          DecR16(R16_TABLE[p])
+        }
+      }
+      4 => Inc(R8_TABLE[y as usize]),
+      5 => Dec(R8_TABLE[y as usize]),
+      6 => LdImmediate(R8_TABLE[y as usize], next()),
+      _ => [Rlca, Rrca, Rla, Rra, Daa, Cpl, Scf, Ccf][y as usize],
+    },
+    1 => {
+      if opcode == 0x76 {
+        Halt
+      } else {
+        Ld(R8_TABLE[y as usize], R8_TABLE[z as usize])
+      }
+    }
+    2 => Alu(ALU_TABLE[y as usize], R8_TABLE[z as usize]),
+    _ => match z {
+      0 => match y {
+        0..=3 => Ret(Some(CONDITION_TABLE[y as usize])),
+        4 => LdhAddressA(next()),
+        5 => AddSpOffset(next() as i8),
+        6 => LdhAAddress(next()),
+        _ => LdHlSpOffset(next() as i8),
+      },
+      1 => {
+        if q == 0 {
+          Pop(R16_STACK_TABLE[p])
+        } else {
+          [Ret(None), Reti, JpHl, LdSpHl][p]
+        }
+      }
+      2 => match y {
+        0..=3 => Jp(Some(CONDITION_TABLE[y as usize]), next_short(&mut next)),
+        4 => LdhCA,
+        5 => LdAddressA(next_short(&mut next)),
+        6 => LdhAC,
+        _ => LdAAddress(next_short(&mut next)),
+      },
+      3 => match y {
+        0 => Jp(None, next_short(&mut next)),
+        1 => decode_cb(next()),
+        6 => Di,
+        7 => Ei,
+        _ => Invalid(opcode),
+      },
+      4 => {
+        if y <= 3 {
+          Call(Some(CONDITION_TABLE[y as usize]), next_short(&mut next))
+        } else {
+          Invalid(opcode)
+        }
+      }
+      5 => {
+        if q == 0 {
+          Push(R16_STACK_TABLE[p])
+        } else if p == 0 {
+          Call(None, next_short(&mut next))
+        } else {
+          Invalid(opcode)
+        }
+      }
+      6 => AluImmediate(ALU_TABLE[y as usize], next()),
+      _ => Rst((y as u16) * 8),
+    },
+  }
+}
+
+fn next_short<F: FnMut() -> u8>(next: &mut F) -> u16 {
+  let low: u16 = next() as u16;
+  let high: u16 = next() as u16;
+  return low | (high << 8);
+}
+
+fn decode_cb(opcode: u8) -> Instruction {
+  let y: u8 = (opcode >> 3) & 0x07;
+  let r: R8 = R8_TABLE[(opcode & 0x07) as usize];
+  match opcode >> 6 {
+    0 => Instruction::Shift(SHIFT_TABLE[y as usize], r),
+    1 => Instruction::Bit(y, r),
+    2 => Instruction::Res(y, r),
+    _ => Instruction::Set(y, r),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn decode_bytes(bytes: &[u8]) -> (Instruction, usize) {
+    let mut consumed: usize = 1;
+    let instruction: Instruction = decode(bytes[0], || {
+      consumed += 1;
+      return bytes[consumed - 1];
+    });
+    return (instruction, consumed);
+  }
+
+  #[test]
+  fn decodes_operands_from_bit_fields() {
+    assert_eq!(decode_bytes(&[0x41]), (Instruction::Ld(R8::B, R8::C), 1));
+    assert_eq!(decode_bytes(&[0x7e]), (Instruction::Ld(R8::A, R8::HlIndirect), 1));
+    assert_eq!(decode_bytes(&[0x76]), (Instruction::Halt, 1));
+    assert_eq!(decode_bytes(&[0x9e]), (Instruction::Alu(AluOp::Sbc, R8::HlIndirect), 1));
+    assert_eq!(decode_bytes(&[0x3a]), (Instruction::LdAIndirect(R16Memory::HlDecrement), 1));
+    assert_eq!(decode_bytes(&[0xf5]), (Instruction::Push(R16Stack::AF), 1));
+    assert_eq!(decode_bytes(&[0x20, 0xfe]), (Instruction::Jr(Some(Condition::NZ), -2), 2));
+    assert_eq!(decode_bytes(&[0xdc, 0x34, 0x12]), (Instruction::Call(Some(Condition::C), 0x1234), 3));
+    assert_eq!(decode_bytes(&[0x31, 0xfe, 0xff]), (Instruction::LdR16Immediate(R16::SP, 0xfffe), 3));
+    assert_eq!(decode_bytes(&[0xef]), (Instruction::Rst(0x28), 1));
+    assert_eq!(decode_bytes(&[0x10, 0x00]), (Instruction::Stop, 2));
+  }
+
+  #[test]
+  fn decodes_the_cb_page() {
+    assert_eq!(decode_bytes(&[0xcb, 0x37]), (Instruction::Shift(ShiftOp::Swap, R8::A), 2));
+    assert_eq!(decode_bytes(&[0xcb, 0x7e]), (Instruction::Bit(7, R8::HlIndirect), 2));
+    assert_eq!(decode_bytes(&[0xcb, 0x80]), (Instruction::Res(0, R8::B), 2));
+    assert_eq!(decode_bytes(&[0xcb, 0xfd]), (Instruction::Set(7, R8::L), 2));
+  }
+
+  #[test]
+  fn every_opcode_has_the_documented_length() {
+    const LENGTHS: [usize; 256] = [
+      1, 3, 1, 1, 1, 1, 2, 1, 3, 1, 1, 1, 1, 1, 2, 1, // 0x0_
+      2, 3, 1, 1, 1, 1, 2, 1, 2, 1, 1, 1, 1, 1, 2, 1, // 0x1_
+      2, 3, 1, 1, 1, 1, 2, 1, 2, 1, 1, 1, 1, 1, 2, 1, // 0x2_
+      2, 3, 1, 1, 1, 1, 2, 1, 2, 1, 1, 1, 1, 1, 2, 1, // 0x3_
+      1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, // 0x4_
+      1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, // 0x5_
+      1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, // 0x6_
+      1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, // 0x7_
+      1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, // 0x8_
+      1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, // 0x9_
+      1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, // 0xa_
+      1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, // 0xb_
+      1, 1, 3, 3, 3, 1, 2, 1, 1, 1, 3, 2, 3, 3, 2, 1, // 0xc_
+      1, 1, 3, 1, 3, 1, 2, 1, 1, 1, 3, 1, 3, 1, 2, 1, // 0xd_
+      2, 1, 1, 1, 1, 1, 2, 1, 2, 1, 3, 1, 1, 1, 2, 1, // 0xe_
+      2, 1, 1, 1, 1, 1, 2, 1, 2, 1, 3, 1, 1, 1, 2, 1, // 0xf_
+    ];
+    let mut invalid: usize = 0;
+    for opcode in 0..=255u8 {
+      let (instruction, length) = decode_bytes(&[opcode, 0x00, 0x00]);
+      assert_eq!(length, LENGTHS[opcode as usize], "opcode {:#04x}", opcode);
+      if let Instruction::Invalid(_) = instruction {
+        invalid += 1;
+      }
+    }
+    assert_eq!(invalid, 11);
+  }
+}
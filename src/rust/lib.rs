@@ -1,12 +1,37 @@
+// Explicit `return` is the house style.
+#![allow(clippy::needless_return)]
+
 extern crate web_sys;
 use wasm_bindgen::prelude::*;
 
-mod game;
+pub mod game;
 
-macro_rules! log {
-    ( $( $t:tt )* ) => {
-        web_sys::console::log_1(&format!( $( $t )* ).into());
-    }
+use game::disassembler::{format_line, Disassembler, Line};
+use game::gpu::Layer;
+use game::logging;
+use game::palette::Palette;
+use game::vram_viewer::{self, ObjectEntry, VramViews};
+use std::cell::{Cell, RefCell};
+
+// The game `load_cartridge` started and what the debugger views keep of it.
+struct Session {
+  game: game::Game,
+  views: VramViews,
+}
+
+thread_local! {
+  // The palette chosen by the frontend, applied to every game loaded.
+  static PALETTE: Cell<Palette> = Cell::new(Default::default());
+  static SESSION: RefCell<Option<Session>> = const { RefCell::new(None) };
+}
+
+fn with_session<T>(f: impl FnOnce(&mut Session) -> Result<T, String>) -> Result<T, JsValue> {
+  return SESSION
+    .with(|session| match session.borrow_mut().as_mut() {
+      Some(session) => f(session),
+      None => Err("no cartridge loaded".to_string()),
+    })
+    .map_err(JsValue::from);
 }
 
 // This is like the `main` function, except for JavaScript.
@@ -17,14 +42,41 @@ pub fn main_js() -> Result<(), JsValue> {
   #[cfg(debug_assertions)]
   console_error_panic_hook::set_once();
 
+  logging::init(Default::default()).map_err(JsValue::from)?;
   Ok(())
 }
 
+// Sets which diagnostics reach the console, e.g. `warn,cpu=debug,mbc=trace`.
+#[wasm_bindgen]
+pub fn set_log_filter(spec: &str) -> Result<(), JsValue> {
+  let filter: logging::Filter = logging::Filter::parse(spec).map_err(JsValue::from)?;
+  return logging::init(filter).map_err(JsValue::from);
+}
+
+// Selects a built-in palette: classic-green, pocket-grey, high-contrast or
+// color-green.
+#[wasm_bindgen]
+pub fn set_palette_preset(name: &str) -> Result<(), JsValue> {
+  let palette: Palette = Palette::preset(name).map_err(JsValue::from)?;
+  PALETTE.with(|selected| selected.set(palette));
+  return Ok(());
+}
+
+// Selects custom colours given as RGBA bytes, lightest shade first: 16 bytes
+// for every layer, or 48 for background, OBJ0 and OBJ1 in turn.
+#[wasm_bindgen]
+pub fn set_custom_palette(colors: &[u8]) -> Result<(), JsValue> {
+  let palette: Palette = Palette::from_rgba(colors).map_err(JsValue::from)?;
+  PALETTE.with(|selected| selected.set(palette));
+  return Ok(());
+}
+
 #[wasm_bindgen]
 pub fn load_cartridge(loaded: &[u8]) {
   match game::validate_cartridge(loaded) {
     Ok(cartridge) => {
       let mut game_instance = game::new_game(cartridge);
+      game_instance.set_palette(PALETTE.with(Cell::get));
       let mut i: u8 = 0;
       loop {
         if i == 100 {
@@ -34,7 +86,162 @@ pub fn load_cartridge(loaded: &[u8]) {
           i += 1;
         }
       }
+      let session: Session = Session {
+        game: game_instance,
+        views: Default::default(),
+      };
+      SESSION.with(|current| *current.borrow_mut() = Some(session));
+    }
+    Err(error) => {
+      log::error!(target: logging::CART, "invalid cartridge: {}", error);
     }
-    Err(_) => (log!("Error!")),
+  }
+}
+
+// Shows or hides a whole layer of the loaded game: background, window or
+// objects.
+#[wasm_bindgen]
+pub fn set_layer_enabled(layer: &str, enabled: bool) -> Result<(), JsValue> {
+  return with_session(|session| {
+    let layer: Layer = match layer {
+      "background" => Layer::Background,
+      "window" => Layer::Window,
+      "objects" => Layer::Objects,
+      _ => return Err(format!("unknown layer '{}'", layer)),
+    };
+    session.game.set_layer_enabled(layer, enabled);
+    return Ok(());
+  });
+}
+
+// Shows or hides the object in OAM entry `index` (0-39) of the loaded game.
+#[wasm_bindgen]
+pub fn set_object_enabled(index: usize, enabled: bool) -> Result<(), JsValue> {
+  return with_session(|session| {
+    session.game.set_object_enabled(index, enabled);
+    return Ok(());
+  });
+}
+
+// The 384 tiles of VRAM bank `bank` as RGBA, 16 to a row. Bank 1 is an
+// error until CGB VRAM is emulated.
+#[wasm_bindgen]
+pub fn vram_tile_data(bank: usize) -> Result<Vec<u8>, JsValue> {
+  return with_session(|session| {
+    session.views.update(&mut session.game.memory.gpu, &session.game.palette);
+    return match session.views.tile_data(bank) {
+      Some(out) => Ok(out.to_vec()),
+      None => Err(format!("VRAM bank {} isn't emulated", bank)),
+    };
+  });
+}
+
+// Tile map `map` (0 for 0x9800, 1 for 0x9c00) as 256x256 RGBA with the
+// screen's viewport outlined.
+#[wasm_bindgen]
+pub fn vram_tile_map(map: usize) -> Result<Vec<u8>, JsValue> {
+  return with_session(|session| {
+    if map > 1 {
+      return Err(format!("no tile map {}", map));
+    }
+    session.views.update(&mut session.game.memory.gpu, &session.game.palette);
+    return Ok(session.views.tile_map(&session.game.memory.gpu, map));
+  });
+}
+
+// All 40 objects as RGBA, each in an 8x16 cell, 8 to a row.
+#[wasm_bindgen]
+pub fn vram_oam() -> Result<Vec<u8>, JsValue> {
+  return with_session(|session| {
+    return Ok(vram_viewer::oam_rgba(&session.game.memory.gpu, &session.game.palette));
+  });
+}
+
+// OAM entry `index` (0-39) with its attributes decoded.
+#[wasm_bindgen]
+pub fn vram_oam_entry(index: usize) -> Result<ObjectEntry, JsValue> {
+  return with_session(|session| {
+    return vram_viewer::objects(&session.game.memory.gpu)
+      .get(index)
+      .copied()
+      .ok_or_else(|| format!("no OAM entry {}", index));
+  });
+}
+
+// `count` lines of disassembly from `address` as the loaded game sees
+// memory, one per line in the disassemble tool's format.
+#[wasm_bindgen]
+pub fn disassemble(address: u16, count: usize) -> Result<String, JsValue> {
+  return with_session(|session| {
+    let disassembler: Disassembler = Default::default();
+    let lines: Vec<Line> = disassembler.disassemble_memory(&session.game.memory, address, count);
+    let mut out: String = String::new();
+    for line in lines.iter() {
+      if let Some(label) = &line.label {
+        out.push_str(&format!("{}:\n", label));
+      }
+      out.push_str(&format_line(line));
+      out.push('\n');
+    }
+    return Ok(out);
+  });
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use game::palette::POCKET_GREY;
+
+  #[test]
+  fn selected_palette_is_kept_for_later_games() {
+    set_palette_preset("pocket-grey").unwrap();
+    assert_eq!(PALETTE.with(Cell::get), Palette::pocket_grey());
+    let bytes: Vec<u8> = POCKET_GREY.iter().rev().flatten().copied().collect();
+    set_custom_palette(&bytes).unwrap();
+    assert_eq!(PALETTE.with(Cell::get).bg[0], POCKET_GREY[3]);
+  }
+
+  #[test]
+  fn views_come_from_the_loaded_game() {
+    let mut rom: Vec<u8> = vec![0; 0x8000];
+    // JR -2
+    rom[0x100..0x102].copy_from_slice(&[0x18, 0xfe]);
+    load_cartridge(&rom);
+    SESSION.with(|session| {
+      let mut session = session.borrow_mut();
+      let gpu: &mut game::gpu::Gpu = &mut session.as_mut().unwrap().game.memory.gpu;
+      gpu.oam[4..8].copy_from_slice(&[16, 8, 5, 0x20]);
+    });
+    assert_eq!(vram_tile_data(0).unwrap().len(), 128 * 192 * 4);
+    assert_eq!(vram_tile_map(1).unwrap().len(), 256 * 256 * 4);
+    assert_eq!(vram_oam().unwrap().len(), 64 * 80 * 4);
+    let entry: ObjectEntry = vram_oam_entry(1).unwrap();
+    assert_eq!((entry.tile, entry.flip_x), (5, true));
+  }
+
+  #[test]
+  fn toggles_reach_the_loaded_game() {
+    load_cartridge(&vec![0; 0x8000]);
+    set_layer_enabled("window", false).unwrap();
+    set_object_enabled(7, false).unwrap();
+    SESSION.with(|session| {
+      let session = session.borrow();
+      let loaded: &game::Game = &session.as_ref().unwrap().game;
+      let toggles: game::gpu::LayerToggles = loaded.memory.gpu.layer_toggles;
+      assert!(!toggles.window && !toggles.object[7]);
+      assert!(toggles.background && toggles.objects && toggles.object[6]);
+    });
+  }
+
+  #[test]
+  fn disassembles_the_loaded_game() {
+    let mut rom: Vec<u8> = vec![0; 0x8000];
+    // LD A, $12; JR -2
+    rom[0x100..0x104].copy_from_slice(&[0x3e, 0x12, 0x18, 0xfe]);
+    load_cartridge(&rom);
+    let text: String = disassemble(0x100, 2).unwrap();
+    let lines: Vec<&str> = text.lines().collect();
+    assert_eq!(lines[0], format!("00:0100  {:<24} ld a, $12 ; 8", "3e 12"));
+    assert_eq!(lines[1], format!("00:0102  {:<24} jr $0102 ; 12", "18 fe"));
   }
 }
@@ -0,0 +1,177 @@
+// Diagnostics go through the `log` facade, using the categories below as
+// log targets:
+//
+//   log::trace!(target: logging::MBC, "ROM bank {}", bank);
+//
+// `init` installs a logger that writes to the browser console on wasm and to
+// stderr natively. Which messages are kept is set by a filter such as
+// `warn,cpu=debug,mbc=trace`: a default level, then levels per category.
+// Until `init` is called, and for any level no category wants, a log call is
+// one comparison and its arguments are never formatted.
+
+#[cfg(target_arch = "wasm32")]
+use log::Level;
+use log::{LevelFilter, Log, Metadata, Record};
+use std::sync::{OnceLock, RwLock};
+
+pub const CPU: &str = "cpu";
+pub const MEM: &str = "mem";
+pub const PPU: &str = "ppu";
+pub const APU: &str = "apu";
+pub const IRQ: &str = "irq";
+pub const MBC: &str = "mbc";
+// Loading and checking cartridges.
+pub const CART: &str = "cart";
+// The GDB remote stub: packets and connections.
+pub const GDB: &str = "gdb";
+
+pub const CATEGORIES: [&str; 8] = [CPU, MEM, PPU, APU, IRQ, MBC, CART, GDB];
+
+fn parse_level(text: &str) -> Result<LevelFilter, String> {
+  return text.parse::<LevelFilter>().map_err(|_| format!("unknown level '{}'", text));
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Filter {
+  pub default: LevelFilter,
+  pub categories: Vec<(&'static str, LevelFilter)>,
+}
+
+impl Default for Filter {
+  fn default() -> Filter {
+    return Filter {
+      default: LevelFilter::Warn,
+      categories: Vec::new(),
+    };
+  }
+}
+
+impl Filter {
+  // Parses `level` and `category=level` items separated by commas.
+  pub fn parse(spec: &str) -> Result<Filter, String> {
+    let mut filter: Filter = Default::default();
+    for item in spec.split(',').map(str::trim).filter(|item| !item.is_empty()) {
+      match item.split_once('=') {
+        Some((name, level)) => {
+          let category: &'static str = match CATEGORIES.iter().find(|c| **c == name.trim()) {
+            Some(category) => category,
+            None => return Err(format!("unknown category '{}'", name)),
+          };
+          filter.categories.retain(|(c, _)| *c != category);
+          filter.categories.push((category, parse_level(level.trim())?));
+        }
+        None => filter.default = parse_level(item)?,
+      }
+    }
+    return Ok(filter);
+  }
+
+  pub fn level(&self, target: &str) -> LevelFilter {
+    return self
+      .categories
+      .iter()
+      .find(|(category, _)| *category == target)
+      .map_or(self.default, |(_, level)| *level);
+  }
+
+  // The most verbose level any category allows, so the facade can drop
+  // everything quieter before reaching the logger.
+  pub fn max_level(&self) -> LevelFilter {
+    return self.categories.iter().map(|(_, level)| *level).fold(self.default, Ord::max);
+  }
+}
+
+struct Logger {
+  filter: RwLock<Filter>,
+}
+
+static LOGGER: Logger = Logger {
+  filter: RwLock::new(Filter {
+    default: LevelFilter::Warn,
+    categories: Vec::new(),
+  }),
+};
+// Whether installing the logger worked, decided once by the first `init`.
+static INSTALLED: OnceLock<Result<(), String>> = OnceLock::new();
+
+impl Log for Logger {
+  fn enabled(&self, metadata: &Metadata) -> bool {
+    let filter = self.filter.read().unwrap_or_else(|poisoned| poisoned.into_inner());
+    return metadata.level() <= filter.level(metadata.target());
+  }
+
+  fn log(&self, record: &Record) {
+    if !self.enabled(record.metadata()) {
+      return;
+    }
+    let line: String = format!("[{} {}] {}", record.level(), record.target(), record.args());
+    #[cfg(target_arch = "wasm32")]
+    {
+      let line: wasm_bindgen::JsValue = line.into();
+      match record.level() {
+        Level::Error => web_sys::console::error_1(&line),
+        Level::Warn => web_sys::console::warn_1(&line),
+        Level::Info => web_sys::console::info_1(&line),
+        Level::Debug | Level::Trace => web_sys::console::debug_1(&line),
+      }
+    }
+    #[cfg(not(target_arch = "wasm32"))]
+    eprintln!("{}", line);
+  }
+
+  fn flush(&self) {}
+}
+
+// Installs the logger on the first call and sets its filter. Later calls
+// just change the filter. Fails, on this and every later call, if the host
+// installed its own logger.
+pub fn init(filter: Filter) -> Result<(), String> {
+  let install = || log::set_logger(&LOGGER).map_err(|error| error.to_string());
+  return init_with(&INSTALLED, install, filter);
+}
+
+// `init` with the install step and the place its outcome is kept passed in,
+// so tests can make installing fail without touching the process's logger.
+fn init_with(
+  installed: &OnceLock<Result<(), String>>,
+  install: impl FnOnce() -> Result<(), String>,
+  filter: Filter,
+) -> Result<(), String> {
+  installed.get_or_init(install).clone()?;
+  log::set_max_level(filter.max_level());
+  *LOGGER.filter.write().unwrap_or_else(|poisoned| poisoned.into_inner()) = filter;
+  return Ok(());
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn parses_levels_per_category() {
+    let filter: Filter = Filter::parse("error, cpu=debug,mbc=trace,cpu=info").unwrap();
+    assert_eq!(filter.level(CPU), LevelFilter::Info);
+    assert_eq!(filter.level(MBC), LevelFilter::Trace);
+    assert_eq!(filter.level(PPU), LevelFilter::Error);
+    assert_eq!(Filter::parse("gdb=debug").unwrap().level(GDB), LevelFilter::Debug);
+    assert_eq!(filter.level("wasm"), LevelFilter::Error);
+    assert_eq!(filter.max_level(), LevelFilter::Trace);
+    assert_eq!(Filter::parse("").unwrap().max_level(), LevelFilter::Warn);
+  }
+
+  #[test]
+  fn rejects_unknown_levels_and_categories() {
+    assert_eq!(Filter::parse("gpu=info").unwrap_err(), "unknown category 'gpu'");
+    assert_eq!(Filter::parse("cpu=loud").unwrap_err(), "unknown level 'loud'");
+  }
+
+  #[test]
+  fn keeps_failing_when_the_host_has_a_logger() {
+    let installed: OnceLock<Result<(), String>> = OnceLock::new();
+    let host_has_one = || Err("logger already set".to_string());
+    assert!(init_with(&installed, host_has_one, Default::default()).is_err());
+    // A later call doesn't try again, and reports the same failure.
+    let result: Result<(), String> = init_with(&installed, || Ok(()), Default::default());
+    assert_eq!(result.unwrap_err(), "logger already set");
+  }
+}
@@ -1,98 +1,351 @@
-use crate::game::gpu::GPU;
-use crate::game::interrupts::INTERRUPTS;
-use crate::game::registers::Registers;
+use crate::game::bus::Bus;
+use crate::game::cartridge::Cartridge;
+use crate::game::gpu::Gpu;
+use crate::game::interrupts::{Interrupts, INTERRUPTS_SERIAL};
+use crate::game::logging;
+use crate::game::sgb::Sgb;
+use crate::game::timer::Timer;
+
+// Bits that always read back as 1 for each register at 0xff00-0xff7f on a
+// DMG. Unused bits, write-only registers and unmapped addresses are set.
+#[rustfmt::skip]
+const IO_READ_MASKS: [u8; 0x80] = [
+  // P1    SB    SC          DIV   TIMA  TMA   TAC                                           IF
+  0xc0, 0x00, 0x7e, 0xff, 0x00, 0x00, 0x00, 0xf8, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xe0,
+  // NR10  NR11  NR12  NR13  NR14        NR21  NR22  NR23  NR24  NR30  NR31  NR32  NR33  NR34
+  0x80, 0x3f, 0x00, 0xff, 0xbf, 0xff, 0x3f, 0x00, 0xff, 0xbf, 0x7f, 0xff, 0x9f, 0xff, 0xbf, 0xff,
+  // NR41  NR42  NR43  NR44  NR50  NR51  NR52
+  0xff, 0x00, 0x00, 0xbf, 0x00, 0x00, 0x70, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+  // Wave RAM
+  0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+  // LCDC  STAT  SCY   SCX   LY    LYC   DMA   BGP   OBP0  OBP1  WY    WX
+  0x00, 0x80, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xff, 0xff, 0xff, 0xff,
+  0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+  0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+  0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+];
 
 pub struct Memory {
-  // the game being played
-  pub cartridge: [u8; 0x8000],
-  pub video_ram: [u8; 0x2000],
+  // the game being played, including its switchable RAM at A000-BFFF
+  pub cartridge: Cartridge,
   pub io: [u8; 0x100],
-  // Addresses A000-BF00
-  pub switchable_ram: [u8; 0x2000],
   // Addresses E000-FE00 & C000-DE00
   pub write_ram: [u8; 0x2000],
   pub hardware_ram: [u8; 0x80],
-  pub oam: [u8; 0x100],
-}
 
-macro_rules! log {
-    ( $( $t:tt )* ) => {
-        web_sys::console::log_1(&format!( $( $t )* ).into());
-    }
+  pub gpu: Gpu,
+  pub interrupts: Interrupts,
+  pub timer: Timer,
+  // Present when running as a Super Game Boy, which listens to P1 writes.
+  pub sgb: Option<Sgb>,
+  // Total T-cycles elapsed since power on.
+  pub ticks: u64,
+  // Every byte sent over the serial port. Nothing is connected to the other
+  // end, so transfers complete immediately and shift in 0xff.
+  pub serial_output: String,
+  // When set, VRAM and OAM are locked while the PPU is using them, as on
+  // hardware. Debugging tools can clear it to see memory at any time.
+  pub access_restrictions: bool,
+
+  // OAM DMA in progress: source address and number of bytes copied so far.
+  dma_source: Option<u16>,
+  dma_index: u16,
+  // DMA starts one M-cycle after the write to 0xff46.
+  dma_delay: bool,
 }
 
 impl Memory {
+  pub fn new(cartridge: Cartridge) -> Memory {
+    return Memory {
+      cartridge,
+      io: [0; 0x100],
+      write_ram: [0; 0x2000],
+      hardware_ram: [0; 0x80],
+
+      gpu: Default::default(),
+      interrupts: Default::default(),
+      timer: Default::default(),
+      sgb: None,
+      ticks: 0,
+      serial_output: String::new(),
+      access_restrictions: true,
+
+      dma_source: None,
+      dma_index: 0,
+      dma_delay: false,
+    };
+  }
+
   pub fn read_byte(&self, address: u16) -> u8 {
+    if self.locked_by_ppu(address) {
+      return 0xff;
+    }
+    return self.peek_byte(address);
+  }
+
+  // Reads like `read_byte` but ignores the PPU's VRAM and OAM locks, for
+  // debuggers.
+  pub fn peek_byte(&self, address: u16) -> u8 {
     let address_as_usize: usize = address as usize;
     match address {
-      0..=0x7fff => self.cartridge[address_as_usize],
-      0x8000..=0x9fff => self.video_ram[address_as_usize - 0x8000],
-      0xa000..=0xbfff => self.switchable_ram[address_as_usize - 0xa000],
+      0..=0x7fff => self.cartridge.read_rom(address),
+      0x8000..=0x9fff => self.gpu.read_vram(address),
+      0xa000..=0xbfff => self.cartridge.read_ram(address),
       0xc000..=0xdfff => self.write_ram[address_as_usize - 0xc000],
       0xe000..=0xfdff => self.write_ram[address_as_usize - 0xe000],
-      0xfe00..=0xfeff => self.oam[address_as_usize - 0xfe00],
-      0xff40 => GPU.control,
-      0xff42 => GPU.scroll_y,
-      0xff43 => GPU.scroll_x,
-      0xff44 => GPU.scanline,
-      0xff0f => INTERRUPTS.flags,
-      0xffff => INTERRUPTS.enable,
-      0xff00..=0xff7f => self.io[address_as_usize - 0xff00],
-      0xff80..=0xfffe => self.hardware_ram[address_as_usize - 0xff00],
+      0xfe00..=0xfe9f => self.gpu.oam[address_as_usize - 0xfe00],
+      // Unusable on a DMG: reads return 0 and writes are ignored.
+      0xfea0..=0xfeff => 0,
+      0xff00..=0xff7f => self.read_io(address),
+      0xff80..=0xfffe => self.hardware_ram[address_as_usize - 0xff80],
+      0xffff => self.interrupts.enable,
     }
   }
 
+  fn read_io(&self, address: u16) -> u8 {
+    let index: usize = (address - 0xff00) as usize;
+    let value: u8 = match address {
+      // No buttons are wired up yet, so none ever read as pressed. With both
+      // lines deselected an SGB in multiplayer mode reports the joypad number.
+      0xff00 => match &self.sgb {
+        Some(sgb) if sgb.players > 1 && self.io[index] & 0x30 == 0x30 => {
+          self.io[index] | sgb.joypad_id()
+        }
+        _ => self.io[index] | 0x0f,
+      },
+      0xff04 => self.timer.read_divider(),
+      0xff05 => self.timer.counter,
+      0xff06 => self.timer.modulo,
+      0xff07 => self.timer.control,
+      0xff0f => self.interrupts.flags,
+      0xff40 => self.gpu.control,
+      0xff41 => self.gpu.read_stat(),
+      0xff42 => self.gpu.scroll_y,
+      0xff43 => self.gpu.scroll_x,
+      0xff44 => self.gpu.scanline,
+      0xff45 => self.gpu.scanline_compare,
+      0xff47 => self.gpu.bg_palette,
+      0xff48 => self.gpu.obj_palette0,
+      0xff49 => self.gpu.obj_palette1,
+      0xff4a => self.gpu.window_y,
+      0xff4b => self.gpu.window_x,
+      _ => self.io[index],
+    };
+    return value | IO_READ_MASKS[index];
+  }
+
   pub fn read_short(&self, address: u16) -> u16 {
     let a: u16 = self.read_byte(address + 1).into();
     let b: u16 = self.read_byte(address).into();
     return b | (a << 8);
   }
 
-  pub fn read_short_from_stack(&self, registers: &mut Registers) -> u16 {
-    let val: u16 = self.read_short(registers.sp);
-    registers.sp = registers.sp.wrapping_add(2);
-    return val;
-  }
-
   pub fn write_byte(&mut self, address: u16, val: u8) {
     let address_as_usize: usize = address as usize;
-    log!("Address: {:#x}", address);
+    log::trace!(target: logging::MEM, "write {:#06x} = {:#04x}", address, val);
+    if self.locked_by_ppu(address) {
+      return;
+    }
     match address {
-      0..=0x7fff => panic!(
-        "Attempting to write to location {}, which is read-only memory",
-        address_as_usize
-      ),
-      0x8000..=0x9fff => self.video_ram[address_as_usize - 0x8000] = val,
-      0xa000..=0xbfff => self.switchable_ram[address_as_usize - 0xa000] = val,
+      0..=0x7fff => self.cartridge.write_rom(address, val),
+      0x8000..=0x9fff => self.gpu.write_vram(address, val),
+      0xa000..=0xbfff => self.cartridge.write_ram(address, val),
       0xc000..=0xdfff => self.write_ram[address_as_usize - 0xc000] = val,
       0xe000..=0xfdff => self.write_ram[address_as_usize - 0xe000] = val,
 
-      0xff40 => GPU.control = val,
-      0xff42 => GPU.scroll_y = val,
-      0xff43 => GPU.scroll_x = val,
-      0xff46 => {
-        // Copy
-        let source_addr: u16 = (val as u16) << 8;
-        for idx in 0..160 {
-          self.write_byte(0xfe00 + idx, self.read_byte(source_addr + idx))
+      0xff00 => {
+        self.io[0x00] = val;
+        if let Some(sgb) = &mut self.sgb {
+          sgb.write_joypad(val, &self.gpu);
+        }
+      }
+      0xff02 => {
+        self.io[0x02] = val;
+        if val & 0x81 == 0x81 {
+          self.serial_output.push(self.io[0x01] as char);
+          self.io[0x01] = 0xff;
+          self.io[0x02] &= 0x7f;
+          self.interrupts.request(INTERRUPTS_SERIAL);
         }
       }
+      0xff04 => self.timer.write_divider(),
+      0xff05 => self.timer.write_counter(val),
+      0xff06 => self.timer.modulo = val,
+      0xff07 => self.timer.write_control(val),
+      0xff40 => self.gpu.write_control(val, &mut self.interrupts),
+      0xff41 => self.gpu.write_stat(val, &mut self.interrupts),
+      0xff42 => self.gpu.scroll_y = val,
+      0xff43 => self.gpu.scroll_x = val,
+      0xff45 => self.gpu.write_scanline_compare(val, &mut self.interrupts),
+      0xff47 => self.gpu.bg_palette = val,
+      0xff48 => self.gpu.obj_palette0 = val,
+      0xff49 => self.gpu.obj_palette1 = val,
+      0xff4a => self.gpu.window_y = val,
+      0xff4b => self.gpu.window_x = val,
+      0xff46 => {
+        // The copy itself happens one byte per M-cycle in `step_dma`.
+        self.io[0x46] = val;
+        self.dma_source = Some((val as u16) << 8);
+        self.dma_index = 0;
+        self.dma_delay = true;
+      }
       // 0xff44 => panic!("Attempting to write to memory address 0xff44, which is read-only memory"),
-      0xfe00..=0xfeff => self.oam[address_as_usize - 0xfe00] = val,
-      0xff0f => INTERRUPTS.flags = val,
-      0xffff => INTERRUPTS.enable = val,
-      0xff00..=0xff7f => self.io[address_as_usize - 0xff00] = val,
-      0xff80..=0xfffe => self.hardware_ram[address_as_usize - 0xff00] = val,
+      0xfe00..=0xfe9f => self.gpu.oam[address_as_usize - 0xfe00] = val,
+      0xfea0..=0xfeff => {}
+      0xff0f => self.interrupts.flags = val,
+      0xffff => self.interrupts.enable = val,
+      0xff10..=0xff3f => {
+        log::trace!(target: logging::APU, "{:#06x} = {:#04x}", address, val);
+        self.io[address_as_usize - 0xff00] = val;
+      }
+      0xff01..=0xff7f => self.io[address_as_usize - 0xff00] = val,
+      0xff80..=0xfffe => self.hardware_ram[address_as_usize - 0xff80] = val,
+    }
+  }
+
+  fn locked_by_ppu(&self, address: u16) -> bool {
+    if !self.access_restrictions {
+      return false;
+    }
+    return match address {
+      0x8000..=0x9fff => !self.gpu.vram_accessible(),
+      0xfe00..=0xfeff => !self.gpu.oam_accessible(),
+      _ => false,
+    };
+  }
+
+  fn step_dma(&mut self) {
+    if self.dma_delay {
+      self.dma_delay = false;
+      return;
+    }
+    if let Some(source) = self.dma_source {
+      let val: u8 = self.read_byte(source + self.dma_index);
+      self.gpu.oam[self.dma_index as usize] = val;
+      self.dma_index += 1;
+      if self.dma_index == 160 {
+        self.dma_source = None;
+      }
     }
   }
 
-  pub fn write_short(&mut self, address: u16, val: u16) {
-    self.write_byte(address, (val & 0x00ff) as u8);
-    self.write_byte(address + 1, ((val & 0xff00) >> 8) as u8);
+  fn dma_blocks(&self, address: u16) -> bool {
+    return self.dma_source.is_some() && !self.dma_delay && (0xfe00..=0xfe9f).contains(&address);
   }
+}
+
+impl Bus for Memory {
+  // While OAM DMA is running the CPU cannot see OAM.
+  fn read(&mut self, address: u16) -> u8 {
+    if self.dma_blocks(address) {
+      return 0xff;
+    }
+    return self.read_byte(address);
+  }
+
+  fn write(&mut self, address: u16, val: u8) {
+    if self.dma_blocks(address) {
+      return;
+    }
+    self.write_byte(address, val);
+  }
+
+  // Advances every component on the bus by `cycles` T-cycles.
+  fn tick(&mut self, cycles: u32) {
+    for _ in 0..(cycles / 4) {
+      self.ticks += 4;
+      self.timer.step(4, &mut self.interrupts);
+      let frames: u64 = self.gpu.frames;
+      self.gpu.step(4, &mut self.interrupts);
+      if self.gpu.frames != frames {
+        if let Some(sgb) = &mut self.sgb {
+          sgb.end_frame(&self.gpu);
+        }
+      }
+      self.step_dma();
+    }
+  }
+
+  fn pending_interrupts(&self) -> u8 {
+    return self.interrupts.pending();
+  }
+
+  fn acknowledge_interrupt(&mut self, flag: u8) {
+    self.interrupts.acknowledge(flag);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn memory() -> Memory {
+    return Memory::new(Cartridge::new(vec![0; 0x8000]));
+  }
+
+  // Ticks one M-cycle at a time until STAT reports `mode`.
+  fn run_to_mode(memory: &mut Memory, mode: u8) {
+    while memory.read_byte(0xff41) & 0x03 != mode {
+      memory.tick(4);
+    }
+  }
+
+  #[test]
+  fn io_registers_read_back_unused_bits_as_set() {
+    let mut memory: Memory = memory();
+    memory.write_byte(0xff07, 0x00);
+    assert_eq!(memory.read_byte(0xff07), 0xf8);
+    memory.write_byte(0xff0f, 0x01);
+    assert_eq!(memory.read_byte(0xff0f), 0xe1);
+    // NR13 is write-only and 0xff4c is unmapped.
+    memory.write_byte(0xff13, 0x12);
+    assert_eq!(memory.read_byte(0xff13), 0xff);
+    memory.write_byte(0xff4c, 0x12);
+    assert_eq!(memory.read_byte(0xff4c), 0xff);
+    memory.write_byte(0xff30, 0x12);
+    assert_eq!(memory.read_byte(0xff30), 0x12);
+  }
+
+  #[test]
+  fn unusable_region_ignores_writes() {
+    let mut memory: Memory = memory();
+    memory.write_byte(0xff40, 0x00);
+    memory.write_byte(0xfea0, 0x12);
+    assert_eq!(memory.read_byte(0xfea0), 0x00);
+    memory.write_byte(0xff80, 0x34);
+    memory.write_byte(0xfffe, 0x56);
+    assert_eq!(memory.read_byte(0xff80), 0x34);
+    assert_eq!(memory.read_byte(0xfffe), 0x56);
+  }
+
+  #[test]
+  fn vram_is_locked_during_mode_3() {
+    let mut memory: Memory = memory();
+    run_to_mode(&mut memory, 0);
+    memory.write_byte(0x8000, 0x12);
+    assert_eq!(memory.read_byte(0x8000), 0x12);
+
+    run_to_mode(&mut memory, 3);
+    assert_eq!(memory.read_byte(0x8000), 0xff);
+    memory.write_byte(0x8000, 0x34);
+    memory.access_restrictions = false;
+    assert_eq!(memory.read_byte(0x8000), 0x12);
+  }
+
+  #[test]
+  fn oam_is_locked_during_modes_2_and_3() {
+    let mut memory: Memory = memory();
+    run_to_mode(&mut memory, 0);
+    memory.write_byte(0xfe00, 0x12);
+
+    run_to_mode(&mut memory, 2);
+    assert_eq!(memory.read_byte(0xfe00), 0xff);
+    memory.write_byte(0xfe00, 0x34);
+    run_to_mode(&mut memory, 3);
+    assert_eq!(memory.read_byte(0xfe00), 0xff);
+    assert_eq!(memory.read_byte(0x8000), 0xff);
 
-  pub fn write_short_to_stack(&mut self, registers: &mut Registers, val: u16) {
-    registers.sp -= 2;
-    self.write_short(registers.sp, val)
+    run_to_mode(&mut memory, 0);
+    assert_eq!(memory.read_byte(0xfe00), 0x12);
   }
 }
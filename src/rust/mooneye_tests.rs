@@ -0,0 +1,159 @@
+// Runs the Mooneye acceptance suite headlessly and prints a summary table.
+//
+// Each ROM ends by executing `LD B,B`. On success B/C/D/E/H/L hold the
+// Fibonacci numbers 3/5/8/13/21/34; on failure they are all 0x42.
+//
+// The ROMs are not checked in. Point `MOONEYE_ROMS` at the `acceptance`
+// directory of a mooneye-test-suite build, or place it at
+// `test-roms/mooneye/acceptance`, then run `cargo test -- --ignored`.
+
+use crate::game::registers::Registers;
+use crate::game::{new_game, Game};
+use std::fs;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+
+const CYCLES_PER_SECOND: u64 = 4_194_304;
+const BUDGET: u64 = 10 * CYCLES_PER_SECOND;
+const FIBONACCI: [u8; 6] = [3, 5, 8, 13, 21, 34];
+
+#[derive(Debug, PartialEq)]
+enum Outcome {
+  Passed,
+  Failed(String),
+  TimedOut,
+  Panicked(String),
+}
+
+fn suite_dir() -> Option<PathBuf> {
+  let dir: PathBuf = match std::env::var("MOONEYE_ROMS") {
+    Ok(dir) => PathBuf::from(dir),
+    Err(_) => PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("test-roms/mooneye/acceptance"),
+  };
+  return if dir.is_dir() { Some(dir) } else { None };
+}
+
+fn find_roms(dir: &Path, roms: &mut Vec<PathBuf>) {
+  for entry in fs::read_dir(dir).unwrap() {
+    let path: PathBuf = entry.unwrap().path();
+    if path.is_dir() {
+      find_roms(&path, roms);
+    } else if path.extension().is_some_and(|ext| ext == "gb") {
+      roms.push(path);
+    }
+  }
+}
+
+// ROM names end in the models they are meant for, e.g. `boot_regs-dmgABC`,
+// `di_timing-GS` (G: DMG and MGB, S: SGB, C: CGB, A: AGB) or `boot_div-dmg0`.
+// Only ROMs that apply to a DMG-ABC are run.
+fn runs_on_dmg(stem: &str) -> bool {
+  let model: &str = match stem.rsplit_once('-') {
+    Some((_, model)) => model,
+    None => return true,
+  };
+  if model.contains("dmgABC") {
+    return true;
+  }
+  if model.chars().all(|c| "GSCA".contains(c)) {
+    return model.contains('G');
+  }
+  return !["dmg0", "mgb", "sgb", "cgb", "agb", "ags"]
+    .iter()
+    .any(|other| model.starts_with(other));
+}
+
+fn fibonacci_registers(registers: &Registers) -> [u8; 6] {
+  return [
+    registers.b,
+    registers.c,
+    registers.d,
+    registers.e,
+    registers.h,
+    registers.l,
+  ];
+}
+
+fn run(rom: Vec<u8>) -> Outcome {
+  let mut game: Game = new_game(rom);
+  while game.memory.ticks < BUDGET {
+    game.step();
+    if game.cpu.breakpoint_hit {
+      let values: [u8; 6] = fibonacci_registers(&game.registers);
+      if values == FIBONACCI {
+        return Outcome::Passed;
+      }
+      return Outcome::Failed(format!("B/C/D/E/H/L = {:02x?}", values));
+    }
+  }
+  return Outcome::TimedOut;
+}
+
+fn run_catching_panics(rom: Vec<u8>) -> Outcome {
+  return match panic::catch_unwind(AssertUnwindSafe(|| run(rom))) {
+    Ok(outcome) => outcome,
+    Err(payload) => {
+      let message: String = match payload.downcast_ref::<&str>() {
+        Some(message) => message.to_string(),
+        None => payload.downcast_ref::<String>().cloned().unwrap_or_default(),
+      };
+      Outcome::Panicked(message)
+    }
+  };
+}
+
+#[test]
+#[ignore = "needs the Mooneye acceptance ROMs (set MOONEYE_ROMS)"]
+fn mooneye_acceptance() {
+  let dir: PathBuf = suite_dir().expect("Mooneye test ROMs not found (set MOONEYE_ROMS)");
+
+  let mut roms: Vec<PathBuf> = Vec::new();
+  find_roms(&dir, &mut roms);
+  roms.retain(|path| runs_on_dmg(&path.file_stem().unwrap().to_string_lossy()));
+  roms.sort();
+
+  let mut passed: usize = 0;
+  println!("{:<48} result", "rom");
+  for path in roms.iter() {
+    let name: String = path.strip_prefix(&dir).unwrap().display().to_string();
+    let outcome: Outcome = run_catching_panics(fs::read(path).unwrap());
+    let result: String = match outcome {
+      Outcome::Passed => {
+        passed += 1;
+        "pass".to_string()
+      }
+      Outcome::Failed(reason) => format!("FAIL {}", reason),
+      Outcome::TimedOut => "FAIL timed out".to_string(),
+      Outcome::Panicked(message) => format!("FAIL panicked: {}", message),
+    };
+    println!("{:<48} {}", name, result);
+  }
+  println!("{}/{} passed", passed, roms.len());
+
+  assert_eq!(passed, roms.len(), "{} roms failed", roms.len() - passed);
+}
+
+#[test]
+fn selects_dmg_roms_by_name() {
+  assert!(runs_on_dmg("add_sp_e_timing"));
+  assert!(runs_on_dmg("boot_regs-dmgABC"));
+  assert!(runs_on_dmg("boot_hwio-dmgABCmgb"));
+  assert!(runs_on_dmg("di_timing-GS"));
+  assert!(!runs_on_dmg("boot_div-dmg0"));
+  assert!(!runs_on_dmg("boot_regs-mgb"));
+  assert!(!runs_on_dmg("boot_div2-S"));
+  assert!(!runs_on_dmg("boot_regs-sgb2"));
+}
+
+#[test]
+fn stops_at_ld_b_b() {
+  let mut rom: Vec<u8> = vec![0; 0x8000];
+  // LD B,3; LD C,5; LD D,8; LD E,13; LD H,21; LD L,34; LD B,B
+  rom[0x100..0x10d].copy_from_slice(&[
+    0x06, 3, 0x0e, 5, 0x16, 8, 0x1e, 13, 0x26, 21, 0x2e, 34, 0x40,
+  ]);
+  assert_eq!(run(rom.clone()), Outcome::Passed);
+
+  rom[0x101] = 0x42;
+  assert!(matches!(run(rom), Outcome::Failed(_)));
+}
@@ -0,0 +1,170 @@
+// Host-side colours for the four DMG shades.
+//
+// BGP/OBP0/OBP1 pick a shade from 0 (lightest) to 3 (darkest) for every
+// pixel; a `Palette` decides what those shades look like on screen. Each
+// layer can have its own set, the way a Game Boy Color colourizes original
+// Game Boy games.
+
+use crate::game::gpu::{LAYER_OBJ0, LAYER_OBJ1};
+
+pub type Rgba = [u8; 4];
+
+pub const CLASSIC_GREEN: [Rgba; 4] = [
+  [0x9b, 0xbc, 0x0f, 0xff],
+  [0x8b, 0xac, 0x0f, 0xff],
+  [0x30, 0x62, 0x30, 0xff],
+  [0x0f, 0x38, 0x0f, 0xff],
+];
+
+pub const POCKET_GREY: [Rgba; 4] = [
+  [0xc5, 0xc9, 0xb8, 0xff],
+  [0x8c, 0x92, 0x7e, 0xff],
+  [0x4a, 0x51, 0x42, 0xff],
+  [0x18, 0x1c, 0x14, 0xff],
+];
+
+pub const HIGH_CONTRAST: [Rgba; 4] = [
+  [0xff, 0xff, 0xff, 0xff],
+  [0xaa, 0xaa, 0xaa, 0xff],
+  [0x55, 0x55, 0x55, 0xff],
+  [0x00, 0x00, 0x00, 0xff],
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Palette {
+  pub bg: [Rgba; 4],
+  pub obj0: [Rgba; 4],
+  pub obj1: [Rgba; 4],
+}
+
+impl Palette {
+  // The same four colours for every layer.
+  pub fn custom(colors: [Rgba; 4]) -> Palette {
+    return Palette::per_layer(colors, colors, colors);
+  }
+
+  pub fn per_layer(bg: [Rgba; 4], obj0: [Rgba; 4], obj1: [Rgba; 4]) -> Palette {
+    return Palette { bg, obj0, obj1 };
+  }
+
+  pub fn classic_green() -> Palette {
+    return Palette::custom(CLASSIC_GREEN);
+  }
+
+  pub fn pocket_grey() -> Palette {
+    return Palette::custom(POCKET_GREY);
+  }
+
+  pub fn high_contrast() -> Palette {
+    return Palette::custom(HIGH_CONTRAST);
+  }
+
+  // A built-in palette by name, as offered to the frontend.
+  pub fn preset(name: &str) -> Result<Palette, String> {
+    return match name {
+      "classic-green" => Ok(Palette::classic_green()),
+      "pocket-grey" => Ok(Palette::pocket_grey()),
+      "high-contrast" => Ok(Palette::high_contrast()),
+      "color-green" => Ok(Palette::color_green()),
+      _ => Err(format!("unknown palette '{}'", name)),
+    };
+  }
+
+  // Colours packed as RGBA bytes, lightest shade first: 16 bytes for every
+  // layer, or 48 for background, OBJ0 and OBJ1 in turn.
+  pub fn from_rgba(bytes: &[u8]) -> Result<Palette, String> {
+    let set = |index: usize| -> [Rgba; 4] {
+      let mut colors: [Rgba; 4] = [[0; 4]; 4];
+      for (shade, color) in colors.iter_mut().enumerate() {
+        let offset: usize = index * 16 + shade * 4;
+        color.copy_from_slice(&bytes[offset..offset + 4]);
+      }
+      return colors;
+    };
+    return match bytes.len() {
+      16 => Ok(Palette::custom(set(0))),
+      48 => Ok(Palette::per_layer(set(0), set(1), set(2))),
+      length => Err(format!("palette needs 16 or 48 bytes, got {}", length)),
+    };
+  }
+
+  // What a Game Boy Color shows when Right is held during the boot logo.
+  pub fn color_green() -> Palette {
+    let objects: [Rgba; 4] = [
+      [0xff, 0xff, 0xff, 0xff],
+      [0xff, 0x84, 0x84, 0xff],
+      [0x94, 0x3a, 0x3a, 0xff],
+      [0x00, 0x00, 0x00, 0xff],
+    ];
+    return Palette::per_layer(
+      [
+        [0xff, 0xff, 0xff, 0xff],
+        [0x7b, 0xff, 0x31, 0xff],
+        [0x00, 0x63, 0xc5, 0xff],
+        [0x00, 0x00, 0x00, 0xff],
+      ],
+      objects,
+      objects,
+    );
+  }
+
+  pub fn color(&self, layer: u8, shade: u8) -> Rgba {
+    let colors: &[Rgba; 4] = match layer {
+      LAYER_OBJ0 => &self.obj0,
+      LAYER_OBJ1 => &self.obj1,
+      _ => &self.bg,
+    };
+    return colors[(shade & 0x03) as usize];
+  }
+
+  // Converts a frame of shades and layers into RGBA, four bytes per pixel.
+  pub fn to_rgba(&self, shades: &[u8], layers: &[u8], out: &mut [u8]) {
+    for ((pixel, &shade), &layer) in out.chunks_mut(4).zip(shades.iter()).zip(layers.iter()) {
+      pixel.copy_from_slice(&self.color(layer, shade));
+    }
+  }
+}
+
+impl Default for Palette {
+  fn default() -> Palette {
+    return Palette::classic_green();
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::game::gpu::LAYER_BG;
+
+  #[test]
+  fn layers_use_their_own_colours() {
+    let palette: Palette = Palette::color_green();
+    let mut out: [u8; 12] = [0; 12];
+    palette.to_rgba(&[1, 1, 1], &[LAYER_BG, LAYER_OBJ0, LAYER_OBJ1], &mut out);
+    assert_eq!(out[0..4], [0x7b, 0xff, 0x31, 0xff]);
+    assert_eq!(out[4..8], [0xff, 0x84, 0x84, 0xff]);
+    assert_eq!(out[8..12], [0xff, 0x84, 0x84, 0xff]);
+  }
+
+  #[test]
+  fn custom_palette_applies_to_every_layer() {
+    let palette: Palette = Palette::custom(HIGH_CONTRAST);
+    assert_eq!(palette, Palette::high_contrast());
+    assert_eq!(palette.color(LAYER_OBJ1, 3), [0, 0, 0, 0xff]);
+  }
+
+  #[test]
+  fn presets_and_packed_colours() {
+    assert_eq!(Palette::preset("pocket-grey"), Ok(Palette::pocket_grey()));
+    assert_eq!(Palette::preset("sepia").unwrap_err(), "unknown palette 'sepia'");
+
+    let bytes: Vec<u8> = HIGH_CONTRAST.iter().flatten().copied().collect();
+    assert_eq!(Palette::from_rgba(&bytes), Ok(Palette::high_contrast()));
+    let mut bytes: Vec<u8> = bytes.repeat(3);
+    bytes[32..36].copy_from_slice(&[1, 2, 3, 4]);
+    let palette: Palette = Palette::from_rgba(&bytes).unwrap();
+    assert_eq!(palette.color(LAYER_OBJ1, 0), [1, 2, 3, 4]);
+    assert_eq!(palette.color(LAYER_OBJ0, 0), HIGH_CONTRAST[0]);
+    assert_eq!(Palette::from_rgba(&bytes[..20]).unwrap_err(), "palette needs 16 or 48 bytes, got 20");
+  }
+}
@@ -0,0 +1,134 @@
+// Runs PPU test ROMs for a fixed number of frames and compares the
+// framebuffer pixel by pixel with a reference screenshot. On a mismatch the
+// rendered frame and a diff image (mismatches in red over a faded copy of
+// the frame) are written to `target/ppu-diffs`.
+//
+// The ROMs are not checked in. Point `PPU_TEST_ROMS` at a directory holding
+// each ROM next to its reference PNG (`dmg-acid2.gb` and `dmg-acid2.png`,
+// ...), or place them in `test-roms/ppu`, then run `cargo test -- --ignored`.
+
+use crate::game::gpu::{Renderer, SCREEN_HEIGHT, SCREEN_WIDTH};
+use crate::game::palette::Palette;
+use crate::game::{new_game, Game};
+use std::fs::{self, File};
+use std::io::BufWriter;
+use std::path::{Path, PathBuf};
+
+type Rgb = [u8; 3];
+
+// Frames are counted in time rather than VBlanks, which stop while the LCD
+// is off.
+const CYCLES_PER_FRAME: u64 = 70224;
+
+fn rom_dir() -> PathBuf {
+  return match std::env::var("PPU_TEST_ROMS") {
+    Ok(dir) => PathBuf::from(dir),
+    Err(_) => PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("test-roms/ppu"),
+  };
+}
+
+fn output_dir() -> PathBuf {
+  return PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("target/ppu-diffs");
+}
+
+fn render(rom: Vec<u8>, frames: u64, renderer: Renderer) -> Vec<Rgb> {
+  let mut game: Game = new_game(rom);
+  game.memory.gpu.set_renderer(renderer);
+  // The acid2 references are drawn in plain greys.
+  game.palette = Palette::high_contrast();
+  while game.memory.ticks < frames * CYCLES_PER_FRAME {
+    game.step();
+  }
+  return game.frame_rgba().chunks(4).map(|pixel| [pixel[0], pixel[1], pixel[2]]).collect();
+}
+
+fn read_png(path: &Path) -> (usize, usize, Vec<Rgb>) {
+  let mut decoder = png::Decoder::new(File::open(path).unwrap());
+  decoder.set_transformations(png::Transformations::normalize_to_color8());
+  let mut reader = decoder.read_info().unwrap();
+  let mut buffer: Vec<u8> = vec![0; reader.output_buffer_size()];
+  let info = reader.next_frame(&mut buffer).unwrap();
+
+  let channels: usize = info.color_type.samples();
+  let pixels: Vec<Rgb> = buffer[..info.buffer_size()]
+    .chunks(channels)
+    .map(|pixel| match info.color_type {
+      png::ColorType::Grayscale | png::ColorType::GrayscaleAlpha => [pixel[0]; 3],
+      _ => [pixel[0], pixel[1], pixel[2]],
+    })
+    .collect();
+  return (info.width as usize, info.height as usize, pixels);
+}
+
+fn write_png(path: &Path, pixels: &[Rgb]) {
+  let file: BufWriter<File> = BufWriter::new(File::create(path).unwrap());
+  let mut encoder = png::Encoder::new(file, SCREEN_WIDTH as u32, SCREEN_HEIGHT as u32);
+  encoder.set_color(png::ColorType::Rgb);
+  encoder.set_depth(png::BitDepth::Eight);
+  let data: Vec<u8> = pixels.iter().flatten().copied().collect();
+  encoder.write_header().unwrap().write_image_data(&data).unwrap();
+}
+
+// Returns the number of mismatched pixels and the diff image.
+fn diff(actual: &[Rgb], expected: &[Rgb]) -> (usize, Vec<Rgb>) {
+  let mut mismatches: usize = 0;
+  let image: Vec<Rgb> = actual
+    .iter()
+    .zip(expected.iter())
+    .map(|(a, e)| {
+      if a == e {
+        [a[0] / 4 + 0xc0, a[1] / 4 + 0xc0, a[2] / 4 + 0xc0]
+      } else {
+        mismatches += 1;
+        [0xff, 0x00, 0x00]
+      }
+    })
+    .collect();
+  return (mismatches, image);
+}
+
+fn compare_with_reference(name: &str, frames: u64, renderer: Renderer) {
+  let rom_path: PathBuf = rom_dir().join(format!("{}.gb", name));
+  let rom: Vec<u8> = fs::read(&rom_path)
+    .unwrap_or_else(|error| panic!("{}: {} (set PPU_TEST_ROMS)", rom_path.display(), error));
+  let (width, height, expected) = read_png(&rom_path.with_extension("png"));
+  assert_eq!((width, height), (SCREEN_WIDTH, SCREEN_HEIGHT), "reference image size");
+
+  let actual: Vec<Rgb> = render(rom, frames, renderer);
+  let (mismatches, image) = diff(&actual, &expected);
+  if mismatches > 0 {
+    let dir: PathBuf = output_dir();
+    fs::create_dir_all(&dir).unwrap();
+    let prefix: String = format!("{}-{:?}", name, renderer).to_lowercase();
+    write_png(&dir.join(format!("{}-actual.png", prefix)), &actual);
+    write_png(&dir.join(format!("{}-diff.png", prefix)), &image);
+    panic!(
+      "{} ({:?}): {} pixels differ from the reference, see {}",
+      name,
+      renderer,
+      mismatches,
+      dir.display()
+    );
+  }
+}
+
+#[test]
+#[ignore = "needs dmg-acid2 and its reference PNG (set PPU_TEST_ROMS)"]
+fn dmg_acid2() {
+  compare_with_reference("dmg-acid2", 60, Renderer::Scanline);
+}
+
+#[test]
+#[ignore = "needs dmg-acid2 and its reference PNG (set PPU_TEST_ROMS)"]
+fn dmg_acid2_pixel_fifo() {
+  compare_with_reference("dmg-acid2", 60, Renderer::PixelFifo);
+}
+
+#[test]
+fn diff_marks_mismatched_pixels() {
+  let actual: Vec<Rgb> = vec![[0xff, 0xff, 0xff], [0x00, 0x00, 0x00]];
+  let expected: Vec<Rgb> = vec![[0xff, 0xff, 0xff], [0xaa, 0xaa, 0xaa]];
+  let (mismatches, image) = diff(&actual, &expected);
+  assert_eq!(mismatches, 1);
+  assert_eq!(image, vec![[0xff, 0xff, 0xff], [0xff, 0x00, 0x00]]);
+}
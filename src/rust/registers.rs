@@ -1,18 +1,11 @@
 #![allow(dead_code)]
-use std::u16;
 
-const FLAG_ZERO: u8 = 1 << 7;
-const FLAG_NEGATIVE: u8 = 1 << 6;
-const FLAG_HALF_CARRY: u8 = 1 << 5;
-const FLAG_CARRY: u8 = 1 << 4;
+pub const FLAG_ZERO: u8 = 1 << 7;
+pub const FLAG_NEGATIVE: u8 = 1 << 6;
+pub const FLAG_HALF_CARRY: u8 = 1 << 5;
+pub const FLAG_CARRY: u8 = 1 << 4;
 
-macro_rules! log {
-    ( $( $t:tt )* ) => {
-        web_sys::console::log_1(&format!( $( $t )* ).into());
-    }
-}
-
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 pub struct Registers {
   pub a: u8,
   pub f: u8,
@@ -44,6 +37,22 @@ impl Default for Registers {
 }
 
 impl Registers {
+  // The state the SGB boot ROM leaves behind.
+  pub fn sgb() -> Registers {
+    return Registers {
+      a: 0x01,
+      f: 0x00,
+      b: 0x00,
+      c: 0x14,
+      d: 0x00,
+      e: 0x00,
+      h: 0xc0,
+      l: 0x60,
+      sp: 0xfffe,
+      pc: 0x100,
+    };
+  }
+
   pub fn get_af(&self) -> u16 {
     return (self.a as u16) << 8 | self.f as u16;
   }
@@ -0,0 +1,701 @@
+// Super Game Boy: command packets sent through P1, colourization of the
+// Game Boy screen and the 256x224 picture with its border.
+//
+// A packet starts with a reset pulse (P14 and P15 both low), followed by 128
+// bits, least significant first: P14 low is a 0 and P15 low is a 1, each
+// followed by both lines high. A final 0 bit ends the packet. The first byte
+// holds the command in bits 3-7 and the number of packets it spans in bits
+// 0-2.
+//
+// Colours are the SNES's 15-bit BGR. The four screen palettes share colour 0.
+
+use crate::game::gpu::{Gpu, LCDC_BG_MAP, LCDC_TILE_DATA, SCREEN_HEIGHT, SCREEN_WIDTH};
+
+pub const SGB_WIDTH: usize = 256;
+pub const SGB_HEIGHT: usize = 224;
+// Where the Game Boy screen sits inside the border.
+pub const SCREEN_LEFT: usize = 48;
+pub const SCREEN_TOP: usize = 40;
+
+// The screen is coloured in 8x8 cells.
+pub const CELLS_WIDE: usize = SCREEN_WIDTH / 8;
+pub const CELLS_HIGH: usize = SCREEN_HEIGHT / 8;
+
+const PAL01: u8 = 0x00;
+const PAL23: u8 = 0x01;
+const PAL03: u8 = 0x02;
+const PAL12: u8 = 0x03;
+const ATTR_BLK: u8 = 0x04;
+const ATTR_LIN: u8 = 0x05;
+const ATTR_DIV: u8 = 0x06;
+const ATTR_CHR: u8 = 0x07;
+const PAL_SET: u8 = 0x0a;
+const PAL_TRN: u8 = 0x0b;
+const MLT_REQ: u8 = 0x11;
+const CHR_TRN: u8 = 0x13;
+const PCT_TRN: u8 = 0x14;
+const ATTR_TRN: u8 = 0x15;
+const ATTR_SET: u8 = 0x16;
+const MASK_EN: u8 = 0x17;
+
+const PACKET_BITS: usize = 128;
+const SYSTEM_PALETTES: usize = 512;
+const ATTRIBUTE_FILES: usize = 45;
+// Four cells per byte.
+const ATTRIBUTE_FILE_SIZE: usize = CELLS_WIDE * CELLS_HIGH / 4;
+const BORDER_TILE_SIZE: usize = 32;
+const BORDER_MAP_SIZE: usize = 32 * 32 * 2;
+
+// The palette the SGB starts with, "1-A" in its palette menu.
+const DEFAULT_PALETTE: [u16; 4] = [0x67bf, 0x265b, 0x10b5, 0x2866];
+
+// What MASK_EN shows in place of the game while it sets up VRAM transfers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mask {
+  None,
+  // Keep showing the last frame.
+  Freeze,
+  Black,
+  // Fill the screen with colour 0.
+  Color0,
+}
+
+// VRAM transfers wait for the next frame, the one the SGB captures.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Transfer {
+  Palettes,
+  BorderTiles(usize),
+  BorderMap,
+  AttributeFiles,
+}
+
+pub struct Sgb {
+  // False for cartridges without the SGB flag, whose packets the SGB ignores.
+  pub enabled: bool,
+  pub palettes: [[u16; 4]; 4],
+  // Palette number of each 8x8 cell of the screen, row by row.
+  pub attributes: [u8; CELLS_WIDE * CELLS_HIGH],
+  pub mask: Mask,
+  // MLT_REQ: number of joypads (1, 2 or 4) and the one P1 reports.
+  pub players: u8,
+  pub player: u8,
+
+  system_palettes: Vec<[u16; 4]>,
+  attribute_files: Vec<[u8; ATTRIBUTE_FILE_SIZE]>,
+  // 256 4bpp tiles in SNES format, then the 32x32 map of 16-bit entries
+  // and border palettes 4-7.
+  border_tiles: Vec<u8>,
+  border_map: Vec<u8>,
+  border_palettes: [[u16; 16]; 4],
+  // The frame shown while the mask is set to freeze, as shades.
+  frozen: Vec<u8>,
+
+  // P1 as last written, and whether both lines have been released since the
+  // last bit.
+  p1: u8,
+  released: bool,
+  receiving: bool,
+  bit: usize,
+  packet: [u8; 16],
+  // Packets of a multi-packet command received so far, and how many more
+  // are to come.
+  command: Vec<u8>,
+  packets_left: u8,
+  transfer: Option<Transfer>,
+}
+
+impl Sgb {
+  pub fn new(enabled: bool) -> Sgb {
+    return Sgb {
+      enabled,
+      palettes: [DEFAULT_PALETTE; 4],
+      attributes: [0; CELLS_WIDE * CELLS_HIGH],
+      mask: Mask::None,
+      players: 1,
+      player: 0,
+
+      system_palettes: vec![[0; 4]; SYSTEM_PALETTES],
+      attribute_files: vec![[0; ATTRIBUTE_FILE_SIZE]; ATTRIBUTE_FILES],
+      border_tiles: vec![0; 256 * BORDER_TILE_SIZE],
+      border_map: vec![0; BORDER_MAP_SIZE],
+      border_palettes: [[0; 16]; 4],
+      frozen: vec![0; SCREEN_WIDTH * SCREEN_HEIGHT],
+
+      p1: 0x30,
+      released: true,
+      receiving: false,
+      bit: 0,
+      packet: [0; 16],
+      command: Vec::new(),
+      packets_left: 0,
+      transfer: None,
+    };
+  }
+
+  // Low nibble of P1 while both select lines are high: with more than one
+  // joypad, 0xf minus the current joypad's number.
+  pub fn joypad_id(&self) -> u8 {
+    return 0x0f - self.player;
+  }
+
+  pub fn write_joypad(&mut self, val: u8, gpu: &Gpu) {
+    let lines: u8 = val & 0x30;
+    // Raising P15 moves on to the next joypad.
+    if self.players > 1 && self.p1 & 0x20 == 0 && lines & 0x20 != 0 {
+      self.player = (self.player + 1) % self.players;
+    }
+    self.p1 = lines;
+    if !self.enabled {
+      return;
+    }
+
+    match lines {
+      0x00 => {
+        self.receiving = true;
+        self.released = false;
+        self.bit = 0;
+        self.packet = [0; 16];
+      }
+      0x10 | 0x20 if self.receiving && self.released => {
+        self.released = false;
+        let one: bool = lines == 0x10;
+        if self.bit == PACKET_BITS {
+          self.receiving = false;
+          // The stop bit must be a 0.
+          if !one {
+            self.receive_packet(gpu);
+          }
+        } else {
+          if one {
+            self.packet[self.bit / 8] |= 1 << (self.bit % 8);
+          }
+          self.bit += 1;
+        }
+      }
+      0x30 => self.released = true,
+      _ => {}
+    }
+  }
+
+  fn receive_packet(&mut self, gpu: &Gpu) {
+    if self.packets_left == 0 {
+      let length: u8 = self.packet[0] & 0x07;
+      if length == 0 {
+        return;
+      }
+      self.command.clear();
+      self.packets_left = length;
+    }
+    self.command.extend_from_slice(&self.packet);
+    self.packets_left -= 1;
+    if self.packets_left == 0 {
+      let command: Vec<u8> = std::mem::take(&mut self.command);
+      self.execute(&command, gpu);
+    }
+  }
+
+  fn execute(&mut self, data: &[u8], gpu: &Gpu) {
+    match data[0] >> 3 {
+      PAL01 => self.set_palette_pair(data, 0, 1),
+      PAL23 => self.set_palette_pair(data, 2, 3),
+      PAL03 => self.set_palette_pair(data, 0, 3),
+      PAL12 => self.set_palette_pair(data, 1, 2),
+      ATTR_BLK => self.attribute_blocks(data),
+      ATTR_LIN => self.attribute_lines(data),
+      ATTR_DIV => self.attribute_division(data),
+      ATTR_CHR => self.attribute_characters(data),
+      PAL_SET => self.palette_set(data),
+      PAL_TRN => self.transfer = Some(Transfer::Palettes),
+      MLT_REQ => {
+        self.players = match data[1] & 0x03 {
+          1 => 2,
+          3 => 4,
+          _ => 1,
+        };
+        self.player = 0;
+      }
+      CHR_TRN => self.transfer = Some(Transfer::BorderTiles((data[1] & 0x01) as usize)),
+      PCT_TRN => self.transfer = Some(Transfer::BorderMap),
+      ATTR_TRN => self.transfer = Some(Transfer::AttributeFiles),
+      ATTR_SET => {
+        self.apply_attribute_file(data[1] & 0x3f);
+        if data[1] & 0x40 != 0 {
+          self.mask = Mask::None;
+        }
+      }
+      MASK_EN => {
+        self.mask = match data[1] & 0x03 {
+          1 => Mask::Freeze,
+          2 => Mask::Black,
+          3 => Mask::Color0,
+          _ => Mask::None,
+        };
+        if self.mask == Mask::Freeze {
+          self.frozen.copy_from_slice(&gpu.framebuffer);
+        }
+      }
+      _ => {}
+    }
+  }
+
+  fn set_palette_pair(&mut self, data: &[u8], first: usize, second: usize) {
+    let color = |index: usize| -> u16 {
+      return u16::from_le_bytes([data[1 + index * 2], data[2 + index * 2]]);
+    };
+    let shared: u16 = color(0);
+    for palette in self.palettes.iter_mut() {
+      palette[0] = shared;
+    }
+    for i in 1..4 {
+      self.palettes[first][i] = color(i);
+      self.palettes[second][i] = color(i + 3);
+    }
+  }
+
+  fn attribute_blocks(&mut self, data: &[u8]) {
+    let count: usize = (data[1] & 0x1f) as usize;
+    for block in data[2..].chunks_exact(6).take(count) {
+      let mut control: u8 = block[0] & 0x07;
+      let inside: u8 = block[1] & 0x03;
+      let mut border: u8 = (block[1] >> 2) & 0x03;
+      let outside: u8 = (block[1] >> 4) & 0x03;
+      // With only one of inside and outside set, the border follows it.
+      if control == 0x01 {
+        control |= 0x02;
+        border = inside;
+      } else if control == 0x04 {
+        control |= 0x02;
+        border = outside;
+      }
+      let (left, top) = (block[2] as usize & 0x1f, block[3] as usize & 0x1f);
+      let (right, bottom) = (block[4] as usize & 0x1f, block[5] as usize & 0x1f);
+
+      for y in 0..CELLS_HIGH {
+        for x in 0..CELLS_WIDE {
+          let within: bool = x >= left && x <= right && y >= top && y <= bottom;
+          let on_edge: bool = within && (x == left || x == right || y == top || y == bottom);
+          let palette: Option<u8> = if on_edge && control & 0x02 != 0 {
+            Some(border)
+          } else if within && !on_edge && control & 0x01 != 0 {
+            Some(inside)
+          } else if !within && control & 0x04 != 0 {
+            Some(outside)
+          } else {
+            None
+          };
+          if let Some(palette) = palette {
+            self.attributes[y * CELLS_WIDE + x] = palette;
+          }
+        }
+      }
+    }
+  }
+
+  fn attribute_lines(&mut self, data: &[u8]) {
+    let count: usize = data[1] as usize;
+    for &line in data[2..].iter().take(count) {
+      let number: usize = (line & 0x1f) as usize;
+      let palette: u8 = (line >> 5) & 0x03;
+      if line & 0x80 != 0 {
+        // A horizontal line: row `number`.
+        if number < CELLS_HIGH {
+          self.attributes[number * CELLS_WIDE..(number + 1) * CELLS_WIDE].fill(palette);
+        }
+      } else if number < CELLS_WIDE {
+        for y in 0..CELLS_HIGH {
+          self.attributes[y * CELLS_WIDE + number] = palette;
+        }
+      }
+    }
+  }
+
+  fn attribute_division(&mut self, data: &[u8]) {
+    let after: u8 = data[1] & 0x03;
+    let before: u8 = (data[1] >> 2) & 0x03;
+    let on_line: u8 = (data[1] >> 4) & 0x03;
+    let horizontal: bool = data[1] & 0x40 != 0;
+    let split: usize = (data[2] & 0x1f) as usize;
+    for y in 0..CELLS_HIGH {
+      for x in 0..CELLS_WIDE {
+        let position: usize = if horizontal { y } else { x };
+        self.attributes[y * CELLS_WIDE + x] = match position {
+          _ if position < split => before,
+          _ if position == split => on_line,
+          _ => after,
+        };
+      }
+    }
+  }
+
+  fn attribute_characters(&mut self, data: &[u8]) {
+    let mut x: usize = (data[1] & 0x1f) as usize;
+    let mut y: usize = (data[2] & 0x1f) as usize;
+    let count: usize = u16::from_le_bytes([data[3], data[4]]) as usize;
+    let vertical: bool = data[5] & 0x01 != 0;
+    for index in 0..count.min((data.len() - 6) * 4) {
+      if x >= CELLS_WIDE || y >= CELLS_HIGH {
+        break;
+      }
+      let byte: u8 = data[6 + index / 4];
+      self.attributes[y * CELLS_WIDE + x] = (byte >> (6 - (index % 4) * 2)) & 0x03;
+      if vertical {
+        y += 1;
+        if y == CELLS_HIGH {
+          y = 0;
+          x += 1;
+        }
+      } else {
+        x += 1;
+        if x == CELLS_WIDE {
+          x = 0;
+          y += 1;
+        }
+      }
+    }
+  }
+
+  fn palette_set(&mut self, data: &[u8]) {
+    for (palette, id) in data[1..9].chunks_exact(2).enumerate() {
+      let id: usize = u16::from_le_bytes([id[0], id[1]]) as usize % SYSTEM_PALETTES;
+      self.palettes[palette] = self.system_palettes[id];
+    }
+    let shared: u16 = self.palettes[0][0];
+    for palette in self.palettes.iter_mut() {
+      palette[0] = shared;
+    }
+    if data[9] & 0x80 != 0 {
+      self.apply_attribute_file(data[9] & 0x3f);
+    }
+    if data[9] & 0x40 != 0 {
+      self.mask = Mask::None;
+    }
+  }
+
+  fn apply_attribute_file(&mut self, file: u8) {
+    let file: usize = file as usize;
+    if file >= ATTRIBUTE_FILES {
+      return;
+    }
+    for (cell, attribute) in self.attributes.iter_mut().enumerate() {
+      let byte: u8 = self.attribute_files[file][cell / 4];
+      *attribute = (byte >> (6 - (cell % 4) * 2)) & 0x03;
+    }
+  }
+
+  // Called when the GPU finishes a frame: runs any VRAM transfer waiting on
+  // it.
+  pub fn end_frame(&mut self, gpu: &Gpu) {
+    let transfer: Transfer = match self.transfer.take() {
+      Some(transfer) => transfer,
+      None => return,
+    };
+    let data: Vec<u8> = transfer_data(gpu);
+    match transfer {
+      Transfer::Palettes => {
+        for (palette, colors) in self.system_palettes.iter_mut().zip(data.chunks_exact(8)) {
+          for (color, bytes) in palette.iter_mut().zip(colors.chunks_exact(2)) {
+            *color = u16::from_le_bytes([bytes[0], bytes[1]]);
+          }
+        }
+      }
+      Transfer::BorderTiles(half) => {
+        let start: usize = half * data.len();
+        self.border_tiles[start..start + data.len()].copy_from_slice(&data);
+      }
+      Transfer::BorderMap => {
+        self.border_map.copy_from_slice(&data[..BORDER_MAP_SIZE]);
+        let colors = data[BORDER_MAP_SIZE..BORDER_MAP_SIZE + 128].chunks_exact(2);
+        for (index, bytes) in colors.enumerate() {
+          self.border_palettes[index / 16][index % 16] = u16::from_le_bytes([bytes[0], bytes[1]]);
+        }
+      }
+      Transfer::AttributeFiles => {
+        for (file, bytes) in self.attribute_files.iter_mut().zip(data.chunks_exact(ATTRIBUTE_FILE_SIZE)) {
+          file.copy_from_slice(bytes);
+        }
+      }
+    }
+  }
+
+  // The Game Boy screen coloured by the attribute map, as RGBA.
+  pub fn screen_rgba(&self, gpu: &Gpu) -> Vec<u8> {
+    let mut out: Vec<u8> = vec![0; SCREEN_WIDTH * SCREEN_HEIGHT * 4];
+    for y in 0..SCREEN_HEIGHT {
+      for x in 0..SCREEN_WIDTH {
+        let offset: usize = (y * SCREEN_WIDTH + x) * 4;
+        out[offset..offset + 4].copy_from_slice(&self.screen_pixel(gpu, x, y));
+      }
+    }
+    return out;
+  }
+
+  // The full 256x224 picture: the border with the screen in the middle.
+  // Transparent border pixels show colour 0.
+  pub fn frame_rgba(&self, gpu: &Gpu) -> Vec<u8> {
+    let mut out: Vec<u8> = vec![0; SGB_WIDTH * SGB_HEIGHT * 4];
+    let backdrop: [u8; 4] = rgba(self.palettes[0][0]);
+    for y in 0..SGB_HEIGHT {
+      for x in 0..SGB_WIDTH {
+        let on_screen: bool = (SCREEN_LEFT..SCREEN_LEFT + SCREEN_WIDTH).contains(&x)
+          && (SCREEN_TOP..SCREEN_TOP + SCREEN_HEIGHT).contains(&y);
+        let color: [u8; 4] = if on_screen {
+          self.screen_pixel(gpu, x - SCREEN_LEFT, y - SCREEN_TOP)
+        } else {
+          self.border_pixel(x, y).map(rgba).unwrap_or(backdrop)
+        };
+        let offset: usize = (y * SGB_WIDTH + x) * 4;
+        out[offset..offset + 4].copy_from_slice(&color);
+      }
+    }
+    return out;
+  }
+
+  fn screen_pixel(&self, gpu: &Gpu, x: usize, y: usize) -> [u8; 4] {
+    let shade: u8 = match self.mask {
+      Mask::None => gpu.framebuffer[y * SCREEN_WIDTH + x],
+      Mask::Freeze => self.frozen[y * SCREEN_WIDTH + x],
+      Mask::Black => return [0, 0, 0, 0xff],
+      Mask::Color0 => 0,
+    };
+    let palette: usize = self.attributes[(y / 8) * CELLS_WIDE + x / 8] as usize;
+    // Colour 0 is shared by every palette.
+    let color: u16 = if shade == 0 {
+      self.palettes[0][0]
+    } else {
+      self.palettes[palette][shade as usize]
+    };
+    return rgba(color);
+  }
+
+  // None where the border is transparent.
+  fn border_pixel(&self, x: usize, y: usize) -> Option<u16> {
+    let entry: usize = ((y / 8) * 32 + x / 8) * 2;
+    let tile: usize = self.border_map[entry] as usize;
+    let attributes: u8 = self.border_map[entry + 1];
+    let palette: usize = ((attributes >> 2) & 0x07) as usize;
+    let column: usize = if attributes & 0x40 != 0 { 7 - x % 8 } else { x % 8 };
+    let row: usize = if attributes & 0x80 != 0 { 7 - y % 8 } else { y % 8 };
+
+    let data: &[u8] = &self.border_tiles[tile * BORDER_TILE_SIZE..(tile + 1) * BORDER_TILE_SIZE];
+    let bit: usize = 7 - column;
+    let planes: [u8; 4] = [data[row * 2], data[row * 2 + 1], data[16 + row * 2], data[17 + row * 2]];
+    let color: usize = planes
+      .iter()
+      .enumerate()
+      .map(|(plane, &byte)| (((byte >> bit) & 1) as usize) << plane)
+      .sum();
+    // The border uses palettes 4-7.
+    if color == 0 || palette < 4 {
+      return None;
+    }
+    return Some(self.border_palettes[palette - 4][color]);
+  }
+}
+
+// The 4KB a VRAM transfer sends: the tile data of the first 256 tiles on
+// screen, read through the background map the way the SGB captures it.
+fn transfer_data(gpu: &Gpu) -> Vec<u8> {
+  let map: u16 = if gpu.control & LCDC_BG_MAP != 0 { 0x1c00 } else { 0x1800 };
+  let mut data: Vec<u8> = Vec::with_capacity(0x1000);
+  for index in 0..256 {
+    let tile: u8 = gpu.read_vram(map + (index / CELLS_WIDE * 32 + index % CELLS_WIDE) as u16);
+    let address: u16 = if gpu.control & LCDC_TILE_DATA != 0 {
+      tile as u16 * 16
+    } else {
+      (0x1000 + (tile as i8 as i16) * 16) as u16
+    };
+    for offset in 0..16 {
+      data.push(gpu.read_vram(address + offset));
+    }
+  }
+  return data;
+}
+
+fn rgba(color: u16) -> [u8; 4] {
+  let channel = |shift: u16| -> u8 {
+    let value: u8 = ((color >> shift) & 0x1f) as u8;
+    return (value << 3) | (value >> 2);
+  };
+  return [channel(0), channel(5), channel(10), 0xff];
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::game::cartridge::Cartridge;
+  use crate::game::memory::Memory;
+
+  // Sends a command the way a game does, one packet of 16 bytes at a time.
+  fn send(sgb: &mut Sgb, gpu: &Gpu, command: &[u8]) {
+    for packet in command.chunks(16) {
+      sgb.write_joypad(0x00, gpu);
+      sgb.write_joypad(0x30, gpu);
+      for bit in 0..PACKET_BITS {
+        let byte: u8 = packet.get(bit / 8).copied().unwrap_or(0);
+        let line: u8 = if byte & (1 << (bit % 8)) != 0 { 0x10 } else { 0x20 };
+        sgb.write_joypad(line, gpu);
+        sgb.write_joypad(0x30, gpu);
+      }
+      sgb.write_joypad(0x20, gpu);
+      sgb.write_joypad(0x30, gpu);
+    }
+  }
+
+  fn command(code: u8, packets: u8, data: &[u8]) -> Vec<u8> {
+    let mut bytes: Vec<u8> = vec![0; packets as usize * 16];
+    bytes[0] = code << 3 | packets;
+    bytes[1..1 + data.len()].copy_from_slice(data);
+    return bytes;
+  }
+
+  // Lays `data` out as 256 tiles shown in order on screen, ready for a
+  // VRAM transfer.
+  fn gpu_showing(data: &[u8]) -> Gpu {
+    let mut gpu: Gpu = Default::default();
+    for (offset, &byte) in data.iter().enumerate() {
+      gpu.write_vram(offset as u16, byte);
+    }
+    for index in 0..256 {
+      gpu.write_vram(0x1800 + (index / 20 * 32 + index % 20) as u16, index as u8);
+    }
+    return gpu;
+  }
+
+  #[test]
+  fn pal01_sets_two_palettes_and_the_shared_colour() {
+    let gpu: Gpu = Default::default();
+    let mut sgb: Sgb = Sgb::new(true);
+    let colors: [u8; 14] = [
+      0x00, 0x7c, 0x01, 0x00, 0x02, 0x00, 0x03, 0x00, 0x04, 0x00, 0x05, 0x00, 0x06, 0x00,
+    ];
+    send(&mut sgb, &gpu, &command(PAL01, 1, &colors));
+    assert_eq!(sgb.palettes[0], [0x7c00, 1, 2, 3]);
+    assert_eq!(sgb.palettes[1], [0x7c00, 4, 5, 6]);
+    assert_eq!(sgb.palettes[2][0], 0x7c00);
+    assert_eq!(sgb.palettes[2][1], DEFAULT_PALETTE[1]);
+  }
+
+  #[test]
+  fn ignores_packets_without_a_stop_bit_or_when_disabled() {
+    let gpu: Gpu = Default::default();
+    let mut sgb: Sgb = Sgb::new(false);
+    send(&mut sgb, &gpu, &command(MLT_REQ, 1, &[0x01]));
+    assert_eq!(sgb.players, 1);
+
+    sgb.enabled = true;
+    let packet: Vec<u8> = command(MLT_REQ, 1, &[0x01]);
+    sgb.write_joypad(0x00, &gpu);
+    sgb.write_joypad(0x30, &gpu);
+    for bit in 0..=PACKET_BITS {
+      let one: bool = bit == PACKET_BITS || packet[(bit / 8) % 16] & (1 << (bit % 8)) != 0;
+      sgb.write_joypad(if one { 0x10 } else { 0x20 }, &gpu);
+      sgb.write_joypad(0x30, &gpu);
+    }
+    assert_eq!(sgb.players, 1);
+  }
+
+  #[test]
+  fn attribute_commands_fill_the_cell_map() {
+    let gpu: Gpu = Default::default();
+    let mut sgb: Sgb = Sgb::new(true);
+
+    // Inside only: (2,3)-(4,5) gets palette 1, border included.
+    send(&mut sgb, &gpu, &command(ATTR_BLK, 1, &[1, 0x01, 0x01, 2, 3, 4, 5]));
+    assert_eq!(sgb.attributes[3 * CELLS_WIDE + 2], 1);
+    assert_eq!(sgb.attributes[4 * CELLS_WIDE + 3], 1);
+    assert_eq!(sgb.attributes[6 * CELLS_WIDE + 3], 0);
+
+    // Row 7 palette 2, column 10 palette 3.
+    send(&mut sgb, &gpu, &command(ATTR_LIN, 1, &[2, 0x80 | 2 << 5 | 7, 3 << 5 | 10]));
+    assert_eq!(sgb.attributes[7 * CELLS_WIDE], 2);
+    assert_eq!(sgb.attributes[CELLS_WIDE + 10], 3);
+
+    // Split at column 5: 1 to the left, 2 on it, 3 to the right.
+    send(&mut sgb, &gpu, &command(ATTR_DIV, 1, &[0x03 | 1 << 2 | 2 << 4, 5]));
+    assert_eq!(sgb.attributes[4], 1);
+    assert_eq!(sgb.attributes[9 * CELLS_WIDE + 5], 2);
+    assert_eq!(sgb.attributes[17 * CELLS_WIDE + 19], 3);
+
+    // Four cells from (18,0), wrapping onto the next row.
+    send(&mut sgb, &gpu, &command(ATTR_CHR, 1, &[18, 0, 4, 0, 0, 0b00_01_10_11]));
+    assert_eq!(sgb.attributes[18..20], [0, 1]);
+    assert_eq!(sgb.attributes[CELLS_WIDE..CELLS_WIDE + 2], [2, 3]);
+  }
+
+  #[test]
+  fn screen_is_coloured_per_cell() {
+    let mut gpu: Gpu = Default::default();
+    let mut sgb: Sgb = Sgb::new(true);
+    gpu.framebuffer[0] = 3;
+    gpu.framebuffer[8] = 3;
+    sgb.palettes[1][3] = 0x001f;
+    sgb.attributes[1] = 1;
+    let out: Vec<u8> = sgb.screen_rgba(&gpu);
+    assert_eq!(out[0..4], rgba(DEFAULT_PALETTE[3]));
+    assert_eq!(out[32..36], [0xff, 0, 0, 0xff]);
+
+    send(&mut sgb, &gpu, &command(MASK_EN, 1, &[2]));
+    assert_eq!(sgb.screen_rgba(&gpu)[0..4], [0, 0, 0, 0xff]);
+  }
+
+  #[test]
+  fn attr_trn_and_attr_set_load_attribute_files() {
+    let mut data: Vec<u8> = vec![0; 0x1000];
+    // File 1 starts with cells 0-3 set to palettes 3, 2, 1, 0.
+    data[ATTRIBUTE_FILE_SIZE] = 0b11_10_01_00;
+    let gpu: Gpu = gpu_showing(&data);
+    let mut sgb: Sgb = Sgb::new(true);
+    send(&mut sgb, &gpu, &command(ATTR_TRN, 1, &[]));
+    sgb.end_frame(&gpu);
+    send(&mut sgb, &gpu, &command(ATTR_SET, 1, &[1]));
+    assert_eq!(sgb.attributes[0..4], [3, 2, 1, 0]);
+  }
+
+  #[test]
+  fn border_comes_from_chr_trn_and_pct_trn() {
+    let mut sgb: Sgb = Sgb::new(true);
+    let mut tiles: Vec<u8> = vec![0; 0x1000];
+    // Border tile 1: its top-left pixel is colour 1.
+    tiles[BORDER_TILE_SIZE] = 0x80;
+    send(&mut sgb, &gpu_showing(&tiles), &command(CHR_TRN, 1, &[0]));
+    sgb.end_frame(&gpu_showing(&tiles));
+
+    let mut map: Vec<u8> = vec![0; 0x1000];
+    // Map entry 0 shows tile 1 with palette 4, whose colour 1 is red.
+    map[0] = 1;
+    map[1] = 4 << 2;
+    map[BORDER_MAP_SIZE + 2] = 0x1f;
+    let gpu: Gpu = gpu_showing(&map);
+    send(&mut sgb, &gpu, &command(PCT_TRN, 1, &[]));
+    // Nothing happens until the frame is captured.
+    assert_eq!(sgb.frame_rgba(&gpu)[0..4], rgba(DEFAULT_PALETTE[0]));
+    sgb.end_frame(&gpu);
+
+    let out: Vec<u8> = sgb.frame_rgba(&gpu);
+    assert_eq!(out.len(), SGB_WIDTH * SGB_HEIGHT * 4);
+    assert_eq!(out[0..4], [0xff, 0, 0, 0xff]);
+    assert_eq!(out[4..8], rgba(DEFAULT_PALETTE[0]));
+  }
+
+  #[test]
+  fn mlt_req_cycles_joypad_ids_through_p1() {
+    let mut memory: Memory = Memory::new(Cartridge::new(vec![0; 0x8000]));
+    memory.sgb = Some(Sgb::new(true));
+    memory.write_byte(0xff00, 0x30);
+    assert_eq!(memory.read_byte(0xff00), 0xff);
+
+    let packet: Vec<u8> = command(MLT_REQ, 1, &[0x01]);
+    let mut sgb: Sgb = memory.sgb.take().unwrap();
+    send(&mut sgb, &memory.gpu, &packet);
+    memory.sgb = Some(sgb);
+    memory.write_byte(0xff00, 0x30);
+    assert_eq!(memory.read_byte(0xff00) & 0x0f, 0x0f);
+    // Raising P15 selects the next joypad, wrapping after the second.
+    memory.write_byte(0xff00, 0x10);
+    memory.write_byte(0xff00, 0x30);
+    assert_eq!(memory.read_byte(0xff00) & 0x0f, 0x0e);
+    memory.write_byte(0xff00, 0x00);
+    memory.write_byte(0xff00, 0x30);
+    assert_eq!(memory.read_byte(0xff00) & 0x0f, 0x0f);
+  }
+}
@@ -0,0 +1,170 @@
+// Runs the SingleStepTests sm83 JSON corpus (one file per opcode, e.g.
+// `00.json` and `cb 00.json`) against `Cpu::step` on a `FlatBus`.
+//
+// The corpus is not checked in. Point `SM83_TESTS` at its `v1` directory, or
+// place it at `test-roms/sm83/v1`, then run `cargo test -- --ignored`.
+
+use crate::game::bus::{Access, FlatBus};
+use crate::game::cpu::Cpu;
+use crate::game::registers::Registers;
+use serde::Deserialize;
+use std::fs;
+use std::path::PathBuf;
+
+#[derive(Deserialize)]
+struct TestCase {
+  name: String,
+  initial: CpuState,
+  #[serde(rename = "final")]
+  expected: CpuState,
+  cycles: Vec<serde_json::Value>,
+}
+
+#[derive(Deserialize)]
+struct CpuState {
+  pc: u16,
+  sp: u16,
+  a: u8,
+  b: u8,
+  c: u8,
+  d: u8,
+  e: u8,
+  f: u8,
+  h: u8,
+  l: u8,
+  #[serde(default)]
+  ime: u8,
+  ram: Vec<(u16, u8)>,
+}
+
+// Each expected cycle is `[address, value, "rwm"]`, where the third field
+// marks a read or write with `r`/`w`; internal cycles may use nulls.
+fn expected_access(cycle: &serde_json::Value) -> Option<Access> {
+  let address = cycle.get(0)?.as_u64()? as u16;
+  let val = cycle.get(1)?.as_u64()? as u8;
+  let kind = cycle.get(2)?.as_str()?;
+  if kind.starts_with('r') {
+    return Some(Access::Read(address, val));
+  } else if kind.contains('w') {
+    return Some(Access::Write(address, val));
+  }
+  return None;
+}
+
+fn registers_from(state: &CpuState) -> Registers {
+  return Registers {
+    a: state.a,
+    f: state.f,
+    b: state.b,
+    c: state.c,
+    d: state.d,
+    e: state.e,
+    h: state.h,
+    l: state.l,
+    sp: state.sp,
+    pc: state.pc,
+  };
+}
+
+// Returns a description of the first mismatch, if any.
+fn run_case(case: &TestCase) -> Option<String> {
+  let mut bus: FlatBus = FlatBus::new();
+  bus.record = true;
+  for &(address, val) in case.initial.ram.iter() {
+    bus.ram[address as usize] = val;
+  }
+  let mut registers: Registers = registers_from(&case.initial);
+  let mut cpu: Cpu = Default::default();
+  cpu.set_interrupts_enabled(case.initial.ime != 0);
+
+  cpu.step(&mut bus, &mut registers);
+
+  let expected: Registers = registers_from(&case.expected);
+  if registers != expected {
+    return Some(format!("registers {:?}, expected {:?}", registers, expected));
+  }
+  if cpu.interrupts_enabled() != (case.expected.ime != 0) {
+    return Some(format!("ime {}, expected {}", cpu.interrupts_enabled(), case.expected.ime));
+  }
+  for &(address, val) in case.expected.ram.iter() {
+    if bus.ram[address as usize] != val {
+      return Some(format!(
+        "ram[{:#06x}] = {:#04x}, expected {:#04x}",
+        address, bus.ram[address as usize], val
+      ));
+    }
+  }
+  if bus.activity.len() != case.cycles.len() {
+    return Some(format!(
+      "took {} M-cycles, expected {}",
+      bus.activity.len(),
+      case.cycles.len()
+    ));
+  }
+  for (index, cycle) in case.cycles.iter().enumerate() {
+    if let Some(access) = expected_access(cycle) {
+      if bus.activity[index] != access {
+        return Some(format!(
+          "M-cycle {} was {:?}, expected {:?}",
+          index, bus.activity[index], access
+        ));
+      }
+    }
+  }
+  return None;
+}
+
+fn corpus_dir() -> Option<PathBuf> {
+  let dir: PathBuf = match std::env::var("SM83_TESTS") {
+    Ok(dir) => PathBuf::from(dir),
+    Err(_) => PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("test-roms/sm83/v1"),
+  };
+  return if dir.is_dir() { Some(dir) } else { None };
+}
+
+#[test]
+#[ignore = "needs the sm83 JSON corpus (set SM83_TESTS)"]
+fn sm83_json_corpus() {
+  let dir: PathBuf = corpus_dir().expect("sm83 test corpus not found (set SM83_TESTS)");
+
+  let mut files: Vec<PathBuf> = fs::read_dir(&dir)
+    .unwrap()
+    .map(|entry| entry.unwrap().path())
+    .filter(|path| path.extension().is_some_and(|ext| ext == "json"))
+    .collect();
+  files.sort();
+
+  let mut failed_opcodes: usize = 0;
+  println!("{:<8} {:>7} {:>7}  first failure", "opcode", "passed", "total");
+  for path in files.iter() {
+    let opcode: String = path.file_stem().unwrap().to_string_lossy().into_owned();
+    let cases: Vec<TestCase> = serde_json::from_str(&fs::read_to_string(path).unwrap())
+      .unwrap_or_else(|err| panic!("{}: {}", path.display(), err));
+
+    let mut passed: usize = 0;
+    let mut first_failure: Option<String> = None;
+    for case in cases.iter() {
+      match run_case(case) {
+        None => passed += 1,
+        Some(reason) => {
+          if first_failure.is_none() {
+            first_failure = Some(format!("{}: {}", case.name, reason));
+          }
+        }
+      }
+    }
+
+    if passed != cases.len() {
+      failed_opcodes += 1;
+    }
+    println!(
+      "{:<8} {:>7} {:>7}  {}",
+      opcode,
+      passed,
+      cases.len(),
+      first_failure.unwrap_or_default()
+    );
+  }
+
+  assert_eq!(failed_opcodes, 0, "{} opcodes failed", failed_opcodes);
+}
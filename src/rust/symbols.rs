@@ -0,0 +1,181 @@
+// Labels from an RGBDS `.sym` file, as written by `rgblink -n`:
+//
+//   ; File generated by rgblink
+//   00:0150 Main
+//   00:0158 Main.loop
+//   02:4000 LoadLevel
+//   00:c000 wPlayerX
+//
+// Each line is `bank:address name` in hex. Banks only tell labels apart in
+// switchable ROM (0x4000-0x7fff); elsewhere a label matches whatever bank
+// it was given, since WRAM and SRAM banking isn't tracked.
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Symbol {
+  pub bank: usize,
+  pub address: u16,
+  pub name: String,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Symbols {
+  // Sorted by address, then bank.
+  symbols: Vec<Symbol>,
+}
+
+fn banked(address: u16) -> bool {
+  return (0x4000..0x8000).contains(&address);
+}
+
+// The start of the memory area holding `address`, so that an offset from a
+// label never reaches into a different kind of memory.
+fn area_start(address: u16) -> u16 {
+  return match address {
+    0x0000..=0x3fff => 0x0000,
+    0x4000..=0x7fff => 0x4000,
+    0x8000..=0x9fff => 0x8000,
+    0xa000..=0xbfff => 0xa000,
+    0xc000..=0xdfff => 0xc000,
+    0xe000..=0xfdff => 0xe000,
+    0xfe00..=0xfeff => 0xfe00,
+    0xff00..=0xff7f => 0xff00,
+    _ => 0xff80,
+  };
+}
+
+fn parse_line(line: &str) -> Result<Option<Symbol>, String> {
+  let line: &str = line.split(';').next().unwrap_or("").trim();
+  if line.is_empty() {
+    return Ok(None);
+  }
+  let mut words = line.split_whitespace();
+  let location: &str = words.next().unwrap_or("");
+  let name: &str = match words.next() {
+    Some(name) => name,
+    None => return Err(format!("missing label name in '{}'", line)),
+  };
+  let (bank, address) = match location.split_once(':') {
+    Some(parts) => parts,
+    None => return Err(format!("expected bank:address in '{}'", line)),
+  };
+  let bank: usize =
+    usize::from_str_radix(bank, 16).map_err(|_| format!("bad bank '{}'", bank))?;
+  let address: u16 =
+    u16::from_str_radix(address, 16).map_err(|_| format!("bad address '{}'", address))?;
+  return Ok(Some(Symbol {
+    bank,
+    address,
+    name: name.to_string(),
+  }));
+}
+
+impl Symbols {
+  pub fn parse(text: &str) -> Result<Symbols, String> {
+    let mut symbols: Vec<Symbol> = Vec::new();
+    for (number, line) in text.lines().enumerate() {
+      match parse_line(line) {
+        Ok(Some(symbol)) => symbols.push(symbol),
+        Ok(None) => {}
+        Err(error) => return Err(format!("line {}: {}", number + 1, error)),
+      }
+    }
+    symbols.sort_by_key(|symbol| (symbol.address, symbol.bank));
+    return Ok(Symbols { symbols });
+  }
+
+  pub fn is_empty(&self) -> bool {
+    return self.symbols.is_empty();
+  }
+
+  pub fn iter(&self) -> impl Iterator<Item = &Symbol> {
+    return self.symbols.iter();
+  }
+
+  fn matches(symbol: &Symbol, bank: usize) -> bool {
+    return !banked(symbol.address) || symbol.bank == bank;
+  }
+
+  // The label at exactly `address`, with `bank` mapped at 0x4000-0x7fff.
+  // With several labels at one address the first in the file wins.
+  pub fn label_at(&self, bank: usize, address: u16) -> Option<&str> {
+    let start: usize = self.symbols.partition_point(|symbol| symbol.address < address);
+    return self.symbols[start..]
+      .iter()
+      .take_while(|symbol| symbol.address == address)
+      .find(|symbol| Symbols::matches(symbol, bank))
+      .map(|symbol| symbol.name.as_str());
+  }
+
+  // `address` as the nearest label at or before it, `Label` or `Label+$1a`.
+  pub fn describe(&self, bank: usize, address: u16) -> Option<String> {
+    let end: usize = self.symbols.partition_point(|symbol| symbol.address <= address);
+    let area: u16 = area_start(address);
+    let symbol: &Symbol = self.symbols[..end]
+      .iter()
+      .rev()
+      .take_while(|symbol| symbol.address >= area)
+      .find(|symbol| Symbols::matches(symbol, bank))?;
+    let offset: u16 = address - symbol.address;
+    if offset == 0 {
+      return Some(symbol.name.clone());
+    }
+    return Some(format!("{}+${:x}", symbol.name, offset));
+  }
+
+  pub fn find(&self, name: &str) -> Option<&Symbol> {
+    return self.symbols.iter().find(|symbol| symbol.name == name);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const SYM: &str = "; File generated by rgblink\n\
+    00:0150 Main\n\
+    00:0158 Main.loop\n\
+    01:4000 Title\n\
+    02:4000 LoadLevel\n\
+    \n\
+    00:c000 wPlayerX ; comment\n";
+
+  #[test]
+  fn parses_labels_and_looks_them_up_by_bank() {
+    let symbols: Symbols = Symbols::parse(SYM).unwrap();
+    assert_eq!(symbols.iter().count(), 5);
+    assert_eq!(symbols.label_at(1, 0x0158), Some("Main.loop"));
+    assert_eq!(symbols.label_at(1, 0x4000), Some("Title"));
+    assert_eq!(symbols.label_at(2, 0x4000), Some("LoadLevel"));
+    assert_eq!(symbols.label_at(3, 0x4000), None);
+    assert_eq!(symbols.label_at(5, 0xc000), Some("wPlayerX"));
+    assert_eq!(
+      symbols.find("LoadLevel"),
+      Some(&Symbol {
+        bank: 2,
+        address: 0x4000,
+        name: "LoadLevel".to_string(),
+      })
+    );
+  }
+
+  #[test]
+  fn describes_addresses_past_a_label() {
+    let symbols: Symbols = Symbols::parse(SYM).unwrap();
+    assert_eq!(symbols.describe(1, 0x0150).as_deref(), Some("Main"));
+    assert_eq!(symbols.describe(1, 0x015a).as_deref(), Some("Main.loop+$2"));
+    assert_eq!(symbols.describe(2, 0x4123).as_deref(), Some("LoadLevel+$123"));
+    // Nothing before it in the same memory area.
+    assert_eq!(symbols.describe(1, 0x0100), None);
+    assert_eq!(symbols.describe(1, 0xa000), None);
+  }
+
+  #[test]
+  fn rejects_malformed_lines() {
+    assert_eq!(
+      Symbols::parse("00:0150 Main\n0150 Oops\n").unwrap_err(),
+      "line 2: expected bank:address in '0150 Oops'"
+    );
+    assert!(Symbols::parse("00:zz00 Main").is_err());
+    assert!(Symbols::parse("00:0150").is_err());
+  }
+}
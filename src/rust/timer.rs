@@ -0,0 +1,88 @@
+use crate::game::interrupts::{Interrupts, INTERRUPTS_TIMER};
+
+// DIV is the upper byte of a 16-bit counter incremented every T-cycle.
+// TIMA increments on the falling edge of the DIV bit selected by TAC.
+#[derive(Debug)]
+pub struct Timer {
+  pub divider: u16,
+  pub counter: u8,
+  pub modulo: u8,
+  pub control: u8,
+
+  // TIMA overflowed during the previous M-cycle and reloads from TMA on this one.
+  overflow: bool,
+}
+
+impl Default for Timer {
+  fn default() -> Timer {
+    return Timer {
+      divider: 0xabcc,
+      counter: 0,
+      modulo: 0,
+      control: 0,
+      overflow: false,
+    };
+  }
+}
+
+impl Timer {
+  pub fn step(&mut self, cycles: u32, interrupts: &mut Interrupts) {
+    for _ in 0..(cycles / 4) {
+      if self.overflow {
+        self.overflow = false;
+        self.counter = self.modulo;
+        interrupts.request(INTERRUPTS_TIMER);
+      }
+
+      let before: bool = self.input();
+      self.divider = self.divider.wrapping_add(4);
+      if before && !self.input() {
+        self.increment();
+      }
+    }
+  }
+
+  pub fn read_divider(&self) -> u8 {
+    return (self.divider >> 8) as u8;
+  }
+
+  pub fn write_divider(&mut self) {
+    let before: bool = self.input();
+    self.divider = 0;
+    if before {
+      self.increment();
+    }
+  }
+
+  pub fn write_counter(&mut self, val: u8) {
+    // Writing TIMA during the reload cycle cancels the reload and the interrupt.
+    self.overflow = false;
+    self.counter = val;
+  }
+
+  pub fn write_control(&mut self, val: u8) {
+    let before: bool = self.input();
+    self.control = val & 0x07;
+    if before && !self.input() {
+      self.increment();
+    }
+  }
+
+  fn input(&self) -> bool {
+    let bit: u16 = match self.control & 0x03 {
+      0 => 1 << 9,
+      1 => 1 << 3,
+      2 => 1 << 5,
+      _ => 1 << 7,
+    };
+    return (self.control & 0x04) != 0 && (self.divider & bit) != 0;
+  }
+
+  fn increment(&mut self) {
+    let (val, overflowed) = self.counter.overflowing_add(1);
+    self.counter = val;
+    if overflowed {
+      self.overflow = true;
+    }
+  }
+}
@@ -0,0 +1,230 @@
+// An instruction trace in the Gameboy Doctor format, one line per
+// instruction showing the state before it runs:
+//
+//   A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0100 PCMEM:00,C3,13,02
+//
+// Lines are diffable against other emulators' logs. Interrupt dispatch and
+// halted cycles aren't instructions, so they don't get lines. With symbols
+// loaded each line ends with ` ; Label+$3`, which Gameboy Doctor won't
+// accept, so leave them out when diffing.
+
+use crate::game::memory::Memory;
+use crate::game::registers::Registers;
+use crate::game::symbols::Symbols;
+use std::io::Write;
+
+pub enum TraceOutput {
+  Buffer(String),
+  Writer(Box<dyn Write>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+  // Waiting for PC to reach the start address.
+  Waiting,
+  Tracing,
+  Finished,
+}
+
+pub struct Tracer {
+  output: TraceOutput,
+  // Tracing begins when PC first reaches `start`, or straight away without
+  // one, and ends when PC reaches `stop` (which gets no line) or after
+  // `max_lines` lines.
+  pub start: Option<u16>,
+  pub stop: Option<u16>,
+  pub max_lines: Option<usize>,
+  pub symbols: Symbols,
+  state: State,
+  lines: usize,
+  error: Option<String>,
+}
+
+pub fn format_line(registers: &Registers, memory: &Memory) -> String {
+  let pc: u16 = registers.pc;
+  let pcmem = |offset: u16| -> u8 { memory.peek_byte(pc.wrapping_add(offset)) };
+  return format!(
+    "A:{:02X} F:{:02X} B:{:02X} C:{:02X} D:{:02X} E:{:02X} H:{:02X} L:{:02X} SP:{:04X} PC:{:04X} PCMEM:{:02X},{:02X},{:02X},{:02X}",
+    registers.a,
+    registers.f,
+    registers.b,
+    registers.c,
+    registers.d,
+    registers.e,
+    registers.h,
+    registers.l,
+    registers.sp,
+    pc,
+    pcmem(0),
+    pcmem(1),
+    pcmem(2),
+    pcmem(3)
+  );
+}
+
+impl Tracer {
+  pub fn new(output: TraceOutput) -> Tracer {
+    return Tracer {
+      output,
+      start: None,
+      stop: None,
+      max_lines: None,
+      symbols: Default::default(),
+      state: State::Waiting,
+      lines: 0,
+      error: None,
+    };
+  }
+
+  pub fn to_buffer() -> Tracer {
+    return Tracer::new(TraceOutput::Buffer(String::new()));
+  }
+
+  pub fn to_file(path: &str) -> Result<Tracer, String> {
+    let file: std::fs::File =
+      std::fs::File::create(path).map_err(|error| format!("{}: {}", path, error))?;
+    return Ok(Tracer::new(TraceOutput::Writer(Box::new(std::io::BufWriter::new(file)))));
+  }
+
+  // Whether the instruction at `pc` should be traced. Called before every
+  // step, so this is where the triggers and the line limit take effect.
+  pub fn wants(&mut self, pc: u16) -> bool {
+    if self.state == State::Waiting && self.start.is_none_or(|start| start == pc) {
+      self.state = State::Tracing;
+    }
+    if self.state == State::Tracing
+      && (self.stop == Some(pc) || self.max_lines.is_some_and(|max| self.lines >= max))
+    {
+      self.finish();
+    }
+    return self.state == State::Tracing;
+  }
+
+  // Adds a line made by `format_line` for an instruction that ran.
+  pub fn record(&mut self, line: String, bank: usize, pc: u16) {
+    let label: Option<String> = self.symbols.describe(bank, pc);
+    let line: String = match label {
+      Some(label) => format!("{} ; {}\n", line, label),
+      None => line + "\n",
+    };
+    self.lines += 1;
+    let result: std::io::Result<()> = match &mut self.output {
+      TraceOutput::Buffer(buffer) => {
+        buffer.push_str(&line);
+        Ok(())
+      }
+      TraceOutput::Writer(writer) => writer.write_all(line.as_bytes()),
+    };
+    if let Err(error) = result {
+      self.error = Some(error.to_string());
+      self.state = State::Finished;
+    }
+  }
+
+  fn finish(&mut self) {
+    self.state = State::Finished;
+    if let TraceOutput::Writer(writer) = &mut self.output {
+      if let Err(error) = writer.flush() {
+        self.error = Some(error.to_string());
+      }
+    }
+  }
+
+  pub fn finished(&self) -> bool {
+    return self.state == State::Finished;
+  }
+
+  pub fn lines(&self) -> usize {
+    return self.lines;
+  }
+
+  // The traced lines so far, when tracing to a buffer.
+  pub fn buffer(&self) -> Option<&str> {
+    return match &self.output {
+      TraceOutput::Buffer(buffer) => Some(buffer),
+      TraceOutput::Writer(_) => None,
+    };
+  }
+
+  // Why writing to the output failed, if it did. Tracing stops at the
+  // first failure.
+  pub fn error(&self) -> Option<&str> {
+    return self.error.as_deref();
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::game::{new_game, Game};
+
+  // 0x100: NOP; JP 0x150
+  // 0x150: INC A; INC A; JR 0x150
+  fn game() -> Game {
+    let mut rom: Vec<u8> = vec![0; 0x8000];
+    rom[0x100..0x104].copy_from_slice(&[0x00, 0xc3, 0x50, 0x01]);
+    rom[0x150..0x154].copy_from_slice(&[0x3c, 0x3c, 0x18, 0xfc]);
+    let mut game: Game = new_game(rom);
+    game.registers = Registers {
+      a: 0x01,
+      f: 0xb0,
+      c: 0x13,
+      e: 0xd8,
+      h: 0x01,
+      l: 0x4d,
+      sp: 0xfffe,
+      pc: 0x100,
+      ..Default::default()
+    };
+    return game;
+  }
+
+  fn run(game: &mut Game, steps: usize) -> Vec<String> {
+    for _ in 0..steps {
+      game.step();
+    }
+    let tracer: &Tracer = game.tracer.as_ref().unwrap();
+    return tracer.buffer().unwrap().lines().map(String::from).collect();
+  }
+
+  #[test]
+  fn writes_gameboy_doctor_lines() {
+    let mut game: Game = game();
+    game.tracer = Some(Tracer::to_buffer());
+    let lines: Vec<String> = run(&mut game, 3);
+    assert_eq!(
+      lines,
+      [
+        "A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0100 PCMEM:00,C3,50,01",
+        "A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0101 PCMEM:C3,50,01,00",
+        "A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0150 PCMEM:3C,3C,18,FC",
+      ]
+    );
+  }
+
+  #[test]
+  fn starts_and_stops_on_triggers() {
+    let mut game: Game = game();
+    let mut tracer: Tracer = Tracer::to_buffer();
+    tracer.start = Some(0x150);
+    tracer.stop = Some(0x152);
+    game.tracer = Some(tracer);
+    let lines: Vec<String> = run(&mut game, 10);
+    let pcs: Vec<&str> = lines.iter().map(|line| line.split(' ').nth(9).unwrap()).collect();
+    assert_eq!(pcs, ["PC:0150", "PC:0151"]);
+    assert!(game.tracer.as_ref().unwrap().finished());
+  }
+
+  #[test]
+  fn stops_after_max_lines_and_labels_lines() {
+    let mut game: Game = game();
+    let mut tracer: Tracer = Tracer::to_buffer();
+    tracer.max_lines = Some(4);
+    tracer.symbols = Symbols::parse("00:0150 Loop").unwrap();
+    game.tracer = Some(tracer);
+    let lines: Vec<String> = run(&mut game, 10);
+    assert_eq!(lines.len(), 4);
+    assert!(lines[0].ends_with("PCMEM:00,C3,50,01"));
+    assert!(lines[3].ends_with(" ; Loop+$1"));
+  }
+}
@@ -0,0 +1,350 @@
+// Renders the contents of VRAM and OAM into RGBA images for the debugger,
+// independently of what the LCD is showing.
+//
+// Tiles and maps are coloured through BGP and objects through their OBP
+// palette, then through the host `Palette`, so the images match the screen.
+// The tile data viewer takes a VRAM bank, but the CGB's second bank isn't
+// emulated yet, so asking for bank 1 gets None rather than a copy of bank 0.
+//
+// Follow-up: showing bank 1 needs CGB VRAM banking (VBK at 0xff4f) in
+// memory.rs and a second tile cache in gpu.rs; then raise `VRAM_BANKS`.
+
+use crate::game::gpu::{
+  shade, Gpu, LAYER_BG, LAYER_OBJ0, LAYER_OBJ1, LCDC_OBJ_TALL, LCDC_TILE_DATA, OBJ_BEHIND_BG,
+  OBJ_FLIP_X, OBJ_FLIP_Y, OBJ_PALETTE, SCREEN_HEIGHT, SCREEN_WIDTH, TILE_COUNT,
+};
+use crate::game::palette::{Palette, Rgba};
+use wasm_bindgen::prelude::*;
+
+// VRAM banks the viewers can show. Becomes 2 once CGB VRAM is emulated.
+pub const VRAM_BANKS: usize = 1;
+
+// The tile data viewer lays the 384 tiles of a bank out 16 to a row.
+pub const TILES_PER_ROW: usize = 16;
+pub const TILE_DATA_WIDTH: usize = TILES_PER_ROW * 8;
+pub const TILE_DATA_HEIGHT: usize = TILE_COUNT / TILES_PER_ROW * 8;
+
+// Each tile map is 32x32 tiles.
+pub const MAP_SIZE: usize = 256;
+
+pub const OBJECT_COUNT: usize = 40;
+// The OAM viewer shows every object in an 8x16 cell, 8 to a row.
+pub const OBJECTS_PER_ROW: usize = 8;
+pub const OAM_WIDTH: usize = OBJECTS_PER_ROW * 8;
+pub const OAM_HEIGHT: usize = OBJECT_COUNT / OBJECTS_PER_ROW * 16;
+
+// Outline drawn over the tile map where SCX/SCY place the screen.
+pub const VIEWPORT_COLOR: Rgba = [0xff, 0x00, 0x00, 0xff];
+
+// One OAM entry with its attribute byte decoded.
+#[wasm_bindgen]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ObjectEntry {
+  pub index: usize,
+  // Raw OAM coordinates: the object's top-left corner is at (x - 8, y - 16).
+  pub y: u8,
+  pub x: u8,
+  pub tile: u8,
+  pub flags: u8,
+  pub behind_bg: bool,
+  pub flip_y: bool,
+  pub flip_x: bool,
+  pub obp1: bool,
+}
+
+fn put_pixel(out: &mut [u8], width: usize, x: usize, y: usize, color: Rgba) {
+  let offset: usize = (y * width + x) * 4;
+  out[offset..offset + 4].copy_from_slice(&color);
+}
+
+fn draw_tile(gpu: &Gpu, palette: &Palette, tile: usize, out: &mut [u8]) {
+  let left: usize = (tile % TILES_PER_ROW) * 8;
+  let top: usize = (tile / TILES_PER_ROW) * 8;
+  for (y, row) in gpu.tiles[tile].iter().enumerate() {
+    for (x, &color) in row.iter().enumerate() {
+      let rgba: Rgba = palette.color(LAYER_BG, shade(gpu.bg_palette, color));
+      put_pixel(out, TILE_DATA_WIDTH, left + x, top + y, rgba);
+    }
+  }
+}
+
+fn draw_map(gpu: &Gpu, palette: &Palette, map: usize, out: &mut [u8]) {
+  let base: u16 = 0x1800 + (map as u16 & 1) * 0x400;
+  for y in 0..MAP_SIZE {
+    for x in 0..MAP_SIZE {
+      let color: u8 = gpu.map_pixel(base, x as u8, y as u8);
+      let rgba: Rgba = palette.color(LAYER_BG, shade(gpu.bg_palette, color));
+      put_pixel(out, MAP_SIZE, x, y, rgba);
+    }
+  }
+}
+
+// Every tile in VRAM bank `bank`, tile 0 (0x8000) at the top left, or None
+// for a bank that isn't emulated.
+pub fn tile_data_rgba(gpu: &Gpu, palette: &Palette, bank: usize) -> Option<Vec<u8>> {
+  if bank >= VRAM_BANKS {
+    return None;
+  }
+  let mut out: Vec<u8> = vec![0; TILE_DATA_WIDTH * TILE_DATA_HEIGHT * 4];
+  for tile in 0..TILE_COUNT {
+    draw_tile(gpu, palette, tile, &mut out);
+  }
+  return Some(out);
+}
+
+// The whole 256x256 background of tile map `map` (0 for 0x9800, 1 for
+// 0x9c00) using the current LCDC tile data area, with the screen's viewport
+// outlined. The outline wraps around the edges like the scroll registers.
+pub fn tile_map_rgba(gpu: &Gpu, palette: &Palette, map: usize) -> Vec<u8> {
+  let mut out: Vec<u8> = vec![0; MAP_SIZE * MAP_SIZE * 4];
+  draw_map(gpu, palette, map, &mut out);
+  draw_viewport(gpu, &mut out);
+  return out;
+}
+
+// Tile data and tile map images kept between updates, so a viewer refreshed
+// every frame only redraws what VRAM writes changed.
+pub struct VramViews {
+  tile_data: Vec<u8>,
+  // Without the viewport, which moves with every scroll.
+  maps: [Vec<u8>; 2],
+  // BGP, LCDC and host palette the images were drawn with. Any change
+  // redraws everything.
+  drawn_with: Option<(u8, u8, Palette)>,
+}
+
+impl Default for VramViews {
+  fn default() -> VramViews {
+    return VramViews {
+      tile_data: vec![0; TILE_DATA_WIDTH * TILE_DATA_HEIGHT * 4],
+      maps: [vec![0; MAP_SIZE * MAP_SIZE * 4], vec![0; MAP_SIZE * MAP_SIZE * 4]],
+      drawn_with: None,
+    };
+  }
+}
+
+impl VramViews {
+  // Redraws the tiles and maps written since the last update, clearing the
+  // GPU's dirty flags. A changed tile redraws both maps, since either may
+  // show it.
+  pub fn update(&mut self, gpu: &mut Gpu, palette: &Palette) {
+    let dirty_tiles: [bool; TILE_COUNT] = gpu.take_dirty_tiles();
+    let dirty_maps: [bool; 2] = gpu.take_dirty_maps();
+    let key: (u8, u8, Palette) = (gpu.bg_palette, gpu.control & LCDC_TILE_DATA, *palette);
+    let all: bool = self.drawn_with != Some(key);
+    self.drawn_with = Some(key);
+
+    for (tile, &dirty) in dirty_tiles.iter().enumerate() {
+      if all || dirty {
+        draw_tile(gpu, palette, tile, &mut self.tile_data);
+      }
+    }
+    let any_tile: bool = dirty_tiles.iter().any(|&dirty| dirty);
+    for (map, out) in self.maps.iter_mut().enumerate() {
+      if all || any_tile || dirty_maps[map] {
+        draw_map(gpu, palette, map, out);
+      }
+    }
+  }
+
+  // As `tile_data_rgba`, as of the last update.
+  pub fn tile_data(&self, bank: usize) -> Option<&[u8]> {
+    if bank >= VRAM_BANKS {
+      return None;
+    }
+    return Some(&self.tile_data);
+  }
+
+  // As `tile_map_rgba`, as of the last update, outlining the viewport where
+  // it is now.
+  pub fn tile_map(&self, gpu: &Gpu, map: usize) -> Vec<u8> {
+    let mut out: Vec<u8> = self.maps[map & 1].clone();
+    draw_viewport(gpu, &mut out);
+    return out;
+  }
+}
+
+fn draw_viewport(gpu: &Gpu, out: &mut [u8]) {
+  let left: usize = gpu.scroll_x as usize;
+  let top: usize = gpu.scroll_y as usize;
+  for dx in 0..SCREEN_WIDTH {
+    let x: usize = (left + dx) % MAP_SIZE;
+    put_pixel(out, MAP_SIZE, x, top, VIEWPORT_COLOR);
+    put_pixel(out, MAP_SIZE, x, (top + SCREEN_HEIGHT - 1) % MAP_SIZE, VIEWPORT_COLOR);
+  }
+  for dy in 0..SCREEN_HEIGHT {
+    let y: usize = (top + dy) % MAP_SIZE;
+    put_pixel(out, MAP_SIZE, left, y, VIEWPORT_COLOR);
+    put_pixel(out, MAP_SIZE, (left + SCREEN_WIDTH - 1) % MAP_SIZE, y, VIEWPORT_COLOR);
+  }
+}
+
+pub fn objects(gpu: &Gpu) -> Vec<ObjectEntry> {
+  return (0..OBJECT_COUNT)
+    .map(|index| {
+      let entry: &[u8] = &gpu.oam[index * 4..index * 4 + 4];
+      let flags: u8 = entry[3];
+      ObjectEntry {
+        index,
+        y: entry[0],
+        x: entry[1],
+        tile: entry[2],
+        flags,
+        behind_bg: flags & OBJ_BEHIND_BG != 0,
+        flip_y: flags & OBJ_FLIP_Y != 0,
+        flip_x: flags & OBJ_FLIP_X != 0,
+        obp1: flags & OBJ_PALETTE != 0,
+      }
+    })
+    .collect();
+}
+
+// A single object as it would be drawn, 8 pixels wide and 8 or 16 tall
+// depending on LCDC.2. Colour 0 is transparent.
+pub fn object_rgba(gpu: &Gpu, palette: &Palette, object: &ObjectEntry) -> Vec<u8> {
+  let height: usize = if gpu.control & LCDC_OBJ_TALL != 0 { 16 } else { 8 };
+  let mut out: Vec<u8> = vec![0; 8 * height * 4];
+  draw_object(gpu, palette, object, height, &mut out, 8, 0, 0);
+  return out;
+}
+
+// All 40 objects in OAM order, each in an 8x16 cell. Cells stay transparent
+// below 8x8 objects.
+pub fn oam_rgba(gpu: &Gpu, palette: &Palette) -> Vec<u8> {
+  let height: usize = if gpu.control & LCDC_OBJ_TALL != 0 { 16 } else { 8 };
+  let mut out: Vec<u8> = vec![0; OAM_WIDTH * OAM_HEIGHT * 4];
+  for object in objects(gpu).iter() {
+    let left: usize = (object.index % OBJECTS_PER_ROW) * 8;
+    let top: usize = (object.index / OBJECTS_PER_ROW) * 16;
+    draw_object(gpu, palette, object, height, &mut out, OAM_WIDTH, left, top);
+  }
+  return out;
+}
+
+#[allow(clippy::too_many_arguments)]
+fn draw_object(
+  gpu: &Gpu,
+  palette: &Palette,
+  object: &ObjectEntry,
+  height: usize,
+  out: &mut [u8],
+  width: usize,
+  left: usize,
+  top: usize,
+) {
+  // 8x16 objects ignore bit 0 of the tile number.
+  let first_tile: usize = if height == 16 {
+    (object.tile & 0xfe) as usize
+  } else {
+    object.tile as usize
+  };
+  let (obp, layer): (u8, u8) = if object.obp1 {
+    (gpu.obj_palette1, LAYER_OBJ1)
+  } else {
+    (gpu.obj_palette0, LAYER_OBJ0)
+  };
+  for y in 0..height {
+    let row: usize = if object.flip_y { height - 1 - y } else { y };
+    for x in 0..8 {
+      let column: usize = if object.flip_x { 7 - x } else { x };
+      let color: u8 = gpu.tiles[first_tile + row / 8][row % 8][column];
+      if color != 0 {
+        put_pixel(out, width, left + x, top + y, palette.color(layer, shade(obp, color)));
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::game::palette::HIGH_CONTRAST;
+
+  // A tile whose top-left pixel is colour 3 and the rest colour 0.
+  fn gpu_with_corner_tile(tile: u16) -> Gpu {
+    let mut gpu: Gpu = Default::default();
+    gpu.bg_palette = 0xe4;
+    gpu.obj_palette0 = 0xe4;
+    gpu.write_vram(tile * 16, 0x80);
+    gpu.write_vram(tile * 16 + 1, 0x80);
+    return gpu;
+  }
+
+  fn pixel(out: &[u8], width: usize, x: usize, y: usize) -> Rgba {
+    let offset: usize = (y * width + x) * 4;
+    return [out[offset], out[offset + 1], out[offset + 2], out[offset + 3]];
+  }
+
+  #[test]
+  fn tile_data_lays_tiles_out_in_rows() {
+    let gpu: Gpu = gpu_with_corner_tile(17);
+    let out: Vec<u8> = tile_data_rgba(&gpu, &Palette::high_contrast(), 0).unwrap();
+    assert_eq!(out.len(), 128 * 192 * 4);
+    assert_eq!(pixel(&out, TILE_DATA_WIDTH, 8, 8), HIGH_CONTRAST[3]);
+    assert_eq!(pixel(&out, TILE_DATA_WIDTH, 9, 8), HIGH_CONTRAST[0]);
+    // The CGB's second bank isn't emulated.
+    assert!(tile_data_rgba(&gpu, &Palette::high_contrast(), 1).is_none());
+  }
+
+  #[test]
+  fn tile_map_outlines_the_viewport() {
+    let mut gpu: Gpu = gpu_with_corner_tile(1);
+    // Tile (2, 0) of map 0 shows tile 1.
+    gpu.write_vram(0x1802, 1);
+    gpu.scroll_x = 200;
+    gpu.scroll_y = 8;
+    let out: Vec<u8> = tile_map_rgba(&gpu, &Palette::high_contrast(), 0);
+    assert_eq!(pixel(&out, MAP_SIZE, 16, 0), HIGH_CONTRAST[3]);
+    assert_eq!(pixel(&out, MAP_SIZE, 16, 1), HIGH_CONTRAST[0]);
+    // The left edge at x=200, the right edge wrapped round to x=103.
+    assert_eq!(pixel(&out, MAP_SIZE, 200, 50), VIEWPORT_COLOR);
+    assert_eq!(pixel(&out, MAP_SIZE, 103, 50), VIEWPORT_COLOR);
+    assert_eq!(pixel(&out, MAP_SIZE, 104, 50), HIGH_CONTRAST[0]);
+    assert_eq!(pixel(&out, MAP_SIZE, 0, 8), VIEWPORT_COLOR);
+    assert_eq!(pixel(&out, MAP_SIZE, 0, 151), VIEWPORT_COLOR);
+  }
+
+  #[test]
+  fn cached_views_redraw_only_dirty_tiles() {
+    let mut gpu: Gpu = gpu_with_corner_tile(17);
+    let palette: Palette = Palette::high_contrast();
+    let mut views: VramViews = Default::default();
+    views.update(&mut gpu, &palette);
+    assert_eq!(views.tile_data(0), tile_data_rgba(&gpu, &palette, 0).as_deref());
+    assert!(views.tile_data(1).is_none());
+    assert!(!gpu.take_dirty_tiles().contains(&true));
+    assert_eq!(gpu.take_dirty_maps(), [false, false]);
+
+    // Tile 3 is written; tile 7 changes behind the cache's back.
+    gpu.write_vram(0x8000 + 3 * 16, 0x80);
+    gpu.tiles[7][0][0] = 3;
+    views.update(&mut gpu, &palette);
+    let out: &[u8] = views.tile_data(0).unwrap();
+    assert_eq!(pixel(out, TILE_DATA_WIDTH, 24, 0), HIGH_CONTRAST[1]);
+    assert_eq!(pixel(out, TILE_DATA_WIDTH, 56, 0), HIGH_CONTRAST[0]);
+
+    // A palette change redraws everything, tile 7 included.
+    gpu.bg_palette = 0x1b;
+    views.update(&mut gpu, &palette);
+    assert_eq!(views.tile_data(0), tile_data_rgba(&gpu, &palette, 0).as_deref());
+    assert_eq!(views.tile_map(&gpu, 0), tile_map_rgba(&gpu, &palette, 0));
+  }
+
+  #[test]
+  fn oam_entries_are_decoded_and_drawn() {
+    let mut gpu: Gpu = gpu_with_corner_tile(5);
+    gpu.oam[4..8].copy_from_slice(&[16, 8, 5, OBJ_FLIP_X | OBJ_BEHIND_BG]);
+    let entries: Vec<ObjectEntry> = objects(&gpu);
+    assert_eq!(entries.len(), 40);
+    assert_eq!(entries[1].tile, 5);
+    assert!(entries[1].flip_x && entries[1].behind_bg);
+    assert!(!entries[1].flip_y && !entries[1].obp1);
+
+    let palette: Palette = Palette::high_contrast();
+    let out: Vec<u8> = oam_rgba(&gpu, &palette);
+    // Object 1 sits in the second cell, flipped so its pixel is on the right.
+    assert_eq!(pixel(&out, OAM_WIDTH, 15, 0), HIGH_CONTRAST[3]);
+    assert_eq!(pixel(&out, OAM_WIDTH, 8, 0), [0, 0, 0, 0]);
+    assert_eq!(object_rgba(&gpu, &palette, &entries[1]).len(), 8 * 8 * 4);
+  }
+}